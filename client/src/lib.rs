@@ -7,16 +7,16 @@ mod postgres;
 
 use anyhow::Result;
 use fallible_iterator::FallibleIterator;
-use postgres::Metadata;
+use postgres::{Metadata, ServerError, Value};
 use postgres_protocol::message::backend::DataRowBody;
-use std::cell::RefCell;
+use postgres_protocol::Oid;
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
 use std::ffi::{CStr, CString};
-use std::ops::Range;
 use std::os::raw::{c_char, c_int, c_void};
 use std::rc::Rc;
 use tracing::trace;
-use unwrap_or::unwrap_ok_or;
+use unwrap_or::{unwrap_ok_or, unwrap_some_or};
 
 thread_local! {
     static ERRMSG: RefCell<Option<CString>> = RefCell::new(None);
@@ -45,24 +45,112 @@ pub type sqlite3_uint64 = u64;
 
 pub const SQLITE_OK: c_int = 0;
 pub const SQLITE_ERROR: c_int = 1;
+pub const SQLITE_ABORT: c_int = 4;
+pub const SQLITE_BUSY: c_int = 5;
 pub const SQLITE_LOCKED: c_int = 6;
+pub const SQLITE_INTERRUPT: c_int = 9;
+pub const SQLITE_IOERR: c_int = 10;
+pub const SQLITE_CONSTRAINT: c_int = 19;
 pub const SQLITE_MISUSE: c_int = 21;
+pub const SQLITE_AUTH: c_int = 23;
+pub const SQLITE_RANGE: c_int = 25;
 pub const SQLITE_ROW: c_int = 100;
 pub const SQLITE_DONE: c_int = 101;
 pub const SQLITE_LOCKED_SHAREDCACHE: c_int = SQLITE_LOCKED | (1 << 8);
+pub const SQLITE_CONSTRAINT_CHECK: c_int = SQLITE_CONSTRAINT | (2 << 8);
+pub const SQLITE_CONSTRAINT_FOREIGNKEY: c_int = SQLITE_CONSTRAINT | (3 << 8);
+pub const SQLITE_CONSTRAINT_NOTNULL: c_int = SQLITE_CONSTRAINT | (5 << 8);
+pub const SQLITE_CONSTRAINT_UNIQUE: c_int = SQLITE_CONSTRAINT | (8 << 8);
 
 pub const SQLITE_TRANSIENT: c_int = -1;
 
 pub const SQLITE_UTF8: c_int = 1;
 
+/// OIDs of the column types the server sends.
+const BOOL_OID: Oid = 16;
+const BYTEA_OID: Oid = 17;
+const INT8_OID: Oid = 20;
+const INT2_OID: Oid = 21;
+const INT4_OID: Oid = 23;
+const TEXT_OID: Oid = 25;
+const FLOAT4_OID: Oid = 700;
+const FLOAT8_OID: Oid = 701;
+const VARCHAR_OID: Oid = 1043;
+const NUMERIC_OID: Oid = 1700;
+
 struct Database {
     conn: RefCell<postgres::Connection>,
+    /// The extended result code and message of the most recent failed call.
+    error: RefCell<(c_int, CString)>,
+    /// Rows modified by the most recent INSERT, UPDATE or DELETE statement.
+    changes: Cell<i64>,
+    /// Rows modified since the connection was opened.
+    total_changes: Cell<i64>,
+    last_insert_rowid: Cell<i64>,
 }
 
 impl Database {
     fn new(conn: postgres::Connection) -> Self {
         let conn = RefCell::new(conn);
-        Self { conn }
+        let error = RefCell::new((SQLITE_OK, CString::default()));
+        Self {
+            conn,
+            error,
+            changes: Cell::new(0),
+            total_changes: Cell::new(0),
+            last_insert_rowid: Cell::new(0),
+        }
+    }
+
+    /// Updates the counters from the result of a statement.
+    fn record_changes(&self, metadata: &Metadata) {
+        if let Some(changes) = metadata.changes {
+            self.changes.set(changes);
+            self.total_changes.set(self.total_changes.get() + changes);
+        }
+        if let Some(rowid) = metadata.last_insert_rowid {
+            self.last_insert_rowid.set(rowid);
+        }
+    }
+
+    /// Records an error on the connection, and returns its result code.
+    fn set_error(&self, code: c_int, msg: impl ToString) -> c_int {
+        let msg = CString::new(msg.to_string()).unwrap_or_default();
+        *self.error.borrow_mut() = (code, msg);
+        code
+    }
+
+    /// Records an error returned by the connection, mapping server errors to the matching result
+    /// code.
+    fn set_query_error(&self, e: anyhow::Error) -> c_int {
+        let code = match e.downcast_ref::<ServerError>() {
+            Some(e) => sqlstate_to_code(&e.code),
+            None if e.is::<std::io::Error>() => SQLITE_IOERR,
+            None => SQLITE_ERROR,
+        };
+        self.set_error(code, e)
+    }
+
+    fn clear_error(&self) {
+        *self.error.borrow_mut() = (SQLITE_OK, CString::default());
+    }
+}
+
+/// Maps a SQLSTATE code to the closest SQLite extended result code.
+fn sqlstate_to_code(sqlstate: &str) -> c_int {
+    match sqlstate {
+        "23502" => SQLITE_CONSTRAINT_NOTNULL,
+        "23503" => SQLITE_CONSTRAINT_FOREIGNKEY,
+        "23505" => SQLITE_CONSTRAINT_UNIQUE,
+        "23514" => SQLITE_CONSTRAINT_CHECK,
+        s if s.starts_with("23") => SQLITE_CONSTRAINT,
+        // lock_not_available, serialization_failure and deadlock_detected can all be retried.
+        "55P03" | "40001" | "40P01" => SQLITE_BUSY,
+        // The transaction was rolled back by the server.
+        "25P03" | "40000" => SQLITE_ABORT,
+        "57014" => SQLITE_INTERRUPT,
+        s if s.starts_with("28") => SQLITE_AUTH,
+        _ => SQLITE_ERROR,
     }
 }
 
@@ -102,14 +190,18 @@ struct Statement {
     parent: Rc<Database>,
     sql: String,
     state: StatementState,
+    /// Values bound to the statement parameters, by index.
+    params: Vec<Value>,
     metadata: Option<Metadata>,
-    current_row: Option<(DataRowBody, Vec<Option<Range<usize>>>)>,
+    /// The decoded values of the current row.
+    current_row: Option<Vec<Option<Vec<u8>>>>,
     rows: VecDeque<DataRowBody>,
 }
 
 impl Statement {
     fn new(parent: Rc<Database>, sql: String) -> Self {
         let state = StatementState::Prepared;
+        let params = Vec::new();
         let metadata = None;
         let current_row = None;
         let rows = VecDeque::default();
@@ -117,6 +209,7 @@ impl Statement {
             parent,
             sql,
             state,
+            params,
             metadata,
             current_row,
             rows,
@@ -192,20 +285,31 @@ pub extern "C" fn sqlite3_os_end() -> c_int {
  */
 
 #[no_mangle]
-pub extern "C" fn sqlite3_errcode(_db: *mut sqlite3) -> c_int {
-    trace!("STUB sqlite3_errcode");
-    SQLITE_OK
+pub extern "C" fn sqlite3_errcode(db: *mut sqlite3) -> c_int {
+    trace!("TRACE sqlite3_errcode");
+    sqlite3_extended_errcode(db) & 0xff
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_extended_errcode(_db: *mut sqlite3) -> c_int {
-    trace!("STUB sqlite3_extended_errcode");
-    SQLITE_OK
+pub extern "C" fn sqlite3_extended_errcode(db: *mut sqlite3) -> c_int {
+    trace!("TRACE sqlite3_extended_errcode");
+    if db.is_null() {
+        return SQLITE_MISUSE;
+    }
+    to_database(db).error.borrow().0
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_errmsg(_db: *mut sqlite3) -> *const c_char {
-    trace!("STUB sqlite3_errmsg");
+pub extern "C" fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char {
+    trace!("TRACE sqlite3_errmsg");
+    // Errors that happen without a connection, like failing to open one, are kept per thread.
+    if !db.is_null() {
+        let database = to_database(db);
+        let error = database.error.borrow();
+        if error.0 != SQLITE_OK {
+            return error.1.as_ptr();
+        }
+    }
     ERRMSG.with(|errmsg| {
         errmsg
             .borrow()
@@ -310,8 +414,8 @@ pub extern "C" fn sqlite3_prepare_v2(
     let database = to_database(db);
     trace!("TRACE sqlite3_prepare_v2");
     let zSql = unsafe { CStr::from_ptr(zSql) };
-    let sql = unwrap_ok_or!(zSql.to_str(), _, {
-        return SQLITE_ERROR;
+    let sql = unwrap_ok_or!(zSql.to_str(), e, {
+        return database.set_error(SQLITE_ERROR, e);
     });
     let sql = sql.to_string();
     unsafe {
@@ -326,14 +430,31 @@ pub extern "C" fn sqlite3_prepare_v2(
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_finalize(_stmt: *mut sqlite3_stmt) -> c_int {
-    trace!("STUB sqlite3_finalize");
+pub extern "C" fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int {
+    trace!("TRACE sqlite3_finalize");
+    if stmt.is_null() {
+        return SQLITE_OK;
+    }
+    let _ = unsafe { Box::from_raw(stmt) };
     SQLITE_OK
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_reset(_stmt: *mut sqlite3_stmt) -> c_int {
-    trace!("STUB sqlite3_reset");
+pub extern "C" fn sqlite3_reset(stmt: *mut sqlite3_stmt) -> c_int {
+    trace!("TRACE sqlite3_reset");
+    // bindings are kept, so that the statement can run again with the same values.
+    let stmt = to_stmt(stmt);
+    stmt.state = StatementState::Prepared;
+    stmt.metadata = None;
+    stmt.current_row = None;
+    stmt.rows.clear();
+    SQLITE_OK
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_clear_bindings(stmt: *mut sqlite3_stmt) -> c_int {
+    trace!("TRACE sqlite3_clear_bindings");
+    to_stmt(stmt).params.clear();
     SQLITE_OK
 }
 
@@ -352,128 +473,231 @@ pub extern "C" fn sqlite3_step(stmt: *mut sqlite3_stmt) -> c_int {
     match stmt.state {
         StatementState::Prepared => {
             let database = stmt.parent.clone();
+            database.clear_error();
             let mut conn = database.conn.borrow_mut();
-            unwrap_ok_or!(conn.send_simple_query(&stmt.sql), e, {
-                set_error_message(e);
-                return SQLITE_ERROR;
+            // Only statements with parameters need the extended query protocol.
+            let sent = if stmt.params.is_empty() {
+                conn.send_simple_query(&stmt.sql)
+            } else {
+                conn.send_extended_query(&stmt.sql, &stmt.params)
+            };
+            unwrap_ok_or!(sent, e, {
+                return database.set_query_error(e);
             });
             let (metadata, rows) = unwrap_ok_or!(conn.wait_until_ready(), e, {
-                set_error_message(e);
-                return SQLITE_ERROR;
+                stmt.state = StatementState::Done;
+                return database.set_query_error(e);
             });
+            database.record_changes(&metadata);
             stmt.metadata = Some(metadata);
             stmt.rows = rows;
-            if let Some(row) = stmt.rows.pop_front() {
-                let column_ranges = parse_column_ranges(&row);
-                stmt.current_row = Some((row, column_ranges));
-                stmt.state = StatementState::Rows;
-                SQLITE_ROW
-            } else {
-                stmt.state = StatementState::Done;
-                SQLITE_DONE
-            }
-        }
-        StatementState::Rows => {
-            if let Some(row) = stmt.rows.pop_front() {
-                let column_ranges = parse_column_ranges(&row);
-                stmt.current_row = Some((row, column_ranges));
-                SQLITE_ROW
-            } else {
-                stmt.state = StatementState::Done;
-                SQLITE_DONE
-            }
+            next_row(stmt)
         }
+        StatementState::Rows => next_row(stmt),
         StatementState::Done => SQLITE_MISUSE,
     }
 }
 
+fn next_row(stmt: &mut Statement) -> c_int {
+    let row = unwrap_some_or!(stmt.rows.pop_front(), {
+        stmt.current_row = None;
+        stmt.state = StatementState::Done;
+        return SQLITE_DONE;
+    });
+    let values = unwrap_ok_or!(decode_row(&row, stmt.metadata.as_ref().unwrap()), e, {
+        return stmt.parent.set_error(SQLITE_ERROR, e);
+    });
+    stmt.current_row = Some(values);
+    stmt.state = StatementState::Rows;
+    SQLITE_ROW
+}
+
+/// Binds `value` to the parameter at `idx` of `stmt`. Parameters are numbered from 1.
+fn bind_value(stmt: *mut sqlite3_stmt, idx: c_int, value: Value) -> c_int {
+    let stmt = to_stmt(stmt);
+    if idx < 1 {
+        return stmt
+            .parent
+            .set_error(SQLITE_RANGE, format!("invalid parameter index {}", idx));
+    }
+    let idx = idx as usize - 1;
+    if stmt.params.len() <= idx {
+        stmt.params.resize(idx + 1, Value::Null);
+    }
+    stmt.params[idx] = value;
+    SQLITE_OK
+}
+
+/// Calls the destructor passed along with a bound text or blob, once its content was copied.
+fn release_bound_value(value: *const c_void, destructor: *const c_void) {
+    // SQLITE_STATIC and SQLITE_TRANSIENT are not actual destructors.
+    if destructor.is_null() || destructor as isize == SQLITE_TRANSIENT as isize {
+        return;
+    }
+    let destructor: extern "C" fn(*mut c_void) = unsafe { std::mem::transmute(destructor) };
+    destructor(value as *mut c_void);
+}
+
+/// Copies `n` bytes at `value`, or the bytes up to its NUL terminator if `n` is negative.
+fn copy_bytes(value: *const c_void, n: i64) -> Vec<u8> {
+    if value.is_null() {
+        return Vec::new();
+    }
+    if n < 0 {
+        unsafe { CStr::from_ptr(value as *const c_char) }
+            .to_bytes()
+            .to_vec()
+    } else {
+        unsafe { std::slice::from_raw_parts(value as *const u8, n as usize) }.to_vec()
+    }
+}
+
+fn bind_blob(
+    stmt: *mut sqlite3_stmt,
+    idx: c_int,
+    value: *const c_void,
+    n: i64,
+    destructor: *const c_void,
+) -> c_int {
+    // a NULL pointer binds NULL, regardless of `n`.
+    let blob = if value.is_null() {
+        Value::Null
+    } else {
+        Value::Blob(copy_bytes(value, n.max(0)))
+    };
+    release_bound_value(value, destructor);
+    bind_value(stmt, idx, blob)
+}
+
+fn bind_text(
+    stmt: *mut sqlite3_stmt,
+    idx: c_int,
+    value: *const c_void,
+    n: i64,
+    destructor: *const c_void,
+) -> c_int {
+    let text = if value.is_null() {
+        Ok(Value::Null)
+    } else {
+        String::from_utf8(copy_bytes(value, n)).map(Value::Text)
+    };
+    release_bound_value(value, destructor);
+    let text = unwrap_ok_or!(text, e, {
+        return to_stmt(stmt).parent.set_error(SQLITE_ERROR, e);
+    });
+    bind_value(stmt, idx, text)
+}
+
 #[no_mangle]
 pub extern "C" fn sqlite3_bind_blob(
-    _stmt: *mut sqlite3_stmt,
-    _idx: c_int,
-    _value: *const (),
-    _n: c_int,
-    _callback: extern "C" fn(*mut ()),
+    stmt: *mut sqlite3_stmt,
+    idx: c_int,
+    value: *const c_void,
+    n: c_int,
+    destructor: *const c_void,
 ) -> c_int {
-    trace!("STUB sqlite3_bind_blob");
-    SQLITE_OK
+    trace!("TRACE sqlite3_bind_blob");
+    bind_blob(stmt, idx, value, n as i64, destructor)
 }
 
 #[no_mangle]
 pub extern "C" fn sqlite3_bind_blob64(
-    _stmt: *mut sqlite3_stmt,
-    _idx: c_int,
-    _value: *const (),
-    _n: sqlite3_uint64,
-    _callback: extern "C" fn(*mut ()),
+    stmt: *mut sqlite3_stmt,
+    idx: c_int,
+    value: *const c_void,
+    n: sqlite3_uint64,
+    destructor: *const c_void,
 ) -> c_int {
-    trace!("STUB sqlite3_bind_blob64");
-    SQLITE_ERROR
+    trace!("TRACE sqlite3_bind_blob64");
+    bind_blob(stmt, idx, value, n as i64, destructor)
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_bind_double(_stmt: *mut sqlite3_stmt, _idx: c_int, _value: f32) -> c_int {
-    trace!("sqlite3_bind_double");
-    SQLITE_OK
+pub extern "C" fn sqlite3_bind_double(stmt: *mut sqlite3_stmt, idx: c_int, value: f64) -> c_int {
+    trace!("TRACE sqlite3_bind_double");
+    bind_value(stmt, idx, Value::Real(value))
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_bind_int(_stmt: *mut sqlite3_stmt, _idx: c_int, _value: c_int) -> c_int {
-    trace!("sqlite3_bind_int");
-    SQLITE_OK
+pub extern "C" fn sqlite3_bind_int(stmt: *mut sqlite3_stmt, idx: c_int, value: c_int) -> c_int {
+    trace!("TRACE sqlite3_bind_int");
+    bind_value(stmt, idx, Value::Integer(value as i64))
 }
 
 #[no_mangle]
 pub extern "C" fn sqlite3_bind_int64(
-    _stmt: *mut sqlite3_stmt,
-    _idx: c_int,
-    _value: sqlite3_int64,
+    stmt: *mut sqlite3_stmt,
+    idx: c_int,
+    value: sqlite3_int64,
 ) -> c_int {
-    trace!("STUB sqlite3_bind_int64");
-    SQLITE_OK
+    trace!("TRACE sqlite3_bind_int64");
+    bind_value(stmt, idx, Value::Integer(value))
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_bind_null(_stmt: *mut sqlite3_stmt, _idx: c_int) -> c_int {
-    trace!("STUB sqlite3_bind_null");
-    SQLITE_OK
+pub extern "C" fn sqlite3_bind_null(stmt: *mut sqlite3_stmt, idx: c_int) -> c_int {
+    trace!("TRACE sqlite3_bind_null");
+    bind_value(stmt, idx, Value::Null)
 }
 
 #[no_mangle]
 pub extern "C" fn sqlite3_bind_text(
-    _stmt: *mut sqlite3_stmt,
-    _idx: c_int,
-    _value: *const c_char,
-    _n: c_int,
-    _callback: extern "C" fn(*mut ()),
+    stmt: *mut sqlite3_stmt,
+    idx: c_int,
+    value: *const c_char,
+    n: c_int,
+    destructor: *const c_void,
 ) -> c_int {
-    trace!("STUB sqlite3_bind_text");
-    SQLITE_OK
+    trace!("TRACE sqlite3_bind_text");
+    bind_text(stmt, idx, value as *const c_void, n as i64, destructor)
 }
 
 #[no_mangle]
 pub extern "C" fn sqlite3_bind_text16(
-    _stmt: *mut sqlite3_stmt,
-    _idx: c_int,
-    _value: *const c_void,
-    _n: c_int,
-    _callback: extern "C" fn(*mut ()),
+    stmt: *mut sqlite3_stmt,
+    idx: c_int,
+    value: *const c_void,
+    n: c_int,
+    destructor: *const c_void,
 ) -> c_int {
-    trace!("STUB sqlite3_bind_text64");
-    SQLITE_OK
+    trace!("TRACE sqlite3_bind_text16");
+    let text = if value.is_null() {
+        Ok(Value::Null)
+    } else {
+        let value = value as *const u16;
+        let len = if n < 0 {
+            // up to the NUL terminator
+            (0..).take_while(|&i| unsafe { *value.add(i) } != 0).count()
+        } else {
+            n as usize / 2
+        };
+        String::from_utf16(unsafe { std::slice::from_raw_parts(value, len) }).map(Value::Text)
+    };
+    release_bound_value(value, destructor);
+    let text = unwrap_ok_or!(text, e, {
+        return to_stmt(stmt).parent.set_error(SQLITE_ERROR, e);
+    });
+    bind_value(stmt, idx, text)
 }
 
 #[no_mangle]
 pub extern "C" fn sqlite3_bind_text64(
-    _stmt: *mut sqlite3_stmt,
-    _idx: c_int,
-    _value: *const c_char,
-    _n: sqlite3_uint64,
-    _callback: extern "C" fn(*mut ()),
-    _encoding: c_char,
+    stmt: *mut sqlite3_stmt,
+    idx: c_int,
+    value: *const c_char,
+    n: sqlite3_uint64,
+    destructor: *const c_void,
+    encoding: c_char,
 ) -> c_int {
-    trace!("STUB sqlite3_bind_text64");
-    SQLITE_OK
+    trace!("TRACE sqlite3_bind_text64");
+    if encoding as c_int != SQLITE_UTF8 {
+        release_bound_value(value as *const c_void, destructor);
+        return to_stmt(stmt).parent.set_error(
+            SQLITE_ERROR,
+            "only UTF-8 text can be bound with sqlite3_bind_text64",
+        );
+    }
+    bind_text(stmt, idx, value as *const c_void, n as i64, destructor)
 }
 
 define_stub!(sqlite3_bind_value);
@@ -497,87 +721,205 @@ pub extern "C" fn sqlite3_column_count(stmt: *mut sqlite3_stmt) -> c_int {
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_column_name(_stmt: *mut sqlite3_stmt, _n: c_int) -> *const c_char {
-    trace!("STUB sqlite3_column_name");
-    std::ptr::null()
+pub extern "C" fn sqlite3_column_name(stmt: *mut sqlite3_stmt, n: c_int) -> *const c_char {
+    trace!("TRACE sqlite3_column_name");
+    let stmt = to_stmt(stmt);
+    stmt.metadata
+        .as_ref()
+        .and_then(|metadata| metadata.col_names.get(n as usize))
+        .map_or_else(std::ptr::null, |name| name.as_ptr())
 }
 
 const SQLITE_INTEGER: c_int = 1;
+const SQLITE_FLOAT: c_int = 2;
 const SQLITE3_TEXT: c_int = 3;
+const SQLITE_BLOB: c_int = 4;
+const SQLITE_NULL: c_int = 5;
+
+fn column_oid(stmt: &Statement, n: c_int) -> Option<Oid> {
+    let metadata = stmt.metadata.as_ref()?;
+    metadata.col_types.get(n as usize).map(|ty| ty.oid())
+}
+
+/// Returns the value of column `n` in the current row, without its NUL terminator, or `None` if
+/// the value is NULL.
+fn column_value(stmt: &Statement, n: c_int) -> Option<&[u8]> {
+    let value = stmt.current_row.as_ref()?.get(n as usize)?.as_ref()?;
+    Some(&value[..value.len() - 1])
+}
+
+fn column_str(stmt: &Statement, n: c_int) -> Option<&str> {
+    std::str::from_utf8(column_value(stmt, n)?).ok()
+}
 
 #[no_mangle]
 pub extern "C" fn sqlite3_column_type(stmt: *mut sqlite3_stmt, n: c_int) -> c_int {
     trace!("TRACE sqlite3_column_type");
     let stmt = to_stmt(stmt);
-    let ty = &stmt.metadata.as_ref().unwrap().col_types[n as usize];
-    match ty.oid() {
-        25 => SQLITE3_TEXT,
-        1700 => SQLITE_INTEGER,
-        _ => todo!("{}", ty.oid()),
+    if column_value(stmt, n).is_none() {
+        return SQLITE_NULL;
+    }
+    match column_oid(stmt, n) {
+        Some(INT2_OID | INT4_OID | INT8_OID | BOOL_OID) => SQLITE_INTEGER,
+        Some(FLOAT4_OID | FLOAT8_OID) => SQLITE_FLOAT,
+        // Numeric values are whatever the column holds, so they are told apart by their content.
+        Some(NUMERIC_OID) => match column_str(stmt, n).map(str::parse::<i64>) {
+            Some(Ok(_)) => SQLITE_INTEGER,
+            _ => SQLITE_FLOAT,
+        },
+        Some(BYTEA_OID) => SQLITE_BLOB,
+        _ => SQLITE3_TEXT,
     }
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_column_decltype(_stmt: *mut sqlite3_stmt, _n: c_int) -> *const c_char {
+pub extern "C" fn sqlite3_column_decltype(stmt: *mut sqlite3_stmt, n: c_int) -> *const c_char {
     trace!("TRACE sqlite3_column_decltype");
-    std::ptr::null()
+    let decltype: &[u8] = match column_oid(to_stmt(stmt), n) {
+        Some(INT2_OID | INT4_OID | INT8_OID) => b"INTEGER\0",
+        Some(BOOL_OID) => b"BOOLEAN\0",
+        Some(FLOAT4_OID | FLOAT8_OID) => b"REAL\0",
+        Some(NUMERIC_OID) => b"NUMERIC\0",
+        Some(TEXT_OID | VARCHAR_OID) => b"TEXT\0",
+        Some(BYTEA_OID) => b"BLOB\0",
+        // Expressions and out of range columns have no declared type.
+        _ => return std::ptr::null(),
+    };
+    decltype.as_ptr() as *const c_char
 }
 
 #[no_mangle]
 pub extern "C" fn sqlite3_column_bytes(stmt: *mut sqlite3_stmt, n: c_int) -> c_int {
     trace!("TRACE sqlite3_column_bytes");
+    column_value(to_stmt(stmt), n).map_or(0, |value| value.len() as c_int)
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_column_bytes16(stmt: *mut sqlite3_stmt, n: c_int) -> c_int {
+    trace!("TRACE sqlite3_column_bytes16");
     let stmt = to_stmt(stmt);
-    if let Some((_, column_ranges)) = &stmt.current_row {
-        column_ranges[n as usize]
-            .as_ref()
-            .unwrap()
-            .len()
-            .try_into()
-            .unwrap()
-    } else {
-        0
+    let value = unwrap_some_or!(column_value(stmt, n), {
+        return 0;
+    });
+    (String::from_utf8_lossy(value).encode_utf16().count() * 2) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_column_blob(stmt: *mut sqlite3_stmt, n: c_int) -> *const c_void {
+    trace!("TRACE sqlite3_column_blob");
+    match column_value(to_stmt(stmt), n) {
+        // SQLite returns NULL for zero-length blobs as well.
+        Some(value) if !value.is_empty() => value.as_ptr() as *const c_void,
+        _ => std::ptr::null(),
     }
 }
 
+#[no_mangle]
+pub extern "C" fn sqlite3_column_double(stmt: *mut sqlite3_stmt, n: c_int) -> f64 {
+    trace!("TRACE sqlite3_column_double");
+    column_str(to_stmt(stmt), n)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0.0)
+}
+
 #[no_mangle]
 pub extern "C" fn sqlite3_column_int64(stmt: *mut sqlite3_stmt, n: c_int) -> sqlite3_int64 {
     trace!("TRACE sqlite3_column_int64");
-    let stmt = to_stmt(stmt);
-    if let Some((row, column_ranges)) = &stmt.current_row {
-        let range = column_ranges[n as usize].as_ref().unwrap();
-        let buf = &row.buffer()[range.to_owned()];
-        let s = unsafe {
-            std::str::from_utf8_unchecked(std::slice::from_raw_parts(buf.as_ptr(), range.len()))
-        };
-        s.parse().unwrap()
-    } else {
-        0
+    let s = unwrap_some_or!(column_str(to_stmt(stmt), n), {
+        return 0;
+    });
+    match s {
+        "t" => 1,
+        "f" => 0,
+        // Like SQLite, reals are truncated towards zero.
+        s => s
+            .parse()
+            .or_else(|_| s.parse::<f64>().map(|x| x as i64))
+            .unwrap_or(0),
     }
 }
 
+#[no_mangle]
+pub extern "C" fn sqlite3_column_int(stmt: *mut sqlite3_stmt, n: c_int) -> c_int {
+    trace!("TRACE sqlite3_column_int");
+    sqlite3_column_int64(stmt, n) as c_int
+}
+
 #[no_mangle]
 pub extern "C" fn sqlite3_column_text(stmt: *mut sqlite3_stmt, n: c_int) -> *const c_char {
     trace!("TRACE sqlite3_column_text");
     let stmt = to_stmt(stmt);
-    if let Some((row, column_ranges)) = &stmt.current_row {
-        if let Some(range) = &column_ranges[n as usize] {
-            let buf = &row.buffer()[range.clone()];
-            buf.as_ptr() as *const c_char
-        } else {
-            std::ptr::null()
-        }
-    } else {
-        std::ptr::null()
+    // Values are stored NUL-terminated, so they can be handed out as C strings.
+    match column_value(stmt, n) {
+        Some(value) => value.as_ptr() as *const c_char,
+        None => std::ptr::null(),
     }
 }
 
-fn parse_column_ranges(row: &DataRowBody) -> Vec<Option<Range<usize>>> {
+/// Decodes the values of a data row, according to the column types of the result set. Each value
+/// is NUL-terminated.
+fn decode_row(row: &DataRowBody, metadata: &Metadata) -> Result<Vec<Option<Vec<u8>>>> {
     let mut ranges = row.ranges();
-    let mut row = vec![];
-    while let Ok(Some(range)) = ranges.next() {
-        row.push(range);
+    let mut values = vec![];
+    while let Some(range) = ranges.next()? {
+        let value = range.map(|range| {
+            let buf = &row.buffer()[range];
+            let mut value = match metadata.col_types.get(values.len()) {
+                Some(ty) if ty.oid() == BYTEA_OID => decode_bytea(buf)?,
+                _ => buf.to_vec(),
+            };
+            value.push(0);
+            Ok::<_, anyhow::Error>(value)
+        });
+        values.push(value.transpose()?);
     }
-    row
+    Ok(values)
+}
+
+/// Decodes a BYTEA value, sent hex-encoded in text format, with or without the `\\x` prefix.
+fn decode_bytea(buf: &[u8]) -> Result<Vec<u8>> {
+    let buf = buf.strip_prefix(b"\\x").unwrap_or(buf);
+    Ok(hex::decode(buf)?)
+}
+
+/*
+ * Modified rows.
+ */
+
+#[no_mangle]
+pub extern "C" fn sqlite3_changes(db: *mut sqlite3) -> c_int {
+    trace!("TRACE sqlite3_changes");
+    sqlite3_changes64(db) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_changes64(db: *mut sqlite3) -> sqlite3_int64 {
+    trace!("TRACE sqlite3_changes64");
+    to_database(db).changes.get()
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_total_changes(db: *mut sqlite3) -> c_int {
+    trace!("TRACE sqlite3_total_changes");
+    sqlite3_total_changes64(db) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_total_changes64(db: *mut sqlite3) -> sqlite3_int64 {
+    trace!("TRACE sqlite3_total_changes64");
+    to_database(db).total_changes.get()
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_last_insert_rowid(db: *mut sqlite3) -> sqlite3_int64 {
+    trace!("TRACE sqlite3_last_insert_rowid");
+    to_database(db).last_insert_rowid.get()
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_set_last_insert_rowid(db: *mut sqlite3, rowid: sqlite3_int64) {
+    trace!("TRACE sqlite3_set_last_insert_rowid");
+    to_database(db).last_insert_rowid.set(rowid);
 }
 
 /*
@@ -617,18 +959,11 @@ define_stub!(sqlite3_blob_write);
 define_stub!(sqlite3_busy_handler);
 define_stub!(sqlite3_busy_timeout);
 define_stub!(sqlite3_cancel_auto_extension);
-define_stub!(sqlite3_changes);
-define_stub!(sqlite3_changes64);
-define_stub!(sqlite3_clear_bindings);
 define_stub!(sqlite3_collation_needed);
 define_stub!(sqlite3_collation_needed16);
-define_stub!(sqlite3_column_blob);
-define_stub!(sqlite3_column_bytes16);
 define_stub!(sqlite3_column_database_name);
 define_stub!(sqlite3_column_database_name16);
 define_stub!(sqlite3_column_decltype16);
-define_stub!(sqlite3_column_double);
-define_stub!(sqlite3_column_int);
 define_stub!(sqlite3_column_name16);
 define_stub!(sqlite3_column_origin_name);
 define_stub!(sqlite3_column_origin_name16);
@@ -689,7 +1024,6 @@ define_stub!(sqlite3_interrupt);
 define_stub!(sqlite3_keyword_check);
 define_stub!(sqlite3_keyword_count);
 define_stub!(sqlite3_keyword_name);
-define_stub!(sqlite3_last_insert_rowid);
 define_stub!(sqlite3_limit);
 define_stub!(sqlite3_load_extension);
 define_stub!(sqlite3_log);
@@ -747,7 +1081,6 @@ define_stub!(sqlite3_rollback_hook);
 define_stub!(sqlite3_serialize);
 define_stub!(sqlite3_set_authorizer);
 define_stub!(sqlite3_set_auxdata);
-define_stub!(sqlite3_set_last_insert_rowid);
 define_stub!(sqlite3_sleep);
 define_stub!(sqlite3_snapshot_cmp);
 define_stub!(sqlite3_snapshot_free);
@@ -787,8 +1120,6 @@ define_stub!(sqlite3_table_column_metadata);
 define_stub!(sqlite3_test_control);
 define_stub!(sqlite3_thread_cleanup);
 define_stub!(sqlite3_threadsafe);
-define_stub!(sqlite3_total_changes);
-define_stub!(sqlite3_total_changes64);
 define_stub!(sqlite3_trace);
 define_stub!(sqlite3_trace_v2);
 define_stub!(sqlite3_transfer_bindings);
@@ -839,3 +1170,137 @@ define_stub!(sqlite3_wal_hook);
 define_stub!(sqlite3_win32_set_directory);
 define_stub!(sqlite3_win32_set_directory16);
 define_stub!(sqlite3_win32_set_directory8);
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use bytes::{BufMut, BytesMut};
+    use postgres_protocol::message::backend;
+    use postgres_types::Type;
+
+    use super::*;
+
+    /// Prepares `sql` on a connection to a server that never answers: the statement can be
+    /// inspected, but not run. The listener must outlive the statement.
+    fn prepare(sql: &str) -> (TcpListener, Box<sqlite3_stmt>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("postgres://test@{}", listener.local_addr().unwrap());
+        let conn = postgres::Connection::connect(&addr).unwrap();
+        let stmt = Statement::new(Rc::new(Database::new(conn)), sql.to_owned());
+        (listener, Box::new(sqlite3_stmt { inner: stmt }))
+    }
+
+    /// Builds a data row of `values`, where `None` is NULL.
+    fn data_row(values: &[Option<&[u8]>]) -> DataRowBody {
+        let mut body = BytesMut::new();
+        body.put_i16(values.len() as i16);
+        for value in values {
+            match value {
+                Some(value) => {
+                    body.put_i32(value.len() as i32);
+                    body.put_slice(value);
+                }
+                None => body.put_i32(-1),
+            }
+        }
+        let mut buf = BytesMut::new();
+        buf.put_u8(b'D');
+        buf.put_i32(body.len() as i32 + 4);
+        buf.put_slice(&body);
+        match backend::Message::parse(&mut buf).unwrap() {
+            Some(backend::Message::DataRow(row)) => row,
+            _ => unreachable!("not a data row"),
+        }
+    }
+
+    /// Returns the metadata of a result set with columns of the given types.
+    fn metadata(col_types: &[Type]) -> Metadata {
+        let mut metadata = Metadata::new();
+        metadata.col_types = col_types.to_vec();
+        metadata
+    }
+
+    #[test]
+    fn bind_parameters() {
+        let (_listener, mut stmt) = prepare("SELECT ?, ?, ?");
+        let ptr = &mut *stmt as *mut sqlite3_stmt;
+        let text = "hello, world";
+        let transient = SQLITE_TRANSIENT as isize as *const c_void;
+
+        assert_eq!(sqlite3_bind_int64(ptr, 1, 42), SQLITE_OK);
+        // Only the first `n` bytes of the text are bound.
+        assert_eq!(
+            sqlite3_bind_text(ptr, 3, text.as_ptr() as *const c_char, 5, transient),
+            SQLITE_OK
+        );
+        // The parameters that weren't bound are NULL.
+        assert!(matches!(
+            stmt.inner.params[..],
+            [Value::Integer(42), Value::Null, Value::Text(ref s)] if s == "hello"
+        ));
+
+        // Binding a parameter again replaces its value.
+        assert_eq!(sqlite3_bind_double(ptr, 1, 1.5), SQLITE_OK);
+        assert!(matches!(stmt.inner.params[0], Value::Real(x) if x == 1.5));
+
+        sqlite3_clear_bindings(ptr);
+        assert!(stmt.inner.params.is_empty());
+    }
+
+    #[test]
+    fn bind_out_of_range() {
+        let (_listener, mut stmt) = prepare("SELECT ?");
+        let ptr = &mut *stmt as *mut sqlite3_stmt;
+
+        // Parameters are numbered from 1.
+        assert_eq!(sqlite3_bind_null(ptr, 0), SQLITE_RANGE);
+        assert_eq!(sqlite3_bind_int(ptr, -1, 1), SQLITE_RANGE);
+        assert_eq!(stmt.inner.parent.error.borrow().0, SQLITE_RANGE);
+        assert!(stmt.inner.params.is_empty());
+    }
+
+    #[test]
+    fn decode_values() {
+        let metadata = metadata(&[Type::BYTEA, Type::BYTEA, Type::TEXT, Type::INT8]);
+        let row = data_row(&[Some(b"\\x00ff"), Some(b"0a"), Some(b"hello"), None]);
+        let values = decode_row(&row, &metadata).unwrap();
+        assert_eq!(
+            values,
+            [
+                Some(b"\x00\xff\0".to_vec()),
+                Some(b"\n\0".to_vec()),
+                Some(b"hello\0".to_vec()),
+                None,
+            ]
+        );
+
+        let row = data_row(&[Some(b"\\xnot hex")]);
+        assert!(decode_row(&row, &metadata).is_err());
+    }
+
+    #[test]
+    fn numeric_column_type() {
+        let (_listener, mut stmt) = prepare("SELECT n FROM numbers");
+        stmt.inner.metadata = Some(metadata(&[Type::NUMERIC, Type::NUMERIC, Type::NUMERIC]));
+        stmt.inner.current_row = Some(vec![Some(b"42\0".to_vec()), Some(b"1.5\0".to_vec()), None]);
+        let ptr = &mut *stmt as *mut sqlite3_stmt;
+
+        // The type of a numeric value depends on its content.
+        assert_eq!(sqlite3_column_type(ptr, 0), SQLITE_INTEGER);
+        assert_eq!(sqlite3_column_type(ptr, 1), SQLITE_FLOAT);
+        assert_eq!(sqlite3_column_type(ptr, 2), SQLITE_NULL);
+        assert_eq!(sqlite3_column_int64(ptr, 1), 1);
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(sqlstate_to_code("23505"), SQLITE_CONSTRAINT_UNIQUE);
+        assert_eq!(sqlstate_to_code("23000"), SQLITE_CONSTRAINT);
+        assert_eq!(sqlstate_to_code("40001"), SQLITE_BUSY);
+        assert_eq!(sqlstate_to_code("25P03"), SQLITE_ABORT);
+        assert_eq!(sqlstate_to_code("57014"), SQLITE_INTERRUPT);
+        assert_eq!(sqlstate_to_code("28P01"), SQLITE_AUTH);
+        assert_eq!(sqlstate_to_code("XX000"), SQLITE_ERROR);
+    }
+}
@@ -1,18 +1,27 @@
-use anyhow::{Context, Result};
-use bytes::BytesMut;
+use anyhow::{bail, Context, Result};
+use bytes::{BufMut, BytesMut};
 use fallible_iterator::FallibleIterator;
-use postgres_protocol::message::backend::DataRowBody;
+use postgres_protocol::message::backend::{DataRowBody, ErrorResponseBody};
+use postgres_protocol::message::frontend::BindError;
 use postgres_protocol::message::{backend, frontend};
+use postgres_protocol::IsNull;
 use postgres_types::Type;
 use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::fmt;
 use std::io::prelude::*;
 use std::net::TcpStream;
 use tracing::trace;
 use url::Url;
 
 pub struct Metadata {
-    pub col_names: Vec<String>,
+    pub col_names: Vec<CString>,
     pub col_types: Vec<Type>,
+    /// Number of rows inserted, updated or deleted, from the command tag.
+    pub changes: Option<i64>,
+    /// Rowid of the last inserted row, reported by the server before the command tag of an
+    /// `INSERT`.
+    pub last_insert_rowid: Option<i64>,
 }
 
 impl Metadata {
@@ -22,9 +31,93 @@ impl Metadata {
         Metadata {
             col_names,
             col_types,
+            changes: None,
+            last_insert_rowid: None,
+        }
+    }
+
+    /// Parses a command tag. The server sends `INSERT 0 <rows>`, `UPDATE <rows>` and
+    /// `DELETE <rows>` for statements that modify rows.
+    fn parse_tag(&mut self, tag: &str) {
+        let mut words = tag.split_whitespace();
+        let rows = match words.next() {
+            // The second word is the OID of the inserted row, which is always 0.
+            Some("INSERT") => words.nth(1),
+            Some("UPDATE" | "DELETE") => words.next(),
+            _ => None,
+        };
+        if let Some(rows) = rows {
+            self.changes = rows.parse().ok();
+        }
+    }
+
+    /// Parses a parameter status. The server reports the rowid of the last inserted row as the
+    /// `last_insert_rowid` parameter.
+    fn parse_parameter_status(&mut self, name: &str, value: &str) {
+        if name == "last_insert_rowid" {
+            self.last_insert_rowid = value.parse().ok();
+        }
+    }
+}
+
+/// An error reported by the server with an ErrorResponse.
+#[derive(Debug)]
+pub struct ServerError {
+    /// The SQLSTATE code of the error.
+    pub code: String,
+    pub message: String,
+}
+
+impl ServerError {
+    fn parse(body: &ErrorResponseBody) -> Result<Self> {
+        let mut code = String::new();
+        let mut message = String::new();
+        let mut fields = body.fields();
+        while let Some(field) = fields.next()? {
+            match field.type_() {
+                b'C' => code = field.value().to_owned(),
+                b'M' => message = field.value().to_owned(),
+                _ => (),
+            }
+        }
+        Ok(Self { code, message })
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// A value bound to a statement parameter.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    /// The type the parameter is sent as.
+    fn ty(&self) -> Type {
+        match self {
+            Value::Null | Value::Text(_) => Type::VARCHAR,
+            Value::Integer(_) => Type::INT8,
+            Value::Real(_) => Type::FLOAT8,
+            Value::Blob(_) => Type::BYTEA,
         }
     }
 }
+
+/// Format codes of the extended query protocol.
+const TEXT_FORMAT: i16 = 0;
+const BINARY_FORMAT: i16 = 1;
+
 pub struct Connection {
     stream: TcpStream,
     rx_buf: BytesMut,
@@ -63,21 +156,68 @@ impl Connection {
         Ok(())
     }
 
+    /// Runs `sql` with the extended query protocol, binding `params` to its placeholders.
+    /// Parameters are sent in binary format, and results are requested in text format.
+    pub fn send_extended_query(&mut self, sql: &str, params: &[Value]) -> Result<()> {
+        let mut msg = BytesMut::new();
+        frontend::parse("", sql, params.iter().map(|p| p.ty().oid()), &mut msg)?;
+        frontend::bind(
+            "",
+            "",
+            params.iter().map(|_| BINARY_FORMAT),
+            params,
+            |value, buf| {
+                match value {
+                    Value::Null => return Ok(IsNull::Yes),
+                    Value::Integer(i) => buf.put_i64(*i),
+                    Value::Real(x) => buf.put_f64(*x),
+                    Value::Text(s) => buf.put_slice(s.as_bytes()),
+                    Value::Blob(b) => buf.put_slice(b),
+                }
+                Ok(IsNull::No)
+            },
+            Some(TEXT_FORMAT),
+            &mut msg,
+        )
+        .map_err(|e| match e {
+            BindError::Conversion(e) => anyhow::anyhow!(e),
+            BindError::Serialization(e) => e.into(),
+        })?;
+        frontend::describe(b'P', "", &mut msg)?;
+        frontend::execute("", 0, &mut msg)?;
+        frontend::sync(&mut msg);
+        self.stream.write_all(&msg)?;
+        Ok(())
+    }
+
+    /// Processes messages until the server is ready for the next query. If the server reported an
+    /// error in the meantime, it is returned as a `ServerError`.
     pub fn wait_until_ready(&mut self) -> Result<(Metadata, VecDeque<DataRowBody>)> {
         let mut metadata = Metadata::new();
         let mut rows = VecDeque::default();
+        let mut error = None;
         loop {
             let msg = backend::Message::parse(&mut self.rx_buf)?;
             match msg {
                 Some(msg) => {
-                    if !self.process_msg(msg, &mut metadata, &mut rows) {
-                        return Ok((metadata, rows));
+                    if !self.process_msg(msg, &mut metadata, &mut rows, &mut error)? {
+                        return match error {
+                            Some(error) => Err(error.into()),
+                            None => Ok((metadata, rows)),
+                        };
                     }
                 }
                 None => {
                     // FIXME: Optimize with spare_capacity_mut() to make zero-copy.
                     let mut buf = [0u8; 1024];
                     let nr = self.stream.read(&mut buf)?;
+                    if nr == 0 {
+                        // The server closes the connection after a fatal error.
+                        match error {
+                            Some(error) => return Err(error.into()),
+                            None => bail!("connection closed by the server"),
+                        }
+                    }
                     self.rx_buf.extend_from_slice(&buf[0..nr]);
                 }
             }
@@ -89,7 +229,8 @@ impl Connection {
         msg: backend::Message,
         metadata: &mut Metadata,
         rows: &mut VecDeque<DataRowBody>,
-    ) -> bool {
+        error: &mut Option<ServerError>,
+    ) -> Result<bool> {
         match msg {
             backend::Message::AuthenticationCleartextPassword => todo!(),
             backend::Message::AuthenticationGss => todo!(),
@@ -107,10 +248,13 @@ impl Connection {
             backend::Message::BackendKeyData(_) => {
                 trace!("TRACE postgres -> BackendKeyData");
             }
-            backend::Message::BindComplete => todo!(),
+            backend::Message::BindComplete => {
+                trace!("TRACE postgres -> BindComplete");
+            }
             backend::Message::CloseComplete => todo!(),
-            backend::Message::CommandComplete(_) => {
+            backend::Message::CommandComplete(body) => {
                 trace!("TRACE postgres -> CommandComplete");
+                metadata.parse_tag(body.tag()?);
             }
             backend::Message::CopyData(_) => todo!(),
             backend::Message::CopyDone => todo!(),
@@ -120,36 +264,82 @@ impl Connection {
                 trace!("TRACE postgres -> DataRow");
                 rows.push_back(row);
             }
-            backend::Message::EmptyQueryResponse => todo!(),
-            backend::Message::ErrorResponse(_) => {
+            // The query had no statement: it has neither rows nor changes.
+            backend::Message::EmptyQueryResponse => {
+                trace!("TRACE postgres -> EmptyQueryResponse");
+            }
+            backend::Message::ErrorResponse(body) => {
                 trace!("TRACE postgres -> ErrorResponse");
+                *error = Some(ServerError::parse(&body)?);
+            }
+            backend::Message::NoData => {
+                trace!("TRACE postgres -> NoData");
             }
-            backend::Message::NoData => todo!(),
             backend::Message::NoticeResponse(_) => {
                 trace!("TRACE postgres -> NoticeResponse");
             }
             backend::Message::NotificationResponse(_) => todo!(),
-            backend::Message::ParameterDescription(_) => todo!(),
-            backend::Message::ParameterStatus(_) => {
+            backend::Message::ParameterDescription(_) => {
+                trace!("TRACE postgres -> ParameterDescription");
+            }
+            backend::Message::ParameterStatus(body) => {
                 trace!("TRACE postgres -> ParameterStatus");
+                metadata.parse_parameter_status(body.name()?, body.value()?);
+            }
+            backend::Message::ParseComplete => {
+                trace!("TRACE postgres -> ParseComplete");
+            }
+            // Portals are executed without a row limit, and never resumed: the rows sent so far are
+            // the result.
+            backend::Message::PortalSuspended => {
+                trace!("TRACE postgres -> PortalSuspended");
             }
-            backend::Message::ParseComplete => todo!(),
-            backend::Message::PortalSuspended => todo!(),
             backend::Message::ReadyForQuery(_) => {
                 trace!("TRACE postgres -> ReadyForQuery");
-                return false;
+                return Ok(false);
             }
             backend::Message::RowDescription(row_description) => {
                 trace!("TRACE postgres -> RowDescription");
                 let mut fields = row_description.fields();
                 while let Some(field) = fields.next().unwrap() {
-                    metadata.col_names.push(field.name().into());
+                    metadata.col_names.push(CString::new(field.name()).unwrap());
                     let ty = Type::from_oid(field.type_oid()).unwrap();
                     metadata.col_types.push(ty);
                 }
             }
             _ => todo!(),
         }
-        true
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_tag() {
+        let mut metadata = Metadata::new();
+        metadata.parse_tag("INSERT 0 3");
+        assert_eq!(metadata.changes, Some(3));
+        metadata.parse_tag("UPDATE 2");
+        assert_eq!(metadata.changes, Some(2));
+        metadata.parse_tag("DELETE 0");
+        assert_eq!(metadata.changes, Some(0));
+
+        // Other commands don't modify rows.
+        let mut metadata = Metadata::new();
+        metadata.parse_tag("SELECT 5");
+        metadata.parse_tag("CREATE");
+        assert_eq!(metadata.changes, None);
+    }
+
+    #[test]
+    fn parse_last_insert_rowid() {
+        let mut metadata = Metadata::new();
+        metadata.parse_parameter_status("TimeZone", "UTC");
+        assert_eq!(metadata.last_insert_rowid, None);
+        metadata.parse_parameter_status("last_insert_rowid", "42");
+        assert_eq!(metadata.last_insert_rowid, Some(42));
     }
 }
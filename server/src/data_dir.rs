@@ -0,0 +1,168 @@
+//! Layout of the data directory, that holds all the state of a sqld instance: the database, the
+//! WAL log of a primary, and the replication index of a replica.
+//!
+//! The directory is locked for as long as the instance runs, so that two instances can't share
+//! it.
+//!
+//! Earlier versions kept their files in the working directory instead. When a data directory is
+//! created, the files of that layout found next to it are moved into it.
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+const DB_FILE: &str = "data.db";
+const WAL_LOG_FILE: &str = "wallog";
+const REPLICA_INDEX_FILE: &str = "replica_index";
+const LOCK_FILE: &str = "sqld.lock";
+/// The files of the layout of earlier versions, along with their name in the data directory.
+const LEGACY_FILES: [(&str, &str); 5] = [
+    ("iku.db", DB_FILE),
+    ("iku.db-wal", "data.db-wal"),
+    ("iku.db-shm", "data.db-shm"),
+    ("wallog", WAL_LOG_FILE),
+    (".wal_index.iku", REPLICA_INDEX_FILE),
+];
+
+pub struct DataDir {
+    path: PathBuf,
+    /// Holds the lock on the directory until it is dropped.
+    _lock: File,
+}
+
+impl DataDir {
+    /// Opens the data directory at `path`, creating it if needed, and locks it. Fails if another
+    /// process holds the lock.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if path.is_file() {
+            bail!(
+                "data directory {} is a file. Earlier versions took the path of the database \
+                instead: pass a directory, and a database found next to it as `iku.db` is moved \
+                into it",
+                path.display()
+            );
+        }
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("failed to create data directory {}", path.display()))?;
+
+        let mut lock = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(path.join(LOCK_FILE))?;
+        // The lock is released by the OS when the process exits, even if it crashes.
+        let ret = unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if ret != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::WouldBlock {
+                bail!(
+                    "data directory {} is used by another process",
+                    path.display()
+                );
+            }
+            return Err(e.into());
+        }
+        // The pid of the owner is only informative.
+        lock.set_len(0)?;
+        writeln!(lock, "{}", std::process::id())?;
+
+        let data_dir = Self { path, _lock: lock };
+        data_dir.migrate_legacy_files()?;
+
+        Ok(data_dir)
+    }
+
+    /// Moves the files of the layout of earlier versions, that are next to the data directory,
+    /// into it. Nothing is moved once the data directory holds a database.
+    fn migrate_legacy_files(&self) -> anyhow::Result<()> {
+        if self.db_path().exists() {
+            return Ok(());
+        }
+        let parent = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        for (legacy_name, name) in LEGACY_FILES {
+            let legacy_path = parent.join(legacy_name);
+            if !legacy_path.is_file() {
+                continue;
+            }
+            let new_path = self.path.join(name);
+            tracing::info!("moving {} to {}", legacy_path.display(), new_path.display());
+            std::fs::rename(&legacy_path, &new_path).with_context(|| {
+                format!(
+                    "failed to move {} into the data directory",
+                    legacy_path.display()
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.path.join(DB_FILE)
+    }
+
+    pub fn wal_log_path(&self) -> PathBuf {
+        self.path.join(WAL_LOG_FILE)
+    }
+
+    /// Path of the file where a replica persists the index of the last transaction it applied.
+    pub fn replica_index_path(&self) -> PathBuf {
+        self.path.join(REPLICA_INDEX_FILE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock_data_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data.sqld");
+        let data_dir = DataDir::open(&path).unwrap();
+        assert!(DataDir::open(&path).is_err());
+
+        drop(data_dir);
+        assert!(DataDir::open(&path).is_ok());
+    }
+
+    #[test]
+    fn data_dir_is_a_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("iku.db");
+        std::fs::write(&path, b"").unwrap();
+
+        assert!(DataDir::open(&path).is_err());
+    }
+
+    #[test]
+    fn migrate_legacy_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        for (legacy_name, _) in LEGACY_FILES {
+            std::fs::write(tmp.path().join(legacy_name), legacy_name).unwrap();
+        }
+
+        let data_dir = DataDir::open(tmp.path().join("data.sqld")).unwrap();
+        for (legacy_name, name) in LEGACY_FILES {
+            assert!(!tmp.path().join(legacy_name).exists());
+            let content = std::fs::read_to_string(data_dir.path.join(name)).unwrap();
+            assert_eq!(content, legacy_name);
+        }
+
+        // legacy files are left alone once the data directory has a database.
+        drop(data_dir);
+        std::fs::write(tmp.path().join("iku.db"), "other").unwrap();
+        let data_dir = DataDir::open(tmp.path().join("data.sqld")).unwrap();
+        assert!(tmp.path().join("iku.db").exists());
+        assert_eq!(
+            std::fs::read_to_string(data_dir.db_path()).unwrap(),
+            "iku.db"
+        );
+    }
+}
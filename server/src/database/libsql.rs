@@ -1,39 +1,75 @@
+use std::collections::HashMap;
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
 #[cfg(feature = "mwal_backend")]
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
 use crossbeam::channel::RecvTimeoutError;
-use rusqlite::{params_from_iter, OpenFlags};
-use tokio::sync::oneshot;
+use futures::{future, stream, StreamExt};
+use once_cell::sync::{Lazy, OnceCell};
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::OpenFlags;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_stream::wrappers::ReceiverStream;
 use tracing::warn;
 
+use crate::libsql::open_with_regular_wal;
 use crate::libsql::wal_hook::WalHook;
+use crate::metrics;
 use crate::query::{
-    Column, ErrorCode, QueryError, QueryResponse, QueryResult, ResultSet, Row, Value,
+    Column, Command, Description, ErrorCode, Params, QueryError, QueryResponse, QueryResult,
+    ResultSet, Row, Value,
 };
 use crate::query_analysis::{State, Statements};
 
-use super::{Database, TXN_TIMEOUT_SECS};
+use super::Database;
+
+/// Number of database workers still running. Used to wait for them to terminate on shutdown.
+static RUNNING_WORKERS: Lazy<watch::Sender<usize>> = Lazy::new(|| watch::channel(0).0);
+
+/// Counts a running worker, until it is dropped.
+struct WorkerGuard;
+
+impl WorkerGuard {
+    fn new() -> Self {
+        RUNNING_WORKERS.send_modify(|count| *count += 1);
+        Self
+    }
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        RUNNING_WORKERS.send_modify(|count| *count -= 1);
+    }
+}
+
+/// A request to the database worker, with the channel its response is sent back on.
+enum Message {
+    Query(Statements, Params, oneshot::Sender<Vec<QueryResult>>),
+    Describe(String, oneshot::Sender<Result<Description, QueryError>>),
+}
 
 #[derive(Clone)]
 pub struct LibSqlDb {
-    sender: crossbeam::channel::Sender<(Statements, Vec<Value>, oneshot::Sender<QueryResult>)>,
+    sender: crossbeam::channel::Sender<Message>,
+    /// Interrupts the statement the worker is running. Set once the worker has opened its
+    /// connection.
+    interrupt: Arc<OnceCell<rusqlite::InterruptHandle>>,
 }
 
-fn execute_query(
-    conn: &rusqlite::Connection,
-    stmts: &Statements,
-    params: Vec<Value>,
-) -> QueryResult {
-    let mut rows = vec![];
-    let mut prepared = conn.prepare(&stmts.stmts)?;
-    let columns = prepared
+/// Number of rows of a streamed result set that are buffered until the client reads them.
+const STREAMED_ROWS_BUFFER: usize = 64;
+
+fn columns(prepared: &rusqlite::Statement) -> Vec<Column> {
+    prepared
         .columns()
         .iter()
         .map(|col| Column {
             name: col.name().into(),
+            decltype: col.decl_type().map(ToOwned::to_owned),
             ty: col
                 .decl_type()
                 .map(FromStr::from_str)
@@ -41,20 +77,202 @@ fn execute_query(
                 .ok()
                 .flatten(),
         })
-        .collect::<Vec<_>>();
-    let mut qresult = prepared.query(params_from_iter(
-        params.into_iter().map(rusqlite::types::Value::from),
-    ))?;
+        .collect()
+}
+
+/// Prepares a single statement without executing it, and describes it.
+fn describe(conn: &rusqlite::Connection, sql: &str) -> Result<Description, QueryError> {
+    let prepared = conn.prepare(sql)?;
+    Ok(Description {
+        param_count: prepared.parameter_count(),
+        columns: columns(&prepared),
+    })
+}
+
+/// Records the command of the statements prepared by a connection, as its authorizer reports
+/// them: the first row modification made by the statement itself, rather than by its triggers.
+/// Schema changes, that modify the internal tables of SQLite, are not recorded.
+#[derive(Clone, Default)]
+struct CommandRecorder(Arc<AtomicU8>);
+
+impl CommandRecorder {
+    fn install(conn: &rusqlite::Connection) -> Self {
+        let recorder = Self::default();
+        let command = recorder.0.clone();
+        conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+            let recorded = match ctx.action {
+                _ if ctx.accessor.is_some() => None,
+                AuthAction::Insert { table_name }
+                | AuthAction::Update { table_name, .. }
+                | AuthAction::Delete { table_name }
+                    if table_name.starts_with("sqlite_") =>
+                {
+                    None
+                }
+                AuthAction::Insert { .. } => Some(Command::Insert),
+                AuthAction::Update { .. } => Some(Command::Update),
+                AuthAction::Delete { .. } => Some(Command::Delete),
+                _ => None,
+            };
+            if let Some(recorded) = recorded {
+                let _ = command.compare_exchange(
+                    Command::Other as u8,
+                    recorded as u8,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            }
+            Authorization::Allow
+        }));
+
+        recorder
+    }
+
+    /// Returns the command of the statement prepared since the last call.
+    fn take(&self) -> Command {
+        match self.0.swap(Command::Other as u8, Ordering::Relaxed) {
+            c if c == Command::Insert as u8 => Command::Insert,
+            c if c == Command::Update as u8 => Command::Update,
+            c if c == Command::Delete as u8 => Command::Delete,
+            _ => Command::Other,
+        }
+    }
+}
+
+/// Prepares a single statement, and binds its parameters. Returns the command it runs, along with
+/// its columns.
+fn prepare_query<'a>(
+    conn: &'a rusqlite::Connection,
+    commands: &CommandRecorder,
+    stmts: &Statements,
+    params: &Params,
+) -> Result<(rusqlite::Statement<'a>, Vec<Column>, Command), QueryError> {
+    commands.take();
+    let mut prepared = conn.prepare(&stmts.stmts)?;
+    let command = commands.take();
+    let columns = columns(&prepared);
+    match params {
+        Params::Positional(values) => bind_positional_params(&mut prepared, values)?,
+        Params::Named(values) => bind_named_params(&mut prepared, values)?,
+    }
+
+    Ok((prepared, columns, command))
+}
+
+fn read_row(row: &rusqlite::Row, column_count: usize) -> Result<Row, QueryError> {
+    let mut values = Vec::with_capacity(column_count);
+    for i in 0..column_count {
+        values.push(row.get::<usize, rusqlite::types::Value>(i)?.into());
+    }
+
+    Ok(Row { values })
+}
+
+/// Executes a prepared statement to completion, buffering its rows.
+fn execute_query(
+    conn: &rusqlite::Connection,
+    mut prepared: rusqlite::Statement,
+    columns: Vec<Column>,
+    command: Command,
+) -> QueryResult {
+    let mut rows = vec![];
+    let mut qresult = prepared.raw_query();
     while let Some(row) = qresult.next()? {
-        let mut values = vec![];
-        for (i, _) in columns.iter().enumerate() {
-            values.push(row.get::<usize, rusqlite::types::Value>(i)?.into());
+        rows.push(Ok(read_row(row, columns.len())?));
+    }
+
+    // The counters are only updated by INSERT, UPDATE and DELETE statements, so they are stale
+    // after any other statement.
+    let (affected_row_count, last_insert_rowid) = match command {
+        Command::Other => (0, None),
+        _ => (conn.changes(), Some(conn.last_insert_rowid())),
+    };
+
+    Ok(QueryResponse::ResultSet(ResultSet {
+        columns,
+        rows: Box::pin(stream::iter(rows)),
+        affected_row_count,
+        last_insert_rowid,
+        command,
+    }))
+}
+
+/// Steps through the rows of a prepared statement, sending each of them on `sender`. Stops early
+/// when the receiving end is dropped, or when it doesn't read a row within `timeout`, which is
+/// reported as an error. Returns whether all the rows were read.
+fn stream_rows(
+    mut prepared: rusqlite::Statement,
+    column_count: usize,
+    sender: mpsc::Sender<Result<Row, QueryError>>,
+    timeout: Duration,
+) -> Result<bool, QueryError> {
+    // The worker runs on a blocking thread of the runtime.
+    let runtime = tokio::runtime::Handle::current();
+    let mut qresult = prepared.raw_query();
+    loop {
+        let row = match qresult.next() {
+            Ok(Some(row)) => read_row(row, column_count),
+            Ok(None) => return Ok(true),
+            Err(e) => Err(e.into()),
+        };
+        let failed = row.is_err();
+        match runtime.block_on(tokio::time::timeout(timeout, sender.send(row))) {
+            Ok(Ok(())) if !failed => (),
+            Ok(_) => return Ok(false),
+            Err(_) => {
+                return Err(QueryError::new(
+                    ErrorCode::TxTimeout,
+                    format!("rows were not read within {}s", timeout.as_secs()),
+                ))
+            }
         }
-        rows.push(Row { values });
     }
-    Ok(QueryResponse::ResultSet(ResultSet { columns, rows }))
 }
 
+/// Binds positional parameters to `stmt`. The parameters of a batch are shared by all its
+/// statements, so a statement may leave some of them unused.
+fn bind_positional_params(
+    stmt: &mut rusqlite::Statement,
+    values: &[Value],
+) -> Result<(), QueryError> {
+    for i in 1..=stmt.parameter_count() {
+        let value = values.get(i - 1).ok_or_else(|| {
+            QueryError::new(ErrorCode::SQLError, format!("missing parameter {i}"))
+        })?;
+        stmt.raw_bind_parameter(i, rusqlite::types::Value::from(value.clone()))?;
+    }
+
+    Ok(())
+}
+
+/// Binds named parameters to `stmt`. Parameters may be named with or without their prefix: a
+/// value for `name` is bound to any of `:name`, `@name` and `$name`.
+fn bind_named_params(
+    stmt: &mut rusqlite::Statement,
+    values: &HashMap<String, Value>,
+) -> Result<(), QueryError> {
+    for i in 1..=stmt.parameter_count() {
+        let Some(name) = stmt.parameter_name(i).map(ToOwned::to_owned) else {
+            return Err(QueryError::new(
+                ErrorCode::SQLError,
+                format!("parameter {i} is positional, but named parameters were provided"),
+            ));
+        };
+        let value = values
+            .get(&name)
+            .or_else(|| values.get(&name[1..]))
+            .ok_or_else(|| {
+                QueryError::new(ErrorCode::SQLError, format!("missing parameter `{name}`"))
+            })?;
+        stmt.raw_bind_parameter(i, rusqlite::types::Value::from(value.clone()))?;
+    }
+
+    Ok(())
+}
+
+const CHECKPOINT_RETRIES: usize = 10;
+const CHECKPOINT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 fn rollback(conn: &rusqlite::Connection) {
     conn.execute("rollback transaction;", ())
         .expect("failed to rollback");
@@ -75,14 +293,15 @@ impl LibSqlDb {
             Arc<Mutex<mwal::ffi::libsql_wal_methods>>,
         >,
         wal_hook: impl WalHook + Send + Clone + 'static,
+        txn_timeout: Duration,
     ) -> anyhow::Result<Self> {
-        let (sender, receiver) = crossbeam::channel::unbounded::<(
-            Statements,
-            Vec<Value>,
-            oneshot::Sender<QueryResult>,
-        )>();
+        let (sender, receiver) = crossbeam::channel::unbounded::<Message>();
+        let interrupt = Arc::new(OnceCell::new());
 
+        let guard = WorkerGuard::new();
+        let interrupt_handle = interrupt.clone();
         tokio::task::spawn_blocking(move || {
+            let _guard = guard;
             let mut retries = 0;
             let conn = loop {
                 #[cfg(feature = "mwal_backend")]
@@ -138,16 +357,21 @@ impl LibSqlDb {
                     }
                 }
             };
+            let _ = interrupt_handle.set(conn.get_interrupt_handle());
+            let commands = CommandRecorder::install(&conn);
 
             let mut state = State::Start;
+            // The session can override the server-wide timeout with `SET txn_timeout`.
+            let mut txn_timeout = txn_timeout;
             let mut timeout_deadline = None;
             let mut timedout = false;
             loop {
-                let (stmts, params, sender) = match timeout_deadline {
+                let msg = match timeout_deadline {
                     Some(deadline) => match receiver.recv_deadline(deadline) {
                         Ok(msg) => msg,
                         Err(RecvTimeoutError::Timeout) => {
                             warn!("transaction timed out");
+                            metrics::TXN_TIMEOUTS.inc();
                             rollback(&conn);
                             timeout_deadline = None;
                             timedout = true;
@@ -161,47 +385,224 @@ impl LibSqlDb {
                         Err(_) => break,
                     },
                 };
+                let (stmts, params, sender) = match msg {
+                    Message::Query(stmts, params, sender) => (stmts, params, sender),
+                    // Describing a statement doesn't affect the transaction.
+                    Message::Describe(sql, sender) => {
+                        ok_or_exit!(sender.send(describe(&conn, &sql)));
+                        continue;
+                    }
+                };
 
                 if !timedout {
-                    let result = execute_query(&conn, &stmts, params);
-                    match stmts.state(state) {
-                        State::TxnOpened => {
-                            timeout_deadline =
-                                Some(Instant::now() + Duration::from_secs(TXN_TIMEOUT_SECS));
-                            state = State::TxnOpened;
+                    if let Some(timeout) = stmts.txn_timeout() {
+                        let result = timeout
+                            .map(|timeout| {
+                                txn_timeout = timeout;
+                                QueryResponse::ResultSet(ResultSet::empty())
+                            })
+                            .map_err(|e| QueryError::new(ErrorCode::SQLError, e));
+                        ok_or_exit!(sender.send(vec![result]));
+                        continue;
+                    }
+
+                    // The statements run in order, until one of them fails.
+                    let mut results = Vec::new();
+                    let mut streamed = None;
+                    let mut batch = stmts.split().peekable();
+                    while let Some(stmt) = batch.next() {
+                        let result = match prepare_query(&conn, &commands, &stmt, &params) {
+                            // The rows of the last statement are streamed rather than buffered,
+                            // unless it modifies rows: the number of modified rows is only known
+                            // once it ran to completion.
+                            Ok((prepared, columns, Command::Other))
+                                if batch.peek().is_none() && !columns.is_empty() =>
+                            {
+                                streamed = Some((stmt, prepared, columns));
+                                break;
+                            }
+                            Ok((prepared, columns, command)) => {
+                                execute_query(&conn, prepared, columns, command)
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let failed = result.is_err();
+                        // A failed statement leaves the transaction as it was.
+                        if !failed {
+                            state = stmt.state(state);
                         }
-                        State::TxnClosed => {
-                            if result.is_ok() {
-                                state = State::Start;
-                                timeout_deadline = None;
+                        results.push(result);
+                        if failed {
+                            break;
+                        }
+                    }
+
+                    // The results are sent as soon as the streamed statement starts, and the
+                    // worker then blocks until its rows are read, for at most the transaction
+                    // timeout per row.
+                    match streamed {
+                        Some((stmt, prepared, columns)) => {
+                            let (row_sender, row_receiver) = mpsc::channel(STREAMED_ROWS_BUFFER);
+                            // Reports the timeout after the rows that were sent.
+                            let (end_sender, end_receiver) = oneshot::channel();
+                            let end = stream::once(end_receiver)
+                                .filter_map(|end| future::ready(end.ok().map(Err)));
+                            let column_count = columns.len();
+                            results.push(Ok(QueryResponse::ResultSet(ResultSet {
+                                columns,
+                                rows: Box::pin(ReceiverStream::new(row_receiver).chain(end)),
+                                affected_row_count: 0,
+                                last_insert_rowid: None,
+                                command: Command::Other,
+                            })));
+                            ok_or_exit!(sender.send(results));
+                            match stream_rows(prepared, column_count, row_sender, txn_timeout) {
+                                Ok(true) => state = stmt.state(state),
+                                Ok(false) => (),
+                                Err(e) => {
+                                    warn!("streamed rows were not read in time");
+                                    metrics::TXN_TIMEOUTS.inc();
+                                    let _ = end_sender.send(e);
+                                    if !conn.is_autocommit() {
+                                        rollback(&conn);
+                                        timedout = true;
+                                    }
+                                    state = State::Start;
+                                }
                             }
                         }
+                        None => ok_or_exit!(sender.send(results)),
+                    }
+
+                    match state {
+                        State::TxnOpened { .. } => {
+                            timeout_deadline = Some(Instant::now() + txn_timeout);
+                        }
+                        State::TxnClosed => {
+                            state = State::Start;
+                            timeout_deadline = None;
+                        }
                         State::Start => (),
                         State::Invalid => panic!("invalid state!"),
                     }
-
-                    ok_or_exit!(sender.send(result));
                 } else {
-                    ok_or_exit!(sender.send(Err(QueryError::new(
+                    ok_or_exit!(sender.send(vec![Err(QueryError::new(
                         ErrorCode::TxTimeout,
-                        "transaction timedout",
-                    ))));
+                        format!("transaction timed out after {}s", txn_timeout.as_secs()),
+                    ))]));
                     timedout = false;
                 }
             }
+
+            // All the handles to this database were dropped: don't leave a transaction open.
+            if !conn.is_autocommit() {
+                warn!("rolling back open transaction");
+                rollback(&conn);
+            }
         });
 
-        Ok(Self { sender })
+        Ok(Self { sender, interrupt })
+    }
+
+    /// Waits until all the database workers have terminated. A worker terminates once all the
+    /// handles to its database are dropped, after rolling back its open transaction, if any.
+    pub async fn wait_for_workers() {
+        let mut workers = RUNNING_WORKERS.subscribe();
+        while *workers.borrow_and_update() != 0 {
+            // the sender is static: this never fails.
+            let _ = workers.changed().await;
+        }
+    }
+
+    /// Checkpoints the database at `path`, copying the content of its WAL back to the main
+    /// database file and truncating the WAL.
+    pub fn checkpoint(path: &Path) -> anyhow::Result<()> {
+        let conn = open_with_regular_wal(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            (),
+        )?;
+        // the checkpoint can't complete while other connections are reading the database.
+        for _ in 0..CHECKPOINT_RETRIES {
+            let busy: i64 =
+                conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |row| row.get(0))?;
+            if busy == 0 {
+                return Ok(());
+            }
+            std::thread::sleep(CHECKPOINT_RETRY_DELAY);
+        }
+
+        anyhow::bail!("database is busy")
     }
 }
 
 #[async_trait::async_trait]
 impl Database for LibSqlDb {
-    async fn execute(&self, query: Statements, params: Vec<Value>) -> QueryResult {
+    async fn execute(&self, query: Statements, params: Params) -> Vec<QueryResult> {
         let (sender, receiver) = oneshot::channel();
-        let _ = self.sender.send((query, params, sender));
+        let _ = self.sender.send(Message::Query(query, params, sender));
         receiver
             .await
-            .map_err(|e| QueryError::new(ErrorCode::Internal, e))?
+            .unwrap_or_else(|e| vec![Err(QueryError::new(ErrorCode::Internal, e))])
+    }
+
+    async fn describe(&self, sql: String) -> Result<Description, QueryError> {
+        let (sender, receiver) = oneshot::channel();
+        let _ = self.sender.send(Message::Describe(sql, sender));
+        receiver
+            .await
+            .unwrap_or_else(|e| Err(QueryError::new(ErrorCode::Internal, e)))
+    }
+
+    fn interrupt(&self) {
+        if let Some(handle) = self.interrupt.get() {
+            handle.interrupt();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands_are_reported_by_sqlite() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "create table t (x);
+            create table log (x);
+            create trigger tr after delete on t begin insert into log values (old.x); end;",
+        )
+        .unwrap();
+        let commands = CommandRecorder::install(&conn);
+        let command = |sql: &str| {
+            let stmts = Statements::parse(sql.to_owned()).unwrap();
+            prepare_query(&conn, &commands, &stmts, &Params::empty())
+                .unwrap()
+                .2
+        };
+
+        assert_eq!(command("select * from t"), Command::Other);
+        assert_eq!(command("-- x\n insert into t values (1)"), Command::Insert);
+        assert_eq!(
+            command("with v(x) as (select 1) insert into t select x from v"),
+            Command::Insert
+        );
+        assert_eq!(
+            command("insert into t values (1) returning x"),
+            Command::Insert
+        );
+        assert_eq!(command("replace into t values (1)"), Command::Insert);
+        assert_eq!(
+            command("update t set x = 2 where x in (select x from log)"),
+            Command::Update
+        );
+        // The trigger inserts into `log`.
+        assert_eq!(command("delete from t"), Command::Delete);
+        assert_eq!(command("create table u (x)"), Command::Other);
+        assert_eq!(command("create index i on t (x)"), Command::Other);
+        assert_eq!(command("begin"), Command::Other);
     }
 }
@@ -1,13 +1,20 @@
-use crate::query::{QueryResult, Value};
+use crate::query::{Description, Params, QueryError, QueryResult};
 use crate::query_analysis::Statements;
 
 pub mod libsql;
 pub mod service;
 pub mod write_proxy;
 
-const TXN_TIMEOUT_SECS: u64 = 5;
-
 #[async_trait::async_trait]
 pub trait Database {
-    async fn execute(&self, query: Statements, params: Vec<Value>) -> QueryResult;
+    /// Executes the statements in order, and returns one result per statement. Execution stops at
+    /// the first statement that fails, whose error is the last result.
+    async fn execute(&self, query: Statements, params: Params) -> Vec<QueryResult>;
+
+    /// Prepares a single statement without executing it, and describes it.
+    async fn describe(&self, sql: String) -> Result<Description, QueryError>;
+
+    /// Interrupts the statement the session is running, if any, which then fails with a
+    /// `QueryCanceled` error. Does nothing if the session is idle.
+    fn interrupt(&self);
 }
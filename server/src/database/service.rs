@@ -1,12 +1,14 @@
 use std::future::ready;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::task::Poll;
 
-use futures::Future;
+use futures::{Future, StreamExt};
+use prometheus::HistogramTimer;
 use tower::Service;
 
 use super::Database;
+use crate::metrics;
 use crate::query::{ErrorCode, Query, QueryError, QueryResponse, QueryResult};
 use crate::query_analysis::Statements;
 pub trait DbFactory: Send + Sync + 'static {
@@ -31,12 +33,22 @@ where
 }
 
 pub struct DbFactoryService<F> {
-    factory: F,
+    factory: Arc<F>,
 }
 
 impl<F> DbFactoryService<F> {
     pub fn new(factory: F) -> Self {
-        Self { factory }
+        Self {
+            factory: Arc::new(factory),
+        }
+    }
+}
+
+impl<F> Clone for DbFactoryService<F> {
+    fn clone(&self) -> Self {
+        Self {
+            factory: self.factory.clone(),
+        }
     }
 }
 
@@ -69,6 +81,26 @@ pub struct DbService<DB> {
     db: Arc<DB>,
 }
 
+/// Interrupts the statement a database session is running, from another task.
+pub type InterruptHandle = Arc<dyn Fn() + Send + Sync>;
+
+/// A service whose running statement can be interrupted.
+pub trait Interrupt {
+    fn interrupt_handle(&self) -> InterruptHandle;
+}
+
+impl<DB: Database + Send + Sync + 'static> Interrupt for DbService<DB> {
+    fn interrupt_handle(&self) -> InterruptHandle {
+        // The handle doesn't keep the session alive once its service is dropped.
+        let db: Weak<DB> = Arc::downgrade(&self.db);
+        Arc::new(move || {
+            if let Some(db) = db.upgrade() {
+                db.interrupt();
+            }
+        })
+    }
+}
+
 impl<DB> Drop for DbService<DB> {
     fn drop(&mut self) {
         tracing::trace!("connection closed");
@@ -76,9 +108,9 @@ impl<DB> Drop for DbService<DB> {
 }
 
 impl<DB: Database + 'static + Send + Sync> Service<Query> for DbService<DB> {
-    type Response = QueryResponse;
+    type Response = Vec<QueryResult>;
     type Error = QueryError;
-    type Future = Pin<Box<dyn Future<Output = QueryResult> + Send>>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
         // need to implement backpressure: one req at a time.
@@ -89,9 +121,82 @@ impl<DB: Database + 'static + Send + Sync> Service<Query> for DbService<DB> {
         let db = self.db.clone();
         match query {
             Query::SimpleQuery(stmts, params) => match Statements::parse(stmts) {
-                Ok(stmts) => Box::pin(async move { db.execute(stmts, params).await }),
+                Ok(stmts) => {
+                    let kind = if stmts.is_read_only() {
+                        "read"
+                    } else {
+                        "write"
+                    };
+                    Box::pin(async move {
+                        metrics::QUERIES.with_label_values(&[kind]).inc();
+                        let timer = metrics::QUERY_LATENCY
+                            .with_label_values(&[kind])
+                            .start_timer();
+                        Ok(time_rows(db.execute(stmts, params).await, timer))
+                    })
+                }
                 Err(e) => Box::pin(ready(Err(QueryError::new(ErrorCode::SQLError, e)))),
             },
+            Query::Describe(sql) => Box::pin(async move {
+                let result = db.describe(sql).await.map(QueryResponse::Description);
+                Ok(vec![result])
+            }),
         }
     }
 }
+
+/// Keeps `timer` running until the rows of all the result sets of `results` are read, or dropped:
+/// the rows are streamed after the statements return them.
+fn time_rows(results: Vec<QueryResult>, timer: HistogramTimer) -> Vec<QueryResult> {
+    let timer = Arc::new(timer);
+    results
+        .into_iter()
+        .map(|result| match result {
+            Ok(QueryResponse::ResultSet(mut set)) => {
+                let timer = timer.clone();
+                set.rows = Box::pin(set.rows.map(move |row| {
+                    let _timer = &timer;
+                    row
+                }));
+                Ok(QueryResponse::ResultSet(set))
+            }
+            result => result,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use futures::stream;
+    use prometheus::{Histogram, HistogramOpts};
+
+    use super::*;
+    use crate::query::{ResultSet, Row, Value};
+
+    #[tokio::test]
+    async fn rows_are_timed() {
+        let histogram = Histogram::with_opts(HistogramOpts::new("test", "test")).unwrap();
+        let rows = (0..3).map(|n| {
+            Ok(Row {
+                values: vec![Value::Integer(n)],
+            })
+        });
+        let set = ResultSet {
+            rows: Box::pin(stream::iter(rows)),
+            ..ResultSet::empty()
+        };
+        let results = vec![
+            Ok(QueryResponse::ResultSet(set)),
+            Ok(QueryResponse::ResultSet(ResultSet::empty())),
+        ];
+
+        let mut results = time_rows(results, histogram.start_timer());
+        let Some(Ok(QueryResponse::ResultSet(set))) = results.pop() else { panic!() };
+        assert_eq!(set.rows.count().await, 0);
+        assert_eq!(histogram.get_sample_count(), 0);
+
+        let Some(Ok(QueryResponse::ResultSet(set))) = results.pop() else { panic!() };
+        assert_eq!(set.rows.count().await, 3);
+        assert_eq!(histogram.get_sample_count(), 1);
+    }
+}
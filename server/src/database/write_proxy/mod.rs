@@ -1,29 +1,40 @@
 mod replication;
 
+use std::collections::HashMap;
 use std::future::{ready, Ready};
 use std::path::PathBuf;
 #[cfg(feature = "mwal_backend")]
 use std::sync::Arc;
 use std::time::Duration;
 
-use crossbeam::channel::TryRecvError;
+use futures::{stream, StreamExt};
 use tokio::sync::Mutex;
 use tonic::transport::Channel;
+use tonic::Streaming;
 use uuid::Uuid;
 
-use crate::query::{ErrorCode, QueryError, QueryResponse, QueryResult, Value};
+use crate::query::{
+    Description, ErrorCode, Params, QueryError, QueryResponse, QueryResult, ResultSet, Row,
+    RowStream,
+};
 use crate::query_analysis::{State, Statements};
 use crate::rpc::proxy::proxy_rpc::proxy_client::ProxyClient;
-use crate::rpc::proxy::proxy_rpc::{query_result, DisconnectMessage, SimpleQuery};
+use crate::rpc::proxy::proxy_rpc::{
+    describe_result::DescribeResult as DescribeOutcome, query_response_message::Message,
+    query_result, DescribeRequest, DisconnectMessage, InterruptMessage, QueryResponseMessage,
+    ResultRows, SimpleQuery,
+};
+use crate::wal_logger::Compression;
 
 use super::{libsql::LibSqlDb, service::DbFactory, Database};
-use replication::PeriodicDbUpdater;
+use replication::DbUpdater;
 
 pub struct WriteProxyDbFactory {
     write_proxy: ProxyClient<Channel>,
     db_path: PathBuf,
     #[cfg(feature = "mwal_backend")]
     vwal_methods: Option<Arc<std::sync::Mutex<mwal::ffi::libsql_wal_methods>>>,
+    txn_timeout: Duration,
     /// abort handle: abort db update loop on drop
     _abort_handle: crossbeam::channel::Sender<()>,
 }
@@ -32,25 +43,23 @@ impl WriteProxyDbFactory {
     pub async fn new(
         addr: String,
         db_path: PathBuf,
+        index_path: PathBuf,
         #[cfg(feature = "mwal_backend")] vwal_methods: Option<
             Arc<std::sync::Mutex<mwal::ffi::libsql_wal_methods>>,
         >,
+        txn_timeout: Duration,
+        compression: Compression,
     ) -> anyhow::Result<Self> {
         let write_proxy = ProxyClient::connect(addr.clone()).await?;
-        let mut db_updater = PeriodicDbUpdater::new(&db_path, addr, Duration::from_secs(1)).await?;
+        let db_updater = DbUpdater::new(&db_path, &index_path, addr, compression).await?;
         let (_abort_handle, receiver) = crossbeam::channel::bounded::<()>(1);
-        tokio::task::spawn_blocking(move || loop {
-            // must abort
-            if let Err(TryRecvError::Disconnected) = receiver.try_recv() {
-                break;
-            }
-            db_updater.step();
-        });
+        tokio::task::spawn_blocking(move || db_updater.run(receiver));
         Ok(Self {
             write_proxy,
             db_path,
             #[cfg(feature = "mwal_backend")]
             vwal_methods,
+            txn_timeout,
             _abort_handle,
         })
     }
@@ -67,6 +76,7 @@ impl DbFactory for WriteProxyDbFactory {
             self.db_path.clone(),
             #[cfg(feature = "mwal_backend")]
             self.vwal_methods.clone(),
+            self.txn_timeout,
         ))
     }
 }
@@ -85,12 +95,14 @@ impl WriteProxyDatabase {
         #[cfg(feature = "mwal_backend")] vwal_methods: Option<
             Arc<std::sync::Mutex<mwal::ffi::libsql_wal_methods>>,
         >,
+        txn_timeout: Duration,
     ) -> anyhow::Result<Self> {
         let read_db = LibSqlDb::new(
             path,
             #[cfg(feature = "mwal_backend")]
             vwal_methods,
             (),
+            txn_timeout,
         )?;
         Ok(Self {
             read_db,
@@ -101,37 +113,168 @@ impl WriteProxyDatabase {
     }
 }
 
+/// Builds a buffered result from its RPC representation, and its rows.
+fn buffered_result(header: ResultRows, rows: Vec<Result<Row, QueryError>>) -> QueryResult {
+    let rows = Box::pin(stream::iter(rows));
+    Ok(QueryResponse::ResultSet(ResultSet::from_rpc(header, rows)))
+}
+
+/// Streams the rows that follow the last result of a response.
+fn stream_rows(stream: Streaming<QueryResponseMessage>) -> RowStream {
+    Box::pin(stream.flat_map(|message| {
+        let rows = match message.map(|m| m.message) {
+            Ok(Some(Message::Rows(rows))) => rows.rows.into_iter().map(|r| Ok(r.into())).collect(),
+            Ok(Some(Message::Error(e))) => vec![Err(e.into())],
+            Ok(_) => Vec::new(),
+            Err(e) => vec![Err(QueryError::new(ErrorCode::Internal, e))],
+        };
+        stream::iter(rows)
+    }))
+}
+
+/// Reads the streamed response to a query of `count` statements. The rows of all the statements
+/// but the last are read in full, while the rows of the last one are streamed to the caller.
+async fn read_results(
+    mut stream: Streaming<QueryResponseMessage>,
+    count: usize,
+) -> Vec<QueryResult> {
+    let mut results = Vec::with_capacity(count);
+    // The result whose rows are being read.
+    let mut current = None;
+    loop {
+        let message = match stream.message().await {
+            Ok(Some(QueryResponseMessage {
+                message: Some(message),
+            })) => message,
+            Ok(Some(_)) => continue,
+            Ok(None) => break,
+            Err(e) => {
+                if let Some((header, rows)) = current.take() {
+                    results.push(buffered_result(header, rows));
+                }
+                results.push(Err(QueryError::new(ErrorCode::Internal, e)));
+                return results;
+            }
+        };
+        match message {
+            Message::Result(result) => {
+                if let Some((header, rows)) = current.take() {
+                    results.push(buffered_result(header, rows));
+                }
+                match result.result() {
+                    query_result::Result::Ok => {
+                        let header = result.rows.expect("invalid response");
+                        if results.len() + 1 == count {
+                            let rows = stream_rows(stream);
+                            results.push(Ok(QueryResponse::ResultSet(ResultSet::from_rpc(
+                                header, rows,
+                            ))));
+                            return results;
+                        }
+                        current = Some((header, Vec::new()));
+                    }
+                    // FIXME: correct error handling
+                    query_result::Result::Err => {
+                        results.push(Err(QueryError::from(result.error.unwrap())));
+                        return results;
+                    }
+                }
+            }
+            Message::Rows(rows) => {
+                if let Some((_, ref mut buffered)) = current {
+                    buffered.extend(rows.rows.into_iter().map(|r| Ok(r.into())));
+                }
+            }
+            Message::Error(e) => {
+                if let Some((_, ref mut buffered)) = current {
+                    buffered.push(Err(e.into()));
+                }
+            }
+        }
+    }
+    if let Some((header, rows)) = current {
+        results.push(buffered_result(header, rows));
+    }
+
+    results
+}
+
 #[async_trait::async_trait]
 impl Database for WriteProxyDatabase {
-    async fn execute(&self, query: Statements, params: Vec<Value>) -> QueryResult {
+    async fn execute(&self, query: Statements, params: Params) -> Vec<QueryResult> {
         let mut state = self.state.lock().await;
         if query.is_read_only() && *state == State::Start {
             self.read_db.execute(query, params).await
         } else {
-            let next_state = query.state(*state);
-            let query = SimpleQuery {
-                q: query.stmts,
+            let (params, named_params) = match params {
+                Params::Positional(params) => {
+                    (params.into_iter().map(Into::into).collect(), HashMap::new())
+                }
+                Params::Named(params) => (
+                    Vec::new(),
+                    params.into_iter().map(|(k, v)| (k, v.into())).collect(),
+                ),
+            };
+            let rpc_query = SimpleQuery {
+                q: query.stmts.clone(),
                 client_id: self.client_id.as_bytes().to_vec(),
+                params,
+                named_params,
             };
             let mut client = self.write_proxy.clone();
-            match client.query(query).await {
+            match client.query(rpc_query).await {
                 Ok(r) => {
-                    let result = r.into_inner();
-                    match result.result() {
-                        query_result::Result::Ok => {
-                            let rows = result.rows.expect("invalid response");
-                            *state = next_state;
-                            return Ok(QueryResponse::ResultSet(rows.into()));
+                    let results = read_results(r.into_inner(), query.split().count()).await;
+                    // The statements that succeeded ran on the primary: the transaction state
+                    // follows them.
+                    for (stmt, result) in query.split().zip(&results) {
+                        if result.is_ok() {
+                            *state = stmt.state(state.clone());
                         }
-                        // FIXME: correct error handling
-                        query_result::Result::Err => Err(QueryError::from(result.error.unwrap())),
                     }
+                    if *state == State::TxnClosed {
+                        *state = State::Start;
+                    }
+                    results
                 }
                 // state unknown!
-                Err(e) => Err(QueryError::new(ErrorCode::Internal, e)),
+                Err(e) => vec![Err(QueryError::new(ErrorCode::Internal, e))],
             }
         }
     }
+
+    async fn describe(&self, sql: String) -> Result<Description, QueryError> {
+        let state = self.state.lock().await;
+        // The replica has the same schema as the primary, but for the changes made by the open
+        // transaction, that only the primary sees.
+        if *state == State::Start {
+            return self.read_db.describe(sql).await;
+        }
+
+        let request = DescribeRequest {
+            client_id: self.client_id.as_bytes().to_vec(),
+            stmt: sql,
+        };
+        let mut client = self.write_proxy.clone();
+        match client.describe(request).await {
+            Ok(r) => match r.into_inner().describe_result {
+                Some(DescribeOutcome::Description(description)) => Ok(description.into()),
+                Some(DescribeOutcome::Error(e)) => Err(e.into()),
+                None => Err(QueryError::new(ErrorCode::Internal, "invalid response")),
+            },
+            Err(e) => Err(QueryError::new(ErrorCode::Internal, e)),
+        }
+    }
+
+    fn interrupt(&self) {
+        self.read_db.interrupt();
+        // The statement may be running on the primary instead.
+        let mut remote = self.write_proxy.clone();
+        let client_id = self.client_id.as_bytes().to_vec();
+        tokio::spawn(async move {
+            let _ = remote.interrupt(InterruptMessage { client_id }).await;
+        });
+    }
 }
 
 impl Drop for WriteProxyDatabase {
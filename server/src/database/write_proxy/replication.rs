@@ -1,93 +1,606 @@
 ///! Replication logic for the write proxy database.
 ///!
-///! The current RO replication for the write proxy database works by periodically polling the
-///! writer node for new WAL pages to apply and apply them.
+///! The current RO replication for the write proxy database works by subscribing to the writer
+///! node's WAL log, and applying new WAL pages as soon as the writer pushes them.
 ///!
 ///! A bit of trickery is required to make it work: in order to have the correct environment set up
 ///! to apply WAL pages, we trick libsql into performing a write to the WAL. This is what the
-///! `PeriodicDbUpdater::run` method does. This will cause libsql's `xFrames` to get called. We
-///! will intercept this call thanks to our implementation of `WalHook`, and then call the
-///! underlying WAL methods with the WAL pages we got from the writer, instead of that from the
-///! dummy write. We then purposedly make xFrame return an error to invalidate any state the dummy
-///! write may have cause in-memory.
+///! `DbUpdater::step` method does, every time a new transaction is received. This will cause
+///! libsql's `xFrames` to get called. We will intercept this call thanks to our implementation of
+///! `WalHook`, and then call the underlying WAL methods with the WAL pages we got from the writer,
+///! instead of that from the dummy write. We then purposedly make xFrame return an error to
+///! invalidate any state the dummy write may have cause in-memory.
 ///!
 ///! This relies on the fact that the layout of the WAL from the reader will match that of the
 ///! writer. This is important because it relies on the `size_after` argument to xFrames from the
 ///! writer. If any write is made from the reader, the database will be in an invalid state.
+///!
+///! When the log entries a replica needs were compacted away by the writer, the replica bootstraps
+///! from a snapshot of the writer's database instead. The snapshot is sent as a single transaction
+///! containing every page of the database. The replica writes its pages to a temporary file as they
+///! are received, rather than holding them in memory, and writes them to the WAL in batches, that
+///! are only committed with the last one.
+///!
+///! WAL pages can only be applied to a database with pages of the same size. The replica creates
+///! its database with the page size of the writer's, before anything else opens it.
+///!
+///! The replica tells the writer which compression it accepts for the pages it is sent. Each frame
+///! carries the compression of its page, and is decompressed as soon as it is received.
+///!
+///! Applying a transaction that fails is retried a few times by the `DbUpdater`, outside of the
+///! replication hook. If it keeps failing, the replica syncs again from a snapshot of the writer's
+///! database, dropping the transactions it received in the meantime. A replica that stopped while
+///! applying a transaction can't tell whether the transaction was applied: it discards its
+///! database when it restarts, and syncs it again from the writer.
+///!
+///! The writer identifies its log with a database id, that changes when the log is created again.
+///! The replica persists the id of the log its database follows, and checks it each time it
+///! subscribes to the writer: when it changed, the replica syncs again from a snapshot.
 use std::collections::VecDeque;
 use std::ffi::c_int;
 use std::fs::{File, OpenOptions};
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind, SeekFrom};
 use std::mem::size_of;
 use std::os::unix::prelude::FileExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::ensure;
-use futures::StreamExt;
-use rusqlite::ffi::SQLITE_ERROR;
-use rusqlite::OpenFlags;
-use tokio::runtime::Handle;
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use rusqlite::ffi::{SQLITE_ERROR, SQLITE_IOERR};
+use rusqlite::{Connection, OpenFlags};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Notify;
 use tonic::transport::Channel;
+use tonic::{Code, Status, Streaming};
+use uuid::Uuid;
 
 use crate::libsql::ffi::{types::XWalFrameFn, PgHdr, Wal};
 use crate::libsql::wal_hook::WalHook;
 use crate::libsql::{open_with_regular_wal, WalConnection};
+use crate::metrics;
 use crate::rpc::wal_log::wal_log_rpc::wal_log_entry::Payload;
-use crate::rpc::wal_log::wal_log_rpc::{wal_log_client::WalLogClient, LogOffset, WalLogEntry};
+use crate::rpc::wal_log::wal_log_rpc::{
+    wal_log_client::WalLogClient, Compression as RpcCompression, DatabaseInfoRequest, LogOffset,
+    SnapshotRequest, WalLogEntry,
+};
 use crate::rpc::wal_log::wal_log_rpc::{Commit, Frame};
+use crate::wal_logger::{Compression, MAX_PAGE_SIZE};
+
+/// Delay before subscribing again to the writer's log after the stream was interrupted.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+/// Number of times applying a transaction is attempted before syncing again from a snapshot.
+const MAX_APPLY_ATTEMPTS: u32 = 5;
+/// Delay before the first retry of a transaction that failed to apply. It doubles on each retry.
+const APPLY_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Number of pages of a snapshot written to the WAL at once.
+const SNAPSHOT_BATCH_PAGES: u32 = 1024;
+
+/// A complete transaction received from the writer.
+struct Transaction {
+    entries: Vec<WalLogEntry>,
+    /// Id of the writer's log the transaction was received from.
+    database_id: u128,
+    /// Set if the transaction is a snapshot, holding every page of the writer's database. Its
+    /// entries are then empty.
+    snapshot: Option<Snapshot>,
+}
+
+/// A snapshot of the writer's database, whose pages were written to a file.
+struct Snapshot {
+    /// The pages, in order.
+    file: File,
+    /// Index of the last log entry included in the snapshot.
+    index: u64,
+    commit: Commit,
+}
+
+/// How applying the buffered transactions went, shared by the replication hook and the
+/// `DbUpdater`.
+#[derive(Default)]
+struct ApplyStatus {
+    /// Number of consecutive failed attempts to apply the next buffered transaction.
+    failed_attempts: AtomicU32,
+    /// Set by the `DbUpdater` for the hook to drop the buffered transactions, and sync again from
+    /// a snapshot.
+    resync: AtomicBool,
+}
+
+/// Returns how long to wait before attempting again to apply a transaction that failed to apply
+/// `failed_attempts` times in a row, or `None` if the replica must sync again from a snapshot.
+fn retry_delay(failed_attempts: u32) -> Option<Duration> {
+    (failed_attempts < MAX_APPLY_ATTEMPTS)
+        .then(|| APPLY_RETRY_DELAY * 2u32.pow(failed_attempts - 1))
+}
 
-pub struct PeriodicDbUpdater {
-    interval: Duration,
+pub struct DbUpdater {
     db: WalConnection,
+    /// Notified each time a complete transaction is received from the writer.
+    new_commits: Receiver<()>,
+    status: Arc<ApplyStatus>,
 }
 
-/// The `PeriodicUpdater` role is to periodically trigger a dummy write that will be intercepted by
-/// its WAL hook.
-impl PeriodicDbUpdater {
+/// The `DbUpdater` role is to trigger a dummy write that will be intercepted by its WAL hook every
+/// time the writer pushes a new transaction.
+impl DbUpdater {
     pub async fn new(
         path: &Path,
+        index_path: &Path,
         remote_logger_addr: String,
-        interval: Duration,
+        compression: Compression,
     ) -> anyhow::Result<Self> {
+        let mut logger = WalLogClient::connect(remote_logger_addr).await?;
+        let (database_id, page_size) = database_info(&mut logger).await?;
+
+        let index_file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(index_path)?;
+        let mut last_applied_index = match read_applied_indexes(&index_file)? {
+            Some((pre, post)) if pre != post => {
+                tracing::warn!(
+                    "transaction {pre} may have been partially applied, discarding the database \
+                    to sync it again from the writer"
+                );
+                discard_database(path, &index_file)?;
+                None
+            }
+            indexes => indexes.map(|(_, post)| post),
+        };
+        match read_database_id(&index_file)? {
+            Some(id) if id != database_id => {
+                tracing::warn!(
+                    "the writer's log was created again, discarding the database to sync it again \
+                    from the writer"
+                );
+                discard_database(path, &index_file)?;
+                last_applied_index = None;
+            }
+            _ => (),
+        }
+        if !init_database(path, page_size)? {
+            tracing::warn!(
+                "the replica database doesn't have the page size of the writer's, discarding it \
+                to sync it again from the writer"
+            );
+            discard_database(path, &index_file)?;
+            last_applied_index = None;
+            init_database(path, page_size)?;
+        }
+
+        let (transactions_sender, transactions) = crossbeam::channel::unbounded();
+        let (new_commits_sender, new_commits) = crossbeam::channel::bounded(1);
+        let resync = Arc::new(Notify::new());
+        let status = Arc::new(ApplyStatus::default());
+        let hook = ReadReplicationHook::new(
+            transactions,
+            index_file,
+            last_applied_index,
+            database_id,
+            resync.clone(),
+            status.clone(),
+        );
+        metrics::REPLICA_HEALTHY.set(1);
+        // ask for the frame right after the one we last applied
+        let start_offset = last_applied_index.map(|x| x + 1).unwrap_or_default();
+        tokio::spawn(stream_log_entries(
+            logger,
+            path.to_owned(),
+            start_offset,
+            database_id,
+            compression,
+            resync,
+            transactions_sender,
+            new_commits_sender,
+        ));
+
         let db = open_with_regular_wal(
             path,
             OpenFlags::SQLITE_OPEN_READ_WRITE
                 | OpenFlags::SQLITE_OPEN_CREATE
                 | OpenFlags::SQLITE_OPEN_URI
                 | OpenFlags::SQLITE_OPEN_NO_MUTEX,
-            ReadReplicationHook::new(remote_logger_addr).await?,
+            hook,
         )?;
 
-        Ok(Self { interval, db })
+        Ok(Self {
+            db,
+            new_commits,
+            status,
+        })
     }
 
+    /// Applies transactions as they are received, until `abort` is disconnected. A transaction
+    /// that failed to apply is attempted again after a delay, until the replica gives up and
+    /// syncs again from a snapshot.
+    ///
     /// blocking!
-    pub fn step(&mut self) {
+    pub fn run(self, abort: Receiver<()>) {
+        loop {
+            let retry = match self.status.failed_attempts.load(Ordering::Relaxed) {
+                0 => crossbeam::channel::never(),
+                failed_attempts => match retry_delay(failed_attempts) {
+                    Some(delay) => crossbeam::channel::after(delay),
+                    None => {
+                        // the hook asks for a snapshot the next time it is called.
+                        self.status.failed_attempts.store(0, Ordering::Relaxed);
+                        self.status.resync.store(true, Ordering::Relaxed);
+                        self.step();
+                        continue;
+                    }
+                },
+            };
+            crossbeam::channel::select! {
+                recv(abort) -> _ => break,
+                recv(self.new_commits) -> msg => {
+                    if msg.is_err() {
+                        break;
+                    }
+                    self.step();
+                }
+                recv(retry) -> _ => self.step(),
+            }
+        }
+    }
+
+    fn step(&self) {
         // dummy write that triggers a call to xFrame
         let _ = self.db.execute(
             "create table if not exists __dummy__ (dummy); insert into __dummy__ values (1);",
             (),
         );
-        std::thread::sleep(self.interval);
     }
 }
 
+/// Reads the indexes persisted by the replication hook: the index of the last transaction whose
+/// application was attempted, and the index of the last transaction applied. Returns `None` if no
+/// transaction was applied yet.
+fn read_applied_indexes(file: &File) -> anyhow::Result<Option<(u64, u64)>> {
+    let mut buf = [0; 2 * size_of::<u64>()];
+    match file.read_exact_at(&mut buf, 0) {
+        Ok(()) => {
+            let pre = u64::from_le_bytes(buf[..size_of::<u64>()].try_into()?);
+            let post = u64::from_le_bytes(buf[size_of::<u64>()..].try_into()?);
+            Ok(Some((pre, post)))
+        }
+        // OK: the file was empty, we don't have a log yet.
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads the id of the writer's log the database follows, persisted by the replication hook next
+/// to the indexes. Returns `None` if no transaction was applied yet, or if the indexes were written
+/// by a version that didn't persist the id.
+fn read_database_id(file: &File) -> anyhow::Result<Option<u128>> {
+    let mut buf = [0; size_of::<u128>()];
+    match file.read_exact_at(&mut buf, 2 * size_of::<u64>() as u64) {
+        Ok(()) => Ok(Some(u128::from_le_bytes(buf))),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the id of the writer's log, and the page size of its database.
+async fn database_info(logger: &mut WalLogClient<Channel>) -> anyhow::Result<(u128, u32)> {
+    let info = logger
+        .database_info(DatabaseInfoRequest {})
+        .await?
+        .into_inner();
+    let database_id = u128::from_le_bytes(info.database_id[..].try_into()?);
+
+    Ok((database_id, info.page_size))
+}
+
+/// Removes the database at `path`, along with its WAL, and the indexes persisted in `index_file`,
+/// for the database to be synced again from the writer.
+fn discard_database(path: &Path, index_file: &File) -> anyhow::Result<()> {
+    for suffix in ["", "-wal", "-shm"] {
+        let mut file_path = path.as_os_str().to_owned();
+        file_path.push(suffix);
+        match std::fs::remove_file(&file_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    index_file.set_len(0)?;
+    metrics::REPLICA_RESYNCS.inc();
+
+    Ok(())
+}
+
+/// Creates the database of the replica with `page_size`, the page size of the writer's database.
+/// Returns whether the database has that page size, which an existing database may not have.
+fn init_database(path: &Path, page_size: u32) -> anyhow::Result<bool> {
+    let conn = Connection::open(path)?;
+    // The page size can only be changed while the database is empty, and before it is in WAL
+    // mode: this is a no-op for an existing database.
+    conn.pragma_update(None, "page_size", page_size)?;
+    conn.pragma_update(None, "journal_mode", "wal")?;
+    let db_page_size: u32 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+
+    Ok(db_page_size == page_size)
+}
+
+/// Keeps a subscription to the writer's log open, and forwards the received transactions to the
+/// replication hook. The writer is asked to compress the pages it sends with `compression`. The
+/// pages of snapshots are written to files next to the database at `db_path`.
+///
+/// If the stream is interrupted, subscribes again right after the last complete transaction. If the
+/// writer compacted the entries we need away, if its log is not the one of `database_id` anymore,
+/// or if the replication hook asks for it through `resync`, bootstraps from a snapshot of its
+/// database instead. Returns when the replication hook is dropped.
+async fn stream_log_entries(
+    mut logger: WalLogClient<Channel>,
+    db_path: PathBuf,
+    mut next_offset: u64,
+    mut database_id: u128,
+    compression: Compression,
+    resync: Arc<Notify>,
+    transactions: Sender<Transaction>,
+    new_commits: Sender<()>,
+) {
+    let compression = RpcCompression::from(compression).into();
+    let mut needs_snapshot = false;
+    let mut subscribed = false;
+    loop {
+        // The id was checked when the replica started: check it again on every new subscription,
+        // since the writer may have restarted with a new log in between.
+        if std::mem::replace(&mut subscribed, true) {
+            match database_info(&mut logger).await {
+                Ok((id, _)) if id != database_id => {
+                    tracing::warn!("the writer's log was created again, fetching a snapshot");
+                    database_id = id;
+                    needs_snapshot = true;
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    tracing::error!("error fetching the writer's database info: {e}");
+                    tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+                    continue;
+                }
+            }
+        }
+        let stream = if needs_snapshot {
+            logger.snapshot(SnapshotRequest { compression }).await
+        } else {
+            let req = LogOffset {
+                start_offset: next_offset,
+                compression,
+            };
+            logger.log_entries(req).await
+        };
+        let result = match stream {
+            Ok(stream) => tokio::select! {
+                result = forward_transactions(
+                    stream.into_inner(),
+                    database_id,
+                    needs_snapshot.then_some(db_path.as_path()),
+                    &mut next_offset,
+                    &transactions,
+                    &new_commits,
+                ) => result,
+                _ = resync.notified() => {
+                    tracing::warn!("fetching a snapshot to sync again with the writer");
+                    needs_snapshot = true;
+                    continue;
+                }
+            },
+            Err(status) => Err(status),
+        };
+
+        match result {
+            Ok(false) => return,
+            Ok(true) if needs_snapshot => {
+                tracing::info!("bootstrapped from snapshot, resuming at index {next_offset}");
+                needs_snapshot = false;
+                continue;
+            }
+            Ok(true) => tracing::warn!("log stream interrupted at index {next_offset}"),
+            Err(status) if status.code() == Code::OutOfRange => {
+                tracing::warn!("log entry {next_offset} was compacted, fetching a snapshot");
+                needs_snapshot = true;
+                continue;
+            }
+            Err(status) => tracing::error!("error receiving log entries: {status}"),
+        }
+
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+/// Forwards the complete transactions received on `stream`, from the log of `database_id`, to the
+/// replication hook, and notifies `new_commits` for each of them. `next_offset` is moved past each
+/// forwarded transaction.
+///
+/// If the stream is a snapshot, `snapshot_db_path` is the path of the database its pages are
+/// written next to.
+///
+/// Returns `Ok(false)` if the replication hook was dropped, and `Ok(true)` when the stream ends.
+async fn forward_transactions(
+    mut stream: Streaming<WalLogEntry>,
+    database_id: u128,
+    snapshot_db_path: Option<&Path>,
+    next_offset: &mut u64,
+    transactions: &Sender<Transaction>,
+    new_commits: &Sender<()>,
+) -> Result<bool, Status> {
+    let snapshot_error =
+        |e: io::Error| Status::internal(format!("failed to write the snapshot: {e}"));
+    let mut snapshot_file = match snapshot_db_path {
+        Some(db_path) => Some(create_snapshot_file(db_path).map_err(snapshot_error)?),
+        None => None,
+    };
+    let mut entries = Vec::new();
+    while let Some(mut entry) = stream.message().await? {
+        let index = entry.index;
+        let last_log_index = entry.last_log_index;
+        decompress_frame(&mut entry)
+            .map_err(|e| Status::data_loss(format!("invalid frame at index {index}: {e}")))?;
+        let transaction = if let Some(file) = &mut snapshot_file {
+            // The pages of a snapshot are written to its file as they are received.
+            match entry.payload {
+                Some(Payload::Frame(frame)) => {
+                    write_snapshot_page(file, &frame)
+                        .await
+                        .map_err(snapshot_error)?;
+                    continue;
+                }
+                Some(Payload::Commit(commit)) => {
+                    let mut file = snapshot_file.take().unwrap();
+                    file.flush().await.map_err(snapshot_error)?;
+                    let snapshot = Snapshot {
+                        file: file.into_std().await,
+                        index,
+                        commit,
+                    };
+                    Transaction {
+                        entries: Vec::new(),
+                        database_id,
+                        snapshot: Some(snapshot),
+                    }
+                }
+                None => continue,
+            }
+        } else {
+            let is_commit = matches!(entry.payload, Some(Payload::Commit(_)));
+            entries.push(entry);
+            if !is_commit {
+                continue;
+            }
+            Transaction {
+                entries: std::mem::take(&mut entries),
+                database_id,
+                snapshot: None,
+            }
+        };
+        if transactions.send(transaction).is_err() {
+            return Ok(false);
+        }
+        // a pending notification already covers this transaction.
+        if let Err(TrySendError::Disconnected(_)) = new_commits.try_send(()) {
+            return Ok(false);
+        }
+        *next_offset = index + 1;
+        metrics::update_replica_indexes(None, Some(last_log_index));
+    }
+
+    Ok(true)
+}
+
+/// Creates the file the pages of a snapshot are written to, next to the database at `db_path`. The
+/// file is removed right away: it only lives as long as it is open.
+fn create_snapshot_file(db_path: &Path) -> io::Result<tokio::fs::File> {
+    let path = db_path.with_extension(format!("snapshot-{}", Uuid::new_v4()));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+
+    Ok(tokio::fs::File::from_std(file))
+}
+
+/// Writes the page of `frame`, received in a snapshot, at its place in `file`.
+async fn write_snapshot_page(file: &mut tokio::fs::File, frame: &Frame) -> io::Result<()> {
+    let Some(page_index) = frame.page_no.checked_sub(1) else {
+        return Err(io::Error::new(ErrorKind::InvalidData, "invalid page number 0"));
+    };
+    file.seek(SeekFrom::Start(page_index as u64 * frame.data.len() as u64))
+        .await?;
+    file.write_all(&frame.data).await
+}
+
+/// Writes the pages of a snapshot, held by `file`, to the WAL with `orig`, in batches of at most
+/// `SNAPSHOT_BATCH_PAGES` pages. The pages are only committed with the last batch. Returns the
+/// error of the batch that failed, if any.
+fn write_snapshot_pages(
+    file: &File,
+    commit: &Commit,
+    wal: *mut Wal,
+    orig: XWalFrameFn,
+) -> Result<(), c_int> {
+    let page_size = commit.page_size as usize;
+    let page_count = commit.size_after;
+    let mut batch = Vec::new();
+    for first in (1..=page_count).step_by(SNAPSHOT_BATCH_PAGES as usize) {
+        let last = page_count.min(first + SNAPSHOT_BATCH_PAGES - 1);
+        batch.clear();
+        for page_no in first..=last {
+            let mut data = vec![0; page_size];
+            if let Err(e) = file.read_exact_at(&mut data, (page_no - 1) as u64 * page_size as u64) {
+                tracing::error!("failed to read page {page_no} of the snapshot: {e}");
+                return Err(SQLITE_IOERR);
+            }
+            batch.push((page_no, data));
+        }
+        let headers = make_page_header(batch.iter().map(|(page_no, data)| (*page_no, &data[..])));
+        let ret = if last == page_count {
+            orig(
+                wal,
+                commit.page_size,
+                headers,
+                commit.size_after,
+                commit.is_commit as _,
+                commit.sync_flags,
+            )
+        } else {
+            orig(wal, commit.page_size, headers, 0, 0, 0)
+        };
+        if ret == 0 {
+            debug_assert!(all_applied(headers));
+        }
+        free_page_header(headers);
+        if ret != 0 {
+            return Err(ret);
+        }
+    }
+
+    Ok(())
+}
+
+/// Decompresses the page of `entry`, if it is a compressed frame.
+fn decompress_frame(entry: &mut WalLogEntry) -> anyhow::Result<()> {
+    if let Some(Payload::Frame(frame)) = &mut entry.payload {
+        let compression = Compression::from(frame.compression());
+        if compression != Compression::None {
+            frame.data = compression.decompress(&frame.data, MAX_PAGE_SIZE)?.into();
+            frame.set_compression(RpcCompression::None);
+        }
+    }
+
+    Ok(())
+}
+
 struct ReadReplicationHook {
-    logger: WalLogClient<Channel>,
-    fetch_frame_index: u64,
+    /// Complete transactions received from the writer
+    transactions: Receiver<Transaction>,
     /// Persistent last committed index used for restarts.
-    /// The File should contain two little-endian u64:
+    /// The File should contain two little-endian u64, followed by a little-endian u128:
     /// - The first one is the attempted commit index before the call xFrame
     /// - The second index is the actually committed index after xFrame
+    /// - The id of the writer's log the committed transaction comes from
     /// After a flight of pages has been successfully written, the two numbers should be the same.
     /// On startup the two number are checked for consistency. If they differ, the database is
-    /// considered corrupted, since it is impossible to know what the actually replicated index is.
+    /// discarded and synced again, since it is impossible to know what the actually replicated
+    /// index is.
     last_applied_index_file: File,
     last_applied_index: Option<u64>,
+    /// Id of the writer's log the database follows. Transactions from another log are dropped,
+    /// until a snapshot of it arrives.
+    database_id: u128,
     /// Buffer for incoming frames
     buffer: VecDeque<WalLogEntry>,
-    rt: Handle,
+    /// Notified to sync again from a snapshot of the writer's database.
+    resync: Arc<Notify>,
+    /// Set when a snapshot was asked for: the transactions received until it arrives are dropped.
+    awaiting_snapshot: bool,
+    /// The snapshot to apply before the buffered transactions, that follow it.
+    snapshot: Option<Snapshot>,
+    status: Arc<ApplyStatus>,
 }
 
 /// Debug assertion. Make sure that all the pages have been applied
@@ -110,25 +623,32 @@ unsafe impl WalHook for ReadReplicationHook {
     fn on_frames(
         &mut self,
         wal: *mut Wal,
-        _page_size: c_int,
+        page_size: c_int,
         _page_headers: *mut PgHdr,
         _size_after: u32,
         _is_commit: c_int,
         _sync_flags: c_int,
         orig: XWalFrameFn,
     ) -> c_int {
-        let rt = self.rt.clone();
-        if let Err(e) = rt.block_on(self.fetch_log_entries()) {
-            tracing::error!("error fetching log entries: {e}");
-            return SQLITE_ERROR;
+        self.receive_transactions();
+        if self.status.resync.swap(false, Ordering::Relaxed) {
+            tracing::error!("failed to apply transactions, syncing again from the writer");
+            self.request_snapshot();
         }
 
-        while let Some((page_headers, truncate, commit)) = self.next_transaction() {
+        if !self.apply_snapshot(wal, page_size, orig) {
+            return SQLITE_ERROR;
+        }
+        while let Some((page_headers, truncate, attempted_commit_index, commit)) =
+            self.next_transaction()
+        {
             tracing::trace!(commit = ?commit, truncate = truncate);
-            let attempted_commit_index = self
-                .last_applied_index
-                .map(|x| x + truncate as u64 - 1)
-                .unwrap_or_default();
+            // The page size is that of the replica database, that the pages are written to.
+            if commit.page_size != page_size {
+                free_page_header(page_headers);
+                self.on_page_size_mismatch(attempted_commit_index, commit.page_size, page_size);
+                break;
+            }
             // pre-write index
             self.last_applied_index_file
                 .write_all_at(&attempted_commit_index.to_le_bytes(), 0)
@@ -140,6 +660,7 @@ unsafe impl WalHook for ReadReplicationHook {
                 sync_flags,
             } = commit;
 
+            // Only attempted once: the `DbUpdater` retries later, without holding the database.
             let ret = orig(
                 wal,
                 page_size,
@@ -148,23 +669,16 @@ unsafe impl WalHook for ReadReplicationHook {
                 is_commit as _,
                 sync_flags,
             );
-
             if ret == 0 {
                 debug_assert!(all_applied(page_headers));
-                // persist new commited index
-                self.last_applied_index_file
-                    .write_all_at(&attempted_commit_index.to_le_bytes(), size_of::<u64>() as _)
-                    .unwrap();
-                self.last_applied_index.replace(attempted_commit_index);
-                // remove commited entries.
-                self.buffer.drain(..truncate);
-                tracing::trace!("applied frame batch");
-            } else {
-                // should we retry?
-                todo!("how to handle apply failure?");
             }
-
             free_page_header(page_headers);
+
+            if ret != 0 {
+                self.on_apply_failure(attempted_commit_index, ret);
+                break;
+            }
+            self.on_applied(attempted_commit_index, truncate);
         }
         // return error from dummy write.
         // this is a trick to prevent sqlite from keeping any state in memory after a dummy write
@@ -172,27 +686,25 @@ unsafe impl WalHook for ReadReplicationHook {
     }
 }
 
-/// Turn a list of `WalLogEntry` into a list of PgHdr.
+/// Turn a list of pages, with their page numbers, into a list of PgHdr.
 /// The caller has the responsibility to free the returned headers.
-fn make_page_header<'a>(entries: impl Iterator<Item = &'a WalLogEntry>) -> *mut PgHdr {
+fn make_page_header<'a>(pages: impl Iterator<Item = (u32, &'a [u8])>) -> *mut PgHdr {
     let mut current_pg = std::ptr::null_mut();
 
     let mut headers_count = 0;
-    for entry in entries {
-        if let Payload::Frame(Frame { page_no, data }) = entry.payload.as_ref().unwrap() {
-            let page = PgHdr {
-                page: std::ptr::null(),
-                data: data.as_ptr() as _,
-                extra: std::ptr::null(),
-                pcache: std::ptr::null(),
-                dirty: current_pg,
-                pager: std::ptr::null(),
-                pgno: *page_no,
-                flags: 0,
-            };
-            headers_count += 1;
-            current_pg = Box::into_raw(Box::new(page));
-        }
+    for (page_no, data) in pages {
+        let page = PgHdr {
+            page: std::ptr::null(),
+            data: data.as_ptr() as _,
+            extra: std::ptr::null(),
+            pcache: std::ptr::null(),
+            dirty: current_pg,
+            pager: std::ptr::null(),
+            pgno: page_no,
+            flags: 0,
+        };
+        headers_count += 1;
+        current_pg = Box::into_raw(Box::new(page));
     }
 
     tracing::trace!("built {headers_count} page headers");
@@ -210,42 +722,153 @@ fn free_page_header(h: *const PgHdr) {
 }
 
 impl ReadReplicationHook {
-    async fn new(remote_addr: String) -> anyhow::Result<Self> {
-        let logger = WalLogClient::connect(remote_addr).await?;
-        let last_applied_index_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(".wal_index.iku")?;
+    fn new(
+        transactions: Receiver<Transaction>,
+        last_applied_index_file: File,
+        last_applied_index: Option<u64>,
+        database_id: u128,
+        resync: Arc<Notify>,
+        status: Arc<ApplyStatus>,
+    ) -> Self {
+        metrics::update_replica_indexes(last_applied_index, last_applied_index);
 
-        let mut buf = [0; 2 * size_of::<u64>()];
-        let last_applied_index = match last_applied_index_file.read_exact_at(&mut buf, 0) {
+        Self {
+            transactions,
+            last_applied_index_file,
+            last_applied_index,
+            database_id,
+            buffer: Default::default(),
+            resync,
+            awaiting_snapshot: false,
+            snapshot: None,
+            status,
+        }
+    }
+
+    /// Buffers the transactions received from the writer. Transactions are dropped while a
+    /// snapshot is awaited, until it arrives, and so are the transactions from another log than the
+    /// one the database follows.
+    fn receive_transactions(&mut self) {
+        let transactions: Vec<_> = self.transactions.try_iter().collect();
+        for transaction in transactions {
+            if transaction.database_id != self.database_id {
+                if transaction.snapshot.is_none() {
+                    if !self.awaiting_snapshot {
+                        tracing::warn!("received a transaction from another log, resyncing");
+                        self.request_snapshot();
+                    }
+                    continue;
+                }
+                // The snapshot replaces the database the buffered transactions apply to.
+                self.buffer.clear();
+                self.database_id = transaction.database_id;
+                self.awaiting_snapshot = true;
+            }
+            match transaction.snapshot {
+                // The snapshot includes the transactions buffered before it.
+                Some(snapshot) => {
+                    self.buffer.clear();
+                    self.snapshot = Some(snapshot);
+                    self.awaiting_snapshot = false;
+                }
+                None if !self.awaiting_snapshot => self.buffer.extend(transaction.entries),
+                None => (),
+            }
+        }
+    }
+
+    /// Asks for a snapshot of the writer's database, and drops the buffered transactions: they may
+    /// depend on the one that failed to apply.
+    fn request_snapshot(&mut self) {
+        self.buffer.clear();
+        self.awaiting_snapshot = true;
+        self.snapshot = None;
+        self.status.failed_attempts.store(0, Ordering::Relaxed);
+        self.resync.notify_one();
+        metrics::REPLICA_HEALTHY.set(0);
+        metrics::REPLICA_RESYNCS.inc();
+    }
+
+    /// Applies the pending snapshot, if any. Returns whether the buffered transactions can be
+    /// applied after it.
+    fn apply_snapshot(&mut self, wal: *mut Wal, page_size: c_int, orig: XWalFrameFn) -> bool {
+        let Some(snapshot) = &self.snapshot else { return true };
+        let index = snapshot.index;
+        // The page size is that of the replica database, that the pages are written to.
+        if snapshot.commit.page_size != page_size {
+            let commit_page_size = snapshot.commit.page_size;
+            self.on_page_size_mismatch(index, commit_page_size, page_size);
+            return false;
+        }
+        // pre-write index
+        self.last_applied_index_file
+            .write_all_at(&index.to_le_bytes(), 0)
+            .unwrap();
+        match write_snapshot_pages(&snapshot.file, &snapshot.commit, wal, orig) {
             Ok(()) => {
-                let pre = u64::from_le_bytes(buf[..size_of::<u64>()].try_into()?);
-                let post = u64::from_le_bytes(buf[size_of::<u64>()..].try_into()?);
-                ensure!(pre == post, "database corrupted");
-                Some(pre)
+                self.on_applied(index, 0);
+                true
             }
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
-                // OK: the file was empty, we don't have a log yet.
-                None
+            Err(e) => {
+                self.on_apply_failure(index, e);
+                false
             }
-            Err(e) => Err(e)?,
-        };
+        }
+    }
 
-        Ok(Self {
-            logger,
-            // ask for the frame right after the one we last applied
-            fetch_frame_index: last_applied_index.map(|x| x + 1).unwrap_or_default(),
-            last_applied_index_file,
-            last_applied_index,
-            buffer: Default::default(),
-            rt: Handle::current(),
-        })
+    /// Records that the transaction at `index`, whose entries are the first `truncate` buffered
+    /// ones, was applied. The pending snapshot, if any, is the transaction that was applied.
+    fn on_applied(&mut self, index: u64, truncate: usize) {
+        // persist new commited index, along with the log it comes from.
+        let mut buf = [0; size_of::<u64>() + size_of::<u128>()];
+        buf[..size_of::<u64>()].copy_from_slice(&index.to_le_bytes());
+        buf[size_of::<u64>()..].copy_from_slice(&self.database_id.to_le_bytes());
+        self.last_applied_index_file
+            .write_all_at(&buf, size_of::<u64>() as _)
+            .unwrap();
+        self.last_applied_index.replace(index);
+        self.snapshot = None;
+        self.status.failed_attempts.store(0, Ordering::Relaxed);
+        metrics::update_replica_indexes(Some(index), None);
+        metrics::REPLICA_HEALTHY.set(1);
+        // remove commited entries.
+        self.buffer.drain(..truncate);
+        tracing::trace!("applied frame batch");
+    }
+
+    /// Records that applying the transaction at `index` failed with `error`. The transaction is
+    /// kept buffered, for the `DbUpdater` to attempt again.
+    fn on_apply_failure(&mut self, index: u64, error: c_int) {
+        let failed_attempts = self.status.failed_attempts.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::warn!(
+            "failed to apply transaction {index} (error {error}), attempt {failed_attempts} of \
+            {MAX_APPLY_ATTEMPTS}"
+        );
+        metrics::REPLICA_APPLY_FAILURES.inc();
+    }
+
+    /// Handles the transaction at `index`, whose pages are `commit_page_size` bytes, while the
+    /// pages of the database are `page_size` bytes.
+    fn on_page_size_mismatch(&mut self, index: u64, commit_page_size: i32, page_size: i32) {
+        tracing::error!(
+            "cannot apply transaction {index}: it has {commit_page_size}-byte pages, but the \
+            database has {page_size}-byte pages"
+        );
+        if self.snapshot.is_some() {
+            // The snapshot has the page size of the writer's database: syncing again won't help.
+            // On restart, the database is created again with the writer's page size.
+            tracing::error!("restart the replica to sync its database again");
+            self.buffer.clear();
+            self.awaiting_snapshot = true;
+            self.snapshot = None;
+            metrics::REPLICA_HEALTHY.set(0);
+        } else {
+            self.request_snapshot();
+        }
     }
 
-    /// Returns the next page headers list the log truncate count, and the commit frame for the
-    /// next buffered transaction.
+    /// Returns the next page headers list the log truncate count, the log index of the commit
+    /// frame, and the commit frame for the next buffered transaction.
     ///
     /// The caller is responsible for freeing the page headers with the `free_page_header` function,
     /// and advancing the internal buffer with
@@ -253,45 +876,330 @@ impl ReadReplicationHook {
     /// Note: It does not seem possible to batch transaction. I suspect that this is because the
     /// original implementation of the sqlite WAL overwrites when pages appear multiple times in
     /// the same transaction.
-    fn next_transaction(&self) -> Option<(*mut PgHdr, usize, Commit)> {
-        let (commit_idx, commit) =
+    fn next_transaction(&self) -> Option<(*mut PgHdr, usize, u64, Commit)> {
+        let (commit_idx, commit_index, commit) =
             self.buffer
                 .iter()
                 .enumerate()
                 .find_map(|(i, e)| match &e.payload {
-                    Some(Payload::Commit(commit)) => Some((i, commit.clone())),
+                    Some(Payload::Commit(commit)) => Some((i, e.index, commit.clone())),
                     _ => None,
                 })?;
 
-        let headers = make_page_header(self.buffer.iter().take(commit_idx));
+        let pages = self
+            .buffer
+            .iter()
+            .take(commit_idx)
+            .filter_map(|e| match &e.payload {
+                Some(Payload::Frame(Frame { page_no, data, .. })) => Some((*page_no, &data[..])),
+                _ => None,
+            });
+        let headers = make_page_header(pages);
 
-        Some((headers, commit_idx + 1, commit))
+        Some((headers, commit_idx + 1, commit_index, commit))
     }
+}
 
-    /// Asks the writer for new log frames to apply.
-    async fn fetch_log_entries(&mut self) -> anyhow::Result<()> {
-        // try to fetch next page.
-        let start_offset = self.fetch_frame_index;
-        let req = LogOffset { start_offset };
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
 
-        let mut stream = self.logger.log_entries(req).await?.into_inner();
-        while let Some(frame) = stream.next().await {
-            let frame = frame?;
-            debug_assert_eq!(
-                frame.index, self.fetch_frame_index,
-                "out of order log frame"
-            );
-            self.fetch_frame_index = frame.index + 1;
-            self.buffer.push_back(frame);
+    use futures::FutureExt;
+
+    use super::*;
+
+    /// The replica metrics are global: the tests that change them don't run concurrently.
+    static METRICS: Mutex<()> = Mutex::new(());
+
+    const DATABASE_ID: u128 = 42;
+
+    fn transaction(index: u64, is_snapshot: bool) -> Transaction {
+        let frame = Frame {
+            page_no: 1,
+            data: vec![0; 4096].into(),
+            compression: RpcCompression::None.into(),
+        };
+        let commit = Commit {
+            page_size: 4096,
+            size_after: 1,
+            is_commit: true,
+            sync_flags: 0,
+        };
+        if is_snapshot {
+            let file = tempfile::tempfile().unwrap();
+            file.write_all_at(&frame.data, 0).unwrap();
+            return Transaction {
+                entries: Vec::new(),
+                database_id: DATABASE_ID,
+                snapshot: Some(Snapshot {
+                    file,
+                    index,
+                    commit,
+                }),
+            };
         }
+        let entries = [Payload::Frame(frame), Payload::Commit(commit)]
+            .into_iter()
+            .map(|payload| WalLogEntry {
+                index,
+                payload: Some(payload),
+                last_log_index: index,
+            })
+            .collect();
 
-        tracing::trace!(
-            start_offset,
-            self.fetch_frame_index,
-            "received {} frames",
-            self.fetch_frame_index - start_offset,
+        Transaction {
+            entries,
+            database_id: DATABASE_ID,
+            snapshot: None,
+        }
+    }
+
+    fn hook() -> (ReadReplicationHook, Sender<Transaction>, Arc<Notify>) {
+        let (sender, transactions) = crossbeam::channel::unbounded();
+        let resync = Arc::new(Notify::new());
+        let hook = ReadReplicationHook::new(
+            transactions,
+            tempfile::tempfile().unwrap(),
+            None,
+            DATABASE_ID,
+            resync.clone(),
+            Default::default(),
         );
 
-        Ok(())
+        (hook, sender, resync)
+    }
+
+    /// Returns the index of the next transaction to apply, and its number of buffered entries.
+    fn next_index(hook: &ReadReplicationHook) -> Option<(u64, usize)> {
+        if let Some(snapshot) = &hook.snapshot {
+            return Some((snapshot.index, 0));
+        }
+        let (headers, truncate, index, _) = hook.next_transaction()?;
+        free_page_header(headers);
+
+        Some((index, truncate))
+    }
+
+    #[test]
+    fn retry_delays() {
+        assert_eq!(retry_delay(1), Some(APPLY_RETRY_DELAY));
+        assert_eq!(retry_delay(2), Some(APPLY_RETRY_DELAY * 2));
+        assert_eq!(retry_delay(4), Some(APPLY_RETRY_DELAY * 8));
+        assert_eq!(retry_delay(MAX_APPLY_ATTEMPTS), None);
+    }
+
+    #[test]
+    fn failed_transaction_is_retried() {
+        let _metrics = METRICS.lock().unwrap();
+        let (mut hook, sender, resync) = hook();
+        sender.send(transaction(0, false)).unwrap();
+        hook.receive_transactions();
+
+        hook.on_apply_failure(0, SQLITE_IOERR);
+        hook.on_apply_failure(0, SQLITE_IOERR);
+        assert_eq!(hook.status.failed_attempts.load(Ordering::Relaxed), 2);
+        assert!(resync.notified().now_or_never().is_none());
+
+        // the transaction is still buffered, for the next attempt.
+        let (index, truncate) = next_index(&hook).unwrap();
+        assert_eq!(index, 0);
+        hook.on_applied(index, truncate);
+        assert_eq!(hook.status.failed_attempts.load(Ordering::Relaxed), 0);
+        assert_eq!(hook.last_applied_index, Some(0));
+        assert!(next_index(&hook).is_none());
+        let indexes = read_applied_indexes(&hook.last_applied_index_file).unwrap();
+        assert_eq!(indexes, Some((0, 0)));
+        assert_eq!(metrics::REPLICA_HEALTHY.get(), 1);
+    }
+
+    #[test]
+    fn resync_from_snapshot() {
+        let _metrics = METRICS.lock().unwrap();
+        let (mut hook, sender, resync) = hook();
+        sender.send(transaction(0, false)).unwrap();
+        sender.send(transaction(1, false)).unwrap();
+        hook.receive_transactions();
+        hook.on_apply_failure(0, SQLITE_IOERR);
+
+        hook.request_snapshot();
+        assert!(resync.notified().now_or_never().is_some());
+        assert_eq!(metrics::REPLICA_HEALTHY.get(), 0);
+        assert_eq!(hook.status.failed_attempts.load(Ordering::Relaxed), 0);
+        assert!(next_index(&hook).is_none());
+
+        // the transactions received before the snapshot are dropped.
+        sender.send(transaction(2, false)).unwrap();
+        sender.send(transaction(5, true)).unwrap();
+        sender.send(transaction(6, false)).unwrap();
+        hook.receive_transactions();
+        let (index, truncate) = next_index(&hook).unwrap();
+        assert_eq!(index, 5);
+        hook.on_applied(index, truncate);
+        assert_eq!(metrics::REPLICA_HEALTHY.get(), 1);
+        assert_eq!(next_index(&hook).unwrap().0, 6);
+    }
+
+    #[test]
+    fn page_size_mismatch_resyncs() {
+        let _metrics = METRICS.lock().unwrap();
+        let (mut hook, sender, resync) = hook();
+        sender.send(transaction(0, false)).unwrap();
+        hook.receive_transactions();
+
+        hook.on_page_size_mismatch(0, 4096, 1024);
+        assert!(resync.notified().now_or_never().is_some());
+        assert_eq!(metrics::REPLICA_HEALTHY.get(), 0);
+
+        // a snapshot has the same page size: the replica waits to be restarted.
+        sender.send(transaction(3, true)).unwrap();
+        hook.receive_transactions();
+        assert_eq!(next_index(&hook).unwrap().0, 3);
+        hook.on_page_size_mismatch(3, 4096, 1024);
+        assert!(resync.notified().now_or_never().is_none());
+        assert!(next_index(&hook).is_none());
+        assert_eq!(metrics::REPLICA_HEALTHY.get(), 0);
+    }
+
+    #[test]
+    fn transactions_from_another_log_are_dropped() {
+        let _metrics = METRICS.lock().unwrap();
+        let (mut hook, sender, resync) = hook();
+        sender.send(transaction(0, false)).unwrap();
+        hook.receive_transactions();
+        let (index, truncate) = next_index(&hook).unwrap();
+        hook.on_applied(index, truncate);
+        assert_eq!(
+            read_database_id(&hook.last_applied_index_file).unwrap(),
+            Some(DATABASE_ID)
+        );
+
+        let other_log = |index, is_snapshot| Transaction {
+            database_id: DATABASE_ID + 1,
+            ..transaction(index, is_snapshot)
+        };
+        sender.send(transaction(1, false)).unwrap();
+        sender.send(other_log(0, false)).unwrap();
+        hook.receive_transactions();
+        assert!(resync.notified().now_or_never().is_some());
+        assert!(next_index(&hook).is_none());
+
+        // a snapshot of the other log replaces the database.
+        sender.send(other_log(1, false)).unwrap();
+        sender.send(other_log(3, true)).unwrap();
+        hook.receive_transactions();
+        let (index, truncate) = next_index(&hook).unwrap();
+        assert_eq!(index, 3);
+        hook.on_applied(index, truncate);
+        assert_eq!(
+            read_database_id(&hook.last_applied_index_file).unwrap(),
+            Some(DATABASE_ID + 1)
+        );
+        assert_eq!(metrics::REPLICA_HEALTHY.get(), 1);
+    }
+
+    #[test]
+    fn read_database_id_of_older_index_file() {
+        let index_file = tempfile::tempfile().unwrap();
+        assert!(read_database_id(&index_file).unwrap().is_none());
+        // the indexes of versions that didn't persist the id.
+        index_file.write_all_at(&[1; 16], 0).unwrap();
+        assert_eq!(
+            read_applied_indexes(&index_file).unwrap(),
+            Some((0x0101010101010101, 0x0101010101010101))
+        );
+        assert!(read_database_id(&index_file).unwrap().is_none());
+    }
+
+    #[test]
+    fn discard_database_with_other_page_size() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data.db");
+        assert!(init_database(&path, 1024).unwrap());
+        assert!(init_database(&path, 1024).unwrap());
+        assert!(!init_database(&path, 4096).unwrap());
+
+        let index_file = tempfile::tempfile().unwrap();
+        index_file.write_all_at(&[1; 16], 0).unwrap();
+        discard_database(&path, &index_file).unwrap();
+        assert!(read_applied_indexes(&index_file).unwrap().is_none());
+        assert!(init_database(&path, 4096).unwrap());
+    }
+
+    #[tokio::test]
+    async fn write_snapshot_to_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut file = create_snapshot_file(&tmp.path().join("data.db")).unwrap();
+        // the file is only reachable through its handle.
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 0);
+
+        let frame = |page_no, byte| Frame {
+            page_no,
+            data: vec![byte; 512].into(),
+            compression: RpcCompression::None.into(),
+        };
+        write_snapshot_page(&mut file, &frame(2, 2)).await.unwrap();
+        write_snapshot_page(&mut file, &frame(1, 1)).await.unwrap();
+        assert!(write_snapshot_page(&mut file, &frame(0, 0)).await.is_err());
+        file.flush().await.unwrap();
+
+        let file = file.into_std().await;
+        let mut data = vec![0; 1024];
+        file.read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(data[..512], [1; 512]);
+        assert_eq!(data[512..], [2; 512]);
+    }
+
+    /// The batches written by `record_frames`: their number of pages, the size of the database
+    /// after them, and whether they commit.
+    static FRAMES: Mutex<Vec<(usize, u32, c_int)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record_frames(
+        _wal: *mut Wal,
+        _page_size: c_int,
+        page_headers: *mut PgHdr,
+        size_after: u32,
+        is_commit: c_int,
+        _sync_flags: c_int,
+    ) -> c_int {
+        let mut pages = 0;
+        let mut current = page_headers;
+        while !current.is_null() {
+            unsafe {
+                (*current).flags |= 0x040;
+                current = (*current).dirty;
+            }
+            pages += 1;
+        }
+        FRAMES.lock().unwrap().push((pages, size_after, is_commit));
+
+        0
+    }
+
+    #[test]
+    fn write_snapshot_in_batches() {
+        let page_count = 2 * SNAPSHOT_BATCH_PAGES + 10;
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(page_count as u64 * 512).unwrap();
+        let commit = Commit {
+            page_size: 512,
+            size_after: page_count,
+            is_commit: true,
+            sync_flags: 0,
+        };
+
+        write_snapshot_pages(&file, &commit, std::ptr::null_mut(), record_frames).unwrap();
+        let batch = SNAPSHOT_BATCH_PAGES as usize;
+        assert_eq!(
+            *FRAMES.lock().unwrap(),
+            [(batch, 0, 0), (batch, 0, 0), (10, page_count, 1)]
+        );
+
+        // the pages that are missing from the file can't be written.
+        let commit = Commit {
+            size_after: page_count + 1,
+            ..commit
+        };
+        let result = write_snapshot_pages(&file, &commit, std::ptr::null_mut(), record_frames);
+        assert_eq!(result, Err(SQLITE_IOERR));
     }
 }
@@ -0,0 +1,472 @@
+//! HTTP/JSON interface to the database.
+//!
+//! Queries are sent as a `POST /` with a JSON body of the form:
+//!
+//! ```json
+//! { "query": "select * from users where id = ?", "params": [1] }
+//! ```
+//!
+//! `params` is optional, and can either be an array of positional parameters, or an object of
+//! named parameters. Values are mapped to JSON as follows: `NULL` is `null`, integers and reals
+//! are numbers, text is a string, and blobs are objects of the form `{"blob": "<hex>"}`.
+//!
+//! The query may contain several statements, separated by `;`. The response is an array with the
+//! result of each statement, in order. Statements run until one of them fails, in which case the
+//! error of that statement is returned instead, although the statements before it have run.
+//!
+//! Every request runs in its own session: a transaction can't span multiple requests.
+//!
+//! Unless the server trusts every client, requests must authenticate with HTTP basic
+//! authentication, with the same credentials as PostgreSQL clients. The API is served over plain
+//! HTTP, so it should only be exposed to untrusted networks through a TLS terminating proxy.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::{poll_fn, Future};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use futures::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Number};
+use tokio_util::sync::CancellationToken;
+use tower::{MakeService, Service};
+
+use crate::postgres::authenticator::Credentials;
+use crate::query::{
+    ErrorCode, Params, Query, QueryError, QueryResponse, QueryResult, ResultSet, Value,
+};
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct QueryRequest {
+    query: String,
+    params: Option<RequestParams>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RequestParams {
+    Positional(Vec<serde_json::Value>),
+    Named(HashMap<String, serde_json::Value>),
+}
+
+impl TryFrom<RequestParams> for Params {
+    type Error = String;
+
+    fn try_from(other: RequestParams) -> Result<Self, Self::Error> {
+        Ok(match other {
+            RequestParams::Positional(values) => Params::Positional(
+                values
+                    .into_iter()
+                    .map(value_from_json)
+                    .collect::<Result<_, _>>()?,
+            ),
+            RequestParams::Named(values) => Params::Named(
+                values
+                    .into_iter()
+                    .map(|(name, value)| Ok((name, value_from_json(value)?)))
+                    .collect::<Result<_, String>>()?,
+            ),
+        })
+    }
+}
+
+fn value_from_json(value: serde_json::Value) -> Result<Value, String> {
+    Ok(match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(i64::from(b)),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().ok_or("number out of range")?),
+        },
+        serde_json::Value::String(s) => Value::Text(s),
+        serde_json::Value::Object(o) => match o.get("blob") {
+            Some(serde_json::Value::String(data)) if o.len() == 1 => {
+                Value::Blob(hex::decode(data).map_err(|e| format!("invalid blob: {e}"))?)
+            }
+            _ => return Err(format!("unsupported parameter value: {}", json!(o))),
+        },
+        serde_json::Value::Array(_) => {
+            return Err(format!("unsupported parameter value: {value}"));
+        }
+    })
+}
+
+fn value_to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => i.into(),
+        Value::Real(x) => Number::from_f64(x)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        Value::Text(s) => s.into(),
+        Value::Blob(b) => json!({ "blob": hex::encode(b) }),
+    }
+}
+
+async fn result_set_to_json(
+    ResultSet {
+        columns,
+        rows,
+        affected_row_count,
+        last_insert_rowid,
+        ..
+    }: ResultSet,
+) -> Result<serde_json::Value, QueryError> {
+    let decltypes = columns
+        .iter()
+        .map(|c| c.decltype.clone())
+        .collect::<Vec<_>>();
+    let columns = columns.into_iter().map(|c| c.name).collect::<Vec<_>>();
+    let rows = rows
+        .map_ok(|row| {
+            row.values
+                .into_iter()
+                .map(value_to_json)
+                .collect::<Vec<_>>()
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    Ok(json!({
+        "columns": columns,
+        "decltypes": decltypes,
+        "rows": rows,
+        "affected_row_count": affected_row_count,
+        "last_insert_rowid": last_insert_rowid,
+    }))
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, code: &str, message: impl ToString) -> Response<Body> {
+    let body = json!({
+        "error": {
+            "code": code,
+            "message": message.to_string(),
+        }
+    });
+
+    json_response(status, body)
+}
+
+fn query_error_response(error: QueryError) -> Response<Body> {
+    let (status, code) = match error.code {
+        ErrorCode::SQLError => (StatusCode::BAD_REQUEST, "SQL_ERROR"),
+        ErrorCode::TxBusy => (StatusCode::SERVICE_UNAVAILABLE, "TX_BUSY"),
+        ErrorCode::TxTimeout => (StatusCode::REQUEST_TIMEOUT, "TX_TIMEOUT"),
+        ErrorCode::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL"),
+        ErrorCode::ConstraintViolation => (StatusCode::CONFLICT, "CONSTRAINT_VIOLATION"),
+        ErrorCode::UniqueViolation => (StatusCode::CONFLICT, "UNIQUE_VIOLATION"),
+        ErrorCode::ForeignKeyViolation => (StatusCode::CONFLICT, "FOREIGN_KEY_VIOLATION"),
+        ErrorCode::NotNullViolation => (StatusCode::CONFLICT, "NOT_NULL_VIOLATION"),
+        ErrorCode::CheckViolation => (StatusCode::CONFLICT, "CHECK_VIOLATION"),
+        ErrorCode::QueryCanceled => (StatusCode::BAD_REQUEST, "QUERY_CANCELED"),
+    };
+
+    error_response(status, code, error.msg)
+}
+
+/// Reads the body of `req`, unless it is larger than `MAX_BODY_SIZE`.
+async fn read_body(req: Request<Body>) -> Result<Bytes, Response<Body>> {
+    let too_large = || {
+        error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "INVALID_REQUEST",
+            format!("the request body is larger than {MAX_BODY_SIZE} bytes"),
+        )
+    };
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<usize>().ok());
+    if content_length.map_or(false, |len| len > MAX_BODY_SIZE) {
+        return Err(too_large());
+    }
+
+    // The body may be sent in chunks, without its length.
+    let mut body = req.into_body();
+    let mut data = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk =
+            chunk.map_err(|e| error_response(StatusCode::BAD_REQUEST, "INVALID_REQUEST", e))?;
+        if data.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data.freeze())
+}
+
+/// Returns whether `req` carries the basic authentication credentials of one of the users in
+/// `credentials`.
+fn is_authorized(req: &Request<Body>, credentials: &Credentials) -> bool {
+    let Some(header) = req.headers().get(AUTHORIZATION) else { return false };
+    let Some(encoded) = header.to_str().ok().and_then(|h| h.strip_prefix("Basic ")) else {
+        return false
+    };
+    let Ok(decoded) = base64::decode(encoded.trim()) else { return false };
+    let Ok(decoded) = String::from_utf8(decoded) else { return false };
+
+    match decoded.split_once(':') {
+        Some((user, password)) => credentials.check(user, password),
+        None => false,
+    }
+}
+
+async fn handle_query<F>(mut factory: F, req: Request<Body>) -> Response<Body>
+where
+    F: MakeService<(), Query, Response = Vec<QueryResult>, Error = QueryError>,
+    F::MakeError: ToString,
+{
+    let body = match read_body(req).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    let QueryRequest { query, params } = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, "INVALID_REQUEST", e),
+    };
+    let params = match params.map(Params::try_from).transpose() {
+        Ok(params) => params.unwrap_or_else(Params::empty),
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, "INVALID_REQUEST", e),
+    };
+
+    let mut service = match poll_fn(|cx| factory.poll_ready(cx)).await {
+        Ok(()) => match factory.make_service(()).await {
+            Ok(service) => service,
+            Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL", e),
+        },
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL", e),
+    };
+    if let Err(e) = poll_fn(|cx| service.poll_ready(cx)).await {
+        return query_error_response(e);
+    }
+
+    let results = match service.call(Query::SimpleQuery(query, params)).await {
+        Ok(results) => results,
+        Err(e) => return query_error_response(e),
+    };
+    let mut sets = Vec::with_capacity(results.len());
+    for result in results {
+        match result {
+            Ok(QueryResponse::ResultSet(set)) => match result_set_to_json(set).await {
+                Ok(set) => sets.push(set),
+                Err(e) => return query_error_response(e),
+            },
+            Ok(QueryResponse::Description(_)) => {
+                unreachable!("the HTTP API does not describe statements")
+            }
+            Err(e) => return query_error_response(e),
+        }
+    }
+
+    json_response(StatusCode::OK, serde_json::Value::Array(sets))
+}
+
+async fn handle_request<F>(
+    factory: F,
+    credentials: Option<Arc<Credentials>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible>
+where
+    F: MakeService<(), Query, Response = Vec<QueryResult>, Error = QueryError>,
+    F::MakeError: ToString,
+{
+    if let Some(ref credentials) = credentials {
+        if !is_authorized(&req, credentials) {
+            let mut response = error_response(
+                StatusCode::UNAUTHORIZED,
+                "UNAUTHORIZED",
+                "invalid credentials",
+            );
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, "Basic realm=\"sqld\"".parse().unwrap());
+            return Ok(response);
+        }
+    }
+    if req.uri().path() != "/" {
+        return Ok(error_response(
+            StatusCode::NOT_FOUND,
+            "INVALID_REQUEST",
+            "not found",
+        ));
+    }
+    if req.method() != Method::POST {
+        return Ok(error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "INVALID_REQUEST",
+            "only POST is supported",
+        ));
+    }
+
+    Ok(handle_query(factory, req).await)
+}
+
+/// Binds the HTTP/JSON API to `addr`, and returns the server, that serves it until `shutdown` is
+/// cancelled. A new database session is created for each request.
+///
+/// Requests are authenticated against `credentials`, unless they are `None`.
+pub fn run_http<F>(
+    addr: SocketAddr,
+    factory: F,
+    credentials: Option<Arc<Credentials>>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<impl Future<Output = anyhow::Result<()>>>
+where
+    F: MakeService<(), Query, Response = Vec<QueryResult>, Error = QueryError>
+        + Clone
+        + Send
+        + 'static,
+    F::MakeError: ToString,
+    F::Future: Send,
+    F::Service: Send,
+    <F::Service as Service<Query>>::Future: Send,
+{
+    let make_svc = make_service_fn(move |_conn| {
+        let factory = factory.clone();
+        let credentials = credentials.clone();
+        let service =
+            service_fn(move |req| handle_request(factory.clone(), credentials.clone(), req));
+        async move { Ok::<_, Infallible>(service) }
+    });
+
+    let server = hyper::Server::try_bind(&addr)?
+        .serve(make_svc)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await });
+    tracing::info!("listening for HTTP requests on {addr}");
+
+    Ok(async move {
+        server.await?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::task::{Context, Poll};
+
+    use futures::future;
+
+    use super::*;
+
+    /// Creates `Empty` sessions.
+    struct EmptyFactory;
+
+    /// A database session that returns no result to any query.
+    struct Empty;
+
+    impl Service<()> for EmptyFactory {
+        type Response = Empty;
+        type Error = Infallible;
+        type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Ok(()).into()
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            future::ok(Empty)
+        }
+    }
+
+    impl Service<Query> for Empty {
+        type Response = Vec<QueryResult>;
+        type Error = QueryError;
+        type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Ok(()).into()
+        }
+
+        fn call(&mut self, _: Query) -> Self::Future {
+            future::ok(Vec::new())
+        }
+    }
+
+    async fn handle(credentials: Option<Arc<Credentials>>, req: Request<Body>) -> Response<Body> {
+        handle_request(EmptyFactory, credentials, req)
+            .await
+            .unwrap()
+    }
+
+    fn query(authorization: Option<&str>) -> Request<Body> {
+        let mut req = Request::post("/");
+        if let Some(credentials) = authorization {
+            req = req.header(
+                AUTHORIZATION,
+                format!("Basic {}", base64::encode(credentials)),
+            );
+        }
+
+        req.body(Body::from(r#"{"query": "select 1"}"#)).unwrap()
+    }
+
+    fn credentials() -> Option<Arc<Credentials>> {
+        let mut credentials = Credentials::default();
+        credentials.add("alice:secret").unwrap();
+
+        Some(Arc::new(credentials))
+    }
+
+    #[tokio::test]
+    async fn basic_authentication() {
+        let response = handle(credentials(), query(None)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+
+        let response = handle(credentials(), query(Some("alice:wrong"))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = handle(credentials(), query(Some("bob:secret"))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = handle(credentials(), query(Some("alice:secret"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"[]");
+    }
+
+    #[tokio::test]
+    async fn trust() {
+        let response = handle(None, query(None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn body_too_large() {
+        let req = Request::post("/")
+            .header(CONTENT_LENGTH, MAX_BODY_SIZE + 1)
+            .body(Body::empty())
+            .unwrap();
+        let response = handle(None, req).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // a body sent without its length is only read up to the limit.
+        let req = Request::post("/")
+            .body(Body::from(vec![b' '; MAX_BODY_SIZE + 1]))
+            .unwrap();
+        let response = handle(None, req).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = Request::post("/")
+            .body(Body::from(vec![b' '; MAX_BODY_SIZE]))
+            .unwrap();
+        let response = handle(None, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -3,19 +3,32 @@ use std::path::PathBuf;
 use std::sync::Arc;
 #[cfg(feature = "mwal_backend")]
 use std::sync::Mutex;
+use std::time::Duration;
 
 use anyhow::Result;
+use data_dir::DataDir;
 use database::libsql::LibSqlDb;
 use database::service::DbFactoryService;
 use database::write_proxy::WriteProxyDbFactory;
+use http::run_http;
+use metrics::run_metrics_server;
 use rpc::run_rpc_server;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
 use wal_logger::{WalLogger, WalLoggerHook};
 
 use crate::postgres::service::PgConnectionFactory;
+use crate::server::tls::load_tls_acceptor;
 use crate::server::Server;
 
+pub use crate::postgres::authenticator::{AuthMethod, Credentials};
+pub use crate::wal_logger::Compression;
+
+mod data_dir;
 mod database;
+mod http;
 mod libsql;
+mod metrics;
 mod postgres;
 mod query;
 mod query_analysis;
@@ -30,20 +43,102 @@ pub enum Backend {
     Mwal,
 }
 
-pub async fn run_server(
-    db_path: PathBuf,
-    tcp_addr: SocketAddr,
-    ws_addr: Option<SocketAddr>,
-    backend: Backend,
-    #[cfg(feature = "mwal_backend")] mwal_addr: Option<String>,
-    writer_rpc_addr: Option<String>,
-    rpc_server_addr: Option<SocketAddr>,
-) -> Result<()> {
+pub struct Config {
+    /// Directory holding the database, and all the other state of the server.
+    pub data_dir: PathBuf,
+    pub tcp_addr: SocketAddr,
+    pub ws_addr: Option<SocketAddr>,
+    pub http_addr: Option<SocketAddr>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub backend: Backend,
+    #[cfg(feature = "mwal_backend")]
+    pub mwal_addr: Option<String>,
+    pub writer_rpc_addr: Option<String>,
+    pub rpc_server_addr: Option<SocketAddr>,
+    pub pg_auth_method: AuthMethod,
+    pub pg_credentials: Credentials,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    /// Size in bytes past which the WAL log is compacted.
+    pub max_log_size: usize,
+    /// Compression of the entries of the WAL log on the primary, and of the pages a replica asks
+    /// the primary to stream.
+    pub compression: Compression,
+    /// How long a transaction may stay idle before it's rolled back. Sessions can override it.
+    pub txn_timeout: Duration,
+    /// On shutdown, how long open connections are given to terminate, and then how long their
+    /// database sessions are given to roll back.
+    pub shutdown_grace_period: Duration,
+}
+
+/// Resolves when the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res,
+        _ = sigterm.recv() => Ok(()),
+    }
+}
+
+pub async fn run_server(config: Config) -> Result<()> {
+    let Config {
+        data_dir,
+        tcp_addr,
+        ws_addr,
+        http_addr,
+        metrics_addr,
+        backend,
+        #[cfg(feature = "mwal_backend")]
+        mwal_addr,
+        writer_rpc_addr,
+        rpc_server_addr,
+        pg_auth_method,
+        pg_credentials,
+        tls_cert_path,
+        tls_key_path,
+        max_log_size,
+        compression,
+        txn_timeout,
+        shutdown_grace_period,
+    } = config;
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            match shutdown_signal().await {
+                Ok(()) => {
+                    tracing::info!("shutting down");
+                    shutdown.cancel();
+                }
+                Err(e) => tracing::error!("failed to listen for shutdown signals: {e}"),
+            }
+        }
+    });
+
+    // Held until the server stops, so that no other process uses the same files.
+    let data_dir = DataDir::open(&data_dir)?;
+    let db_path = data_dir.db_path();
+
+    let pg_credentials = Arc::new(pg_credentials);
+    // The HTTP API is authenticated with the credentials of PostgreSQL clients.
+    let http_credentials = (pg_auth_method != AuthMethod::Trust).then(|| pg_credentials.clone());
+    let tls = match (tls_cert_path, tls_key_path) {
+        (Some(cert_path), Some(key_path)) => Some(load_tls_acceptor(&cert_path, &key_path)?),
+        (None, None) => None,
+        _ => anyhow::bail!("both a TLS certificate and a TLS private key must be provided"),
+    };
+
+    let metrics_server = metrics_addr
+        .map(|addr| run_metrics_server(addr, shutdown.clone()))
+        .transpose()?
+        .map(tokio::spawn);
+
     let mut server = Server::new();
     server.bind_tcp(tcp_addr).await?;
 
     if let Some(addr) = ws_addr {
-        server.bind_ws(addr).await?;
+        server.bind_ws(addr, tls.clone()).await?;
     }
 
     tracing::trace!("Backend: {:?}", backend);
@@ -56,22 +151,44 @@ pub async fn run_server(
     let vwal_methods =
         mwal_addr.map(|_| Arc::new(Mutex::new(mwal::ffi::libsql_wal_methods::new())));
 
-    match writer_rpc_addr {
+    let checkpoint_db_path = db_path.clone();
+    let (logger, rpc_server, http_server) = match writer_rpc_addr {
         Some(addr) => {
             let factory = WriteProxyDbFactory::new(
                 addr,
                 db_path,
+                data_dir.replica_index_path(),
                 #[cfg(feature = "mwal_backend")]
                 vwal_methods,
+                txn_timeout,
+                compression,
             )
             .await?;
             let service = DbFactoryService::new(factory);
-            let factory = PgConnectionFactory::new(service);
-            server.serve(factory).await;
+            let http_server = http_addr
+                .map(|addr| run_http(addr, service.clone(), http_credentials, shutdown.clone()))
+                .transpose()?
+                .map(tokio::spawn);
+            let factory = PgConnectionFactory::new(
+                service,
+                pg_auth_method,
+                pg_credentials,
+                tls,
+                shutdown.clone(),
+            );
+            server
+                .serve(factory, shutdown.clone(), shutdown_grace_period)
+                .await;
+            (None, None, http_server)
         }
         None => {
-            let logger = Arc::new(WalLogger::open("wallog")?);
+            let logger = Arc::new(WalLogger::open(
+                data_dir.wal_log_path(),
+                max_log_size,
+                compression,
+            )?);
             let logger_clone = logger.clone();
+            let snapshot_db_path = db_path.clone();
             let db_factory = move || {
                 let db_path = db_path.clone();
                 #[cfg(feature = "mwal_backend")]
@@ -83,17 +200,69 @@ pub async fn run_server(
                         #[cfg(feature = "mwal_backend")]
                         vwal_methods,
                         hook,
+                        txn_timeout,
                     )
                 }
             };
             let service = DbFactoryService::new(db_factory.clone());
-            let factory = PgConnectionFactory::new(service);
-            if let Some(addr) = rpc_server_addr {
-                tokio::spawn(run_rpc_server(addr, db_factory, logger_clone));
-            }
-            server.serve(factory).await;
+            let http_server = http_addr
+                .map(|addr| run_http(addr, service.clone(), http_credentials, shutdown.clone()))
+                .transpose()?
+                .map(tokio::spawn);
+            let factory = PgConnectionFactory::new(
+                service,
+                pg_auth_method,
+                pg_credentials,
+                tls,
+                shutdown.clone(),
+            );
+            let rpc_server = rpc_server_addr.map(|addr| {
+                tokio::spawn(run_rpc_server(
+                    addr,
+                    db_factory,
+                    logger_clone.clone(),
+                    snapshot_db_path,
+                    shutdown.clone(),
+                ))
+            });
+            server
+                .serve(factory, shutdown.clone(), shutdown_grace_period)
+                .await;
+            (Some(logger_clone), rpc_server, http_server)
         }
+    };
+
+    // The RPC, HTTP and metrics servers were notified of the shutdown along with the other
+    // listeners. Once they have stopped, and all the connections are closed, the database workers
+    // roll back their open transactions and terminate.
+    let mut servers_result = Ok(());
+    let terminated = async {
+        for server in [http_server, metrics_server].into_iter().flatten() {
+            let result = server
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|res| res);
+            servers_result = servers_result.and(result);
+        }
+        if let Some(rpc_server) = rpc_server {
+            let _ = rpc_server.await;
+        }
+        LibSqlDb::wait_for_workers().await;
+    };
+    if tokio::time::timeout(shutdown_grace_period, terminated)
+        .await
+        .is_err()
+    {
+        tracing::warn!("some database sessions were still open after the shutdown grace period");
+    }
+
+    if let Some(logger) = logger {
+        logger.sync()?;
+    }
+    if backend == Backend::Libsql {
+        tokio::task::spawn_blocking(move || LibSqlDb::checkpoint(&checkpoint_db_path)).await??;
     }
+    tracing::info!("shutdown complete");
 
-    Ok(())
+    servers_result
 }
@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use anyhow::Result;
 use clap::Parser;
@@ -8,14 +8,23 @@ use clap::Parser;
 #[command(name = "sqld")]
 #[command(about = "SQL daemon", long_about = None)]
 struct Cli {
-    #[clap(long, short, default_value = "iku.db")]
-    db_path: PathBuf,
+    /// The directory holding the database, and all the other state of the server. It can't be
+    /// shared with another running instance.
+    #[clap(long, short, default_value = "data.sqld")]
+    data_dir: PathBuf,
     /// The address and port the PostgreSQL server listens to.
     #[clap(long, short, default_value = "127.0.0.1:5000")]
     pg_listen_addr: SocketAddr,
     /// The address and port the PostgreSQL over WebSocket server listens to.
     #[clap(long, short)]
     ws_listen_addr: Option<SocketAddr>,
+    /// The address and port the HTTP/JSON query API listens to. Example: `127.0.0.1:8080`.
+    #[clap(long)]
+    http_listen_addr: Option<SocketAddr>,
+    /// The address and port Prometheus metrics are served on, at `/metrics`. Example:
+    /// `127.0.0.1:9090`.
+    #[clap(long)]
+    metrics_listen_addr: Option<SocketAddr>,
     /// The address and port the inter-node RPC protocol listens to. Example: `0.0.0.0:5001`.
     #[clap(long, conflicts_with = "primary_grpc_url")]
     grpc_listen_addr: Option<SocketAddr>,
@@ -24,6 +33,35 @@ struct Cli {
     primary_grpc_url: Option<String>,
     #[clap(long, short, value_enum, default_value = "libsql")]
     backend: sqld::Backend,
+    /// The authentication method PostgreSQL clients must use.
+    #[clap(long, value_enum, default_value = "trust")]
+    pg_auth_method: sqld::AuthMethod,
+    /// Path to a file containing the `user:password` pairs allowed to connect, one per line.
+    #[clap(long)]
+    pg_auth_file: Option<PathBuf>,
+    /// Path to a PEM-encoded TLS certificate chain. When set, PostgreSQL clients can negotiate
+    /// TLS, and the WebSocket listener only accepts wss connections.
+    #[clap(long, requires = "tls_key_file")]
+    tls_cert_file: Option<PathBuf>,
+    /// Path to the PEM-encoded private key of the TLS certificate.
+    #[clap(long, requires = "tls_cert_file")]
+    tls_key_file: Option<PathBuf>,
+    /// Size of the WAL log, in MiB, past which its oldest entries are compacted away. Replicas
+    /// that fall behind the compacted log bootstrap from a snapshot of the database.
+    #[clap(long, default_value = "256")]
+    max_log_size: usize,
+    /// Compression of the frames of the WAL log. On a replica, compression the primary is asked to
+    /// use for the frames it streams.
+    #[clap(long, value_enum, default_value = "none")]
+    compression: sqld::Compression,
+    /// How long, in seconds, a transaction may stay idle before it's rolled back. Sessions can
+    /// override it with `SET txn_timeout = <seconds>`, or with the `txn_timeout` startup parameter.
+    #[clap(long, default_value = "5", value_parser = clap::value_parser!(u64).range(1..))]
+    txn_timeout_s: u64,
+    /// On shutdown, how long, in seconds, open connections are given to terminate. Database
+    /// sessions are then given the same time to roll back their transactions.
+    #[clap(long, default_value = "2")]
+    shutdown_grace_period_s: u64,
     // The url to connect with mWAL backend, based on mvSQLite
     #[cfg(feature = "mwal_backend")]
     #[clap(long, short)]
@@ -49,17 +87,39 @@ async fn main() -> Result<()> {
         _ => (),
     }
 
-    sqld::run_server(
-        args.db_path,
-        args.pg_listen_addr,
-        args.ws_listen_addr,
-        args.backend,
+    let mut pg_credentials = sqld::Credentials::default();
+    if let Some(ref path) = args.pg_auth_file {
+        pg_credentials.load(path)?;
+    }
+    if args.pg_auth_method != sqld::AuthMethod::Trust && pg_credentials.is_empty() {
+        anyhow::bail!(
+            "--pg-auth-method {:?} requires credentials from --pg-auth-file",
+            args.pg_auth_method
+        );
+    }
+
+    let config = sqld::Config {
+        data_dir: args.data_dir,
+        tcp_addr: args.pg_listen_addr,
+        ws_addr: args.ws_listen_addr,
+        http_addr: args.http_listen_addr,
+        metrics_addr: args.metrics_listen_addr,
+        backend: args.backend,
         #[cfg(feature = "mwal_backend")]
-        args.mwal_addr,
-        args.primary_grpc_url,
-        args.grpc_listen_addr,
-    )
-    .await?;
+        mwal_addr: args.mwal_addr,
+        writer_rpc_addr: args.primary_grpc_url,
+        rpc_server_addr: args.grpc_listen_addr,
+        pg_auth_method: args.pg_auth_method,
+        pg_credentials,
+        tls_cert_path: args.tls_cert_file,
+        tls_key_path: args.tls_key_file,
+        max_log_size: args.max_log_size * 1024 * 1024,
+        compression: args.compression,
+        txn_timeout: Duration::from_secs(args.txn_timeout_s),
+        shutdown_grace_period: Duration::from_secs(args.shutdown_grace_period_s),
+    };
+
+    sqld::run_server(config).await?;
 
     Ok(())
 }
@@ -0,0 +1,165 @@
+//! Prometheus metrics, and the HTTP endpoint exposing them.
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use tokio_util::sync::CancellationToken;
+
+pub static ACTIVE_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "sqld_active_connections",
+        "Number of open client connections"
+    )
+    .unwrap()
+});
+
+/// Labelled by query kind: `read` or `write`.
+pub static QUERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sqld_queries_total",
+        "Number of executed queries",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+/// Labelled by query kind: `read` or `write`. Measured until the rows of the query are read.
+pub static QUERY_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sqld_query_duration_seconds",
+        "Query latency, until its rows are read",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+pub static TXN_TIMEOUTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "sqld_txn_timeouts_total",
+        "Number of transactions rolled back because they timed out"
+    )
+    .unwrap()
+});
+
+pub static WAL_LOG_SIZE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("sqld_wal_log_size_bytes", "Size of the WAL log file").unwrap()
+});
+
+pub static WAL_LOG_FRAMES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "sqld_wal_log_frames",
+        "Number of entries in the WAL log, since it was last compacted"
+    )
+    .unwrap()
+});
+
+pub static REPLICA_APPLIED_INDEX: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "sqld_replica_applied_index",
+        "Index of the last log entry applied by the replica"
+    )
+    .unwrap()
+});
+
+pub static REPLICA_PRIMARY_INDEX: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "sqld_replica_primary_index",
+        "Index of the last entry of the primary's log, as of the last entry the replica received"
+    )
+    .unwrap()
+});
+
+pub static REPLICA_LAG: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "sqld_replica_lag",
+        "Number of entries of the primary's log that the replica has yet to apply"
+    )
+    .unwrap()
+});
+
+pub static REPLICA_HEALTHY: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "sqld_replica_healthy",
+        "Whether the replica is applying the primary's transactions: 0 while it syncs again after \
+        failing to apply one"
+    )
+    .unwrap()
+});
+
+pub static REPLICA_APPLY_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "sqld_replica_apply_failures_total",
+        "Number of failed attempts of the replica to apply a transaction"
+    )
+    .unwrap()
+});
+
+pub static REPLICA_RESYNCS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "sqld_replica_resyncs_total",
+        "Number of times the replica dropped its state to sync again from the primary"
+    )
+    .unwrap()
+});
+
+/// Records the replica's progress. Either index may be `None` if it is unchanged.
+pub fn update_replica_indexes(applied: Option<u64>, primary: Option<u64>) {
+    if let Some(applied) = applied {
+        REPLICA_APPLIED_INDEX.set(applied as _);
+    }
+    if let Some(primary) = primary {
+        REPLICA_PRIMARY_INDEX.set(primary as _);
+    }
+    let lag = REPLICA_PRIMARY_INDEX.get() - REPLICA_APPLIED_INDEX.get();
+    REPLICA_LAG.set(lag.max(0));
+}
+
+async fn handle_request(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/metrics" || req.method() != Method::GET {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    let response = match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => Response::builder()
+            .header(CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buffer)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string())),
+    };
+
+    Ok(response.unwrap())
+}
+
+/// Binds the metrics endpoint to `addr`, and returns the server, that serves the metrics on
+/// `GET /metrics` until `shutdown` is cancelled.
+pub fn run_metrics_server(
+    addr: SocketAddr,
+    shutdown: CancellationToken,
+) -> anyhow::Result<impl Future<Output = anyhow::Result<()>>> {
+    let make_svc =
+        make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+
+    let server = hyper::Server::try_bind(&addr)?
+        .serve(make_svc)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await });
+    tracing::info!("serving metrics on {addr}");
+
+    Ok(async move {
+        server.await?;
+        Ok(())
+    })
+}
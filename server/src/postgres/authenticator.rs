@@ -1,13 +1,160 @@
-use pgwire::api::auth::{noop::NoopStartupHandler, StartupHandler};
-use pgwire::error::PgWireError;
-use pgwire::messages::PgWireFrontendMessage;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{bail, Context};
+use futures::{Sink, SinkExt};
+use pgwire::api::auth::noop::NoopStartupHandler;
+use pgwire::api::auth::scram::{gen_salted_password, SASLScramAuthStartupHandler};
+use pgwire::api::auth::{
+    finish_authentication, save_startup_parameters_to_metadata, AuthSource,
+    DefaultServerParameterProvider, LoginInfo, Password, StartupHandler,
+};
+use pgwire::api::{ClientInfo, PgWireConnectionState};
+use pgwire::error::{PgWireError, PgWireResult};
+use pgwire::messages::startup::Authentication;
+use pgwire::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 use pgwire::tokio::PgWireMessageServerCodec;
+use rand::RngCore;
+use subtle::ConstantTimeEq;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::Framed;
 
-pub struct PgAuthenticator;
+/// Number of PBKDF2 iterations used to derive SCRAM salted passwords.
+const SCRAM_ITERATIONS: usize = 4096;
+
+/// Authentication method used by the PostgreSQL listener.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMethod {
+    /// Accept every client without asking for a password.
+    Trust,
+    /// Ask the client for a cleartext password.
+    Password,
+    /// Ask the client for a salted MD5 hash of its password.
+    Md5,
+    /// SCRAM-SHA-256 challenge-response authentication.
+    #[value(name = "scram-sha-256")]
+    ScramSha256,
+}
+
+/// User credentials accepted by the PostgreSQL listener.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    users: HashMap<String, String>,
+}
+
+impl Credentials {
+    /// Parses a single `user:password` pair.
+    pub fn add(&mut self, pair: &str) -> anyhow::Result<()> {
+        let Some((user, password)) = pair.split_once(':') else {
+            bail!("invalid credentials `{pair}`: expected `user:password`")
+        };
+        if user.is_empty() {
+            bail!("invalid credentials `{pair}`: empty user name");
+        }
+        self.users.insert(user.to_owned(), password.to_owned());
+
+        Ok(())
+    }
+
+    /// Loads credentials from a file containing one `user:password` pair per line. Empty lines
+    /// and lines starting with `#` are ignored.
+    pub fn load(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read credentials file {}", path.display()))?;
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.add(line)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Returns whether `password` is the password of `user`.
+    pub fn check(&self, user: &str, password: &str) -> bool {
+        self.password(user)
+            .map_or(false, |expected| secrets_eq(password, expected))
+    }
+
+    fn password(&self, user: &str) -> Option<&str> {
+        self.users.get(user).map(String::as_str)
+    }
+}
+
+#[async_trait::async_trait]
+impl AuthSource for Credentials {
+    async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password> {
+        let user = login.user().cloned().unwrap_or_default();
+        let Some(password) = self.password(&user) else {
+            return Err(PgWireError::InvalidPassword(user));
+        };
+        let mut salt = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let salted_password = gen_salted_password(password, &salt, SCRAM_ITERATIONS);
+
+        Ok(Password::new(Some(salt), salted_password))
+    }
+}
+
+/// Compares secrets in constant time, so that the time taken doesn't tell how much of them match.
+fn secrets_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+type ScramHandler = SASLScramAuthStartupHandler<Credentials, DefaultServerParameterProvider>;
+
+enum Handler {
+    Trust,
+    Password,
+    Md5 { salt: [u8; 4] },
+    Scram(ScramHandler),
+}
+
+/// Authenticates a single PostgreSQL connection.
+///
+/// An authenticator holds per-connection state (the MD5 salt, the SCRAM exchange), so a new one
+/// must be created for each connection.
+///
+/// The ReadyForQuery that completes the startup is held back: the connection sends it once the
+/// session of the authenticated client is ready.
+pub struct PgAuthenticator {
+    handler: Handler,
+    credentials: Arc<Credentials>,
+}
 
 impl PgAuthenticator {
+    pub fn new(method: AuthMethod, credentials: Arc<Credentials>) -> Self {
+        let handler = match method {
+            AuthMethod::Trust => Handler::Trust,
+            AuthMethod::Password => Handler::Password,
+            AuthMethod::Md5 => {
+                let mut salt = [0; 4];
+                rand::thread_rng().fill_bytes(&mut salt);
+                Handler::Md5 { salt }
+            }
+            AuthMethod::ScramSha256 => Handler::Scram(ScramHandler::new(
+                credentials.clone(),
+                Arc::new(DefaultServerParameterProvider),
+            )),
+        };
+
+        Self {
+            handler,
+            credentials,
+        }
+    }
+
     pub async fn authenticate<T>(
         &self,
         client: &mut Framed<T, PgWireMessageServerCodec>,
@@ -16,8 +163,246 @@ impl PgAuthenticator {
     where
         T: AsyncRead + AsyncWrite + Unpin + Send,
     {
-        NoopStartupHandler.on_startup(client, msg).await?;
+        let client = &mut HoldReady { client };
+        match self.handler {
+            Handler::Trust => NoopStartupHandler.on_startup(client, msg).await?,
+            Handler::Scram(ref scram) => scram.on_startup(client, msg).await?,
+            Handler::Password | Handler::Md5 { .. } => self.password_auth(client, msg).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Cleartext and MD5 password authentication. Both methods only differ by the challenge sent
+    /// to the client and by how the received password is compared to the expected one.
+    async fn password_auth<C>(
+        &self,
+        client: &mut C,
+        msg: PgWireFrontendMessage,
+    ) -> Result<(), PgWireError>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<C::Error>,
+    {
+        match msg {
+            PgWireFrontendMessage::Startup(ref startup) => {
+                save_startup_parameters_to_metadata(client, startup);
+                client.set_state(PgWireConnectionState::AuthenticationInProgress);
+                let challenge = match self.handler {
+                    Handler::Md5 { salt } => Authentication::MD5Password(salt.to_vec()),
+                    _ => Authentication::CleartextPassword,
+                };
+                client
+                    .send(PgWireBackendMessage::Authentication(challenge))
+                    .await?;
+            }
+            PgWireFrontendMessage::PasswordMessageFamily(pwd) => {
+                let pwd = pwd.into_password()?;
+                let user = LoginInfo::from_client_info(client)
+                    .user()
+                    .cloned()
+                    .unwrap_or_default();
+                let valid = match (&self.handler, self.credentials.password(&user)) {
+                    (Handler::Md5 { salt }, Some(expected)) => {
+                        let hash = postgres_protocol::authentication::md5_hash(
+                            user.as_bytes(),
+                            expected.as_bytes(),
+                            *salt,
+                        );
+                        secrets_eq(pwd.password(), &hash)
+                    }
+                    (_, Some(expected)) => secrets_eq(pwd.password(), expected),
+                    (_, None) => false,
+                };
+
+                if !valid {
+                    tracing::warn!("password authentication failed for user `{user}`");
+                    return Err(PgWireError::InvalidPassword(user));
+                }
+
+                finish_authentication(client, &DefaultServerParameterProvider).await;
+            }
+            _ => (),
+        }
 
         Ok(())
     }
 }
+
+/// Wraps a client during authentication, to drop the ReadyForQuery that completes the startup.
+struct HoldReady<'a, C> {
+    client: &'a mut C,
+}
+
+impl<C: ClientInfo> ClientInfo for HoldReady<'_, C> {
+    fn socket_addr(&self) -> &SocketAddr {
+        self.client.socket_addr()
+    }
+
+    fn is_secure(&self) -> bool {
+        self.client.is_secure()
+    }
+
+    fn state(&self) -> &PgWireConnectionState {
+        self.client.state()
+    }
+
+    fn set_state(&mut self, new_state: PgWireConnectionState) {
+        self.client.set_state(new_state)
+    }
+
+    fn metadata(&self) -> &HashMap<String, String> {
+        self.client.metadata()
+    }
+
+    fn metadata_mut(&mut self) -> &mut HashMap<String, String> {
+        self.client.metadata_mut()
+    }
+}
+
+impl<C> Sink<PgWireBackendMessage> for HoldReady<'_, C>
+where
+    C: Sink<PgWireBackendMessage> + Unpin,
+{
+    type Error = C::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), C::Error>> {
+        Pin::new(&mut *self.get_mut().client).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: PgWireBackendMessage) -> Result<(), C::Error> {
+        match item {
+            PgWireBackendMessage::ReadyForQuery(_) => Ok(()),
+            item => Pin::new(&mut *self.get_mut().client).start_send(item),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), C::Error>> {
+        Pin::new(&mut *self.get_mut().client).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), C::Error>> {
+        Pin::new(&mut *self.get_mut().client).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use futures::StreamExt;
+    use pgwire::api::ClientInfoHolder;
+    use postgres_protocol::authentication::md5_hash;
+    use postgres_protocol::authentication::sasl::{ChannelBinding, ScramSha256};
+    use postgres_protocol::message::frontend;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::Decoder;
+
+    use std::io::Write;
+
+    use super::*;
+    use crate::postgres::proto::process_error;
+
+    /// Authenticates as `user` with `password`, to a server that authenticates its clients with
+    /// `method`, and only knows `alice`, whose password is `secret`. Returns the SQLSTATE of the
+    /// error the server replied with, if the authentication failed.
+    async fn authenticate(method: AuthMethod, user: &str, password: &str) -> Result<(), String> {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut credentials = Credentials::default();
+        credentials.add("alice:secret").unwrap();
+        let authenticator = PgAuthenticator::new(method, Arc::new(credentials));
+        let client_info = ClientInfoHolder::new(([127, 0, 0, 1], 5432).into(), false);
+        let mut socket = PgWireMessageServerCodec::new(client_info).framed(server);
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = socket.next().await {
+                if let Err(e) = authenticator.authenticate(&mut socket, msg).await {
+                    let _ = process_error(&mut socket, e).await;
+                }
+            }
+        });
+
+        let mut buf = BytesMut::new();
+        frontend::startup_message([("user", user)], &mut buf).unwrap();
+        let mut scram = None;
+        loop {
+            client.write_all(&buf).await.unwrap();
+            buf.clear();
+            let tag = client.read_u8().await.unwrap();
+            let len = client.read_i32().await.unwrap() as usize;
+            let mut body = vec![0; len - 4];
+            client.read_exact(&mut body).await.unwrap();
+            if tag == b'E' {
+                let code = body
+                    .split(|b| *b == 0)
+                    .find_map(|field| field.strip_prefix(b"C"))
+                    .unwrap();
+                return Err(String::from_utf8(code.to_vec()).unwrap());
+            }
+            assert_eq!(tag, b'R');
+            let (code, data) = body.split_at(4);
+            match i32::from_be_bytes(code.try_into().unwrap()) {
+                0 => return Ok(()),
+                3 => frontend::password_message(password.as_bytes(), &mut buf).unwrap(),
+                5 => {
+                    let salt = data.try_into().unwrap();
+                    let hash = md5_hash(user.as_bytes(), password.as_bytes(), salt);
+                    frontend::password_message(hash.as_bytes(), &mut buf).unwrap();
+                }
+                10 => {
+                    let exchange =
+                        ScramSha256::new(password.as_bytes(), ChannelBinding::unsupported());
+                    frontend::sasl_initial_response("SCRAM-SHA-256", exchange.message(), &mut buf)
+                        .unwrap();
+                    scram = Some(exchange);
+                }
+                11 => {
+                    let exchange = scram.as_mut().unwrap();
+                    exchange.update(data).unwrap();
+                    frontend::sasl_response(exchange.message(), &mut buf).unwrap();
+                }
+                12 => scram.as_mut().unwrap().finish(data).unwrap(),
+                code => panic!("unexpected authentication request {code}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn trust() {
+        assert_eq!(authenticate(AuthMethod::Trust, "bob", "").await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn password_authentication() {
+        for method in [
+            AuthMethod::Password,
+            AuthMethod::Md5,
+            AuthMethod::ScramSha256,
+        ] {
+            assert_eq!(authenticate(method, "alice", "secret").await, Ok(()));
+            let invalid_password = Err("28P01".to_owned());
+            assert_eq!(
+                authenticate(method, "alice", "wrong").await,
+                invalid_password
+            );
+            assert_eq!(
+                authenticate(method, "bob", "secret").await,
+                invalid_password
+            );
+        }
+    }
+
+    #[test]
+    fn load_credentials() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "# users\nalice:secret\n\nbob:pass:word").unwrap();
+        let mut credentials = Credentials::default();
+        credentials.load(file.path()).unwrap();
+        assert!(credentials.check("alice", "secret"));
+        assert!(credentials.check("bob", "pass:word"));
+        assert!(!credentials.check("alice", "secre"));
+        assert!(!credentials.check("carol", "secret"));
+
+        assert!(credentials.add("nopassword").is_err());
+        assert!(credentials.add(":password").is_err());
+    }
+}
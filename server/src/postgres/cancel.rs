@@ -0,0 +1,72 @@
+//! Cancellation of running queries.
+//!
+//! Each connection is given a process id and a secret key, sent to the client in a
+//! BackendKeyData message. To cancel the query a connection is running, the client opens a new
+//! connection and sends both in a CancelRequest, instead of a startup message.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rand::Rng;
+
+use crate::database::service::InterruptHandle;
+
+#[derive(Default)]
+pub struct CancelRegistry {
+    /// The secret key of each connection, and the handle interrupting its queries, by process id.
+    sessions: Mutex<HashMap<i32, (i32, InterruptHandle)>>,
+    last_pid: AtomicI32,
+}
+
+impl CancelRegistry {
+    /// Registers a connection, whose queries are interrupted by `interrupt`. The connection can
+    /// be cancelled until the returned key is dropped.
+    pub fn register(self: &Arc<Self>, interrupt: InterruptHandle) -> CancelKey {
+        let secret = rand::thread_rng().gen();
+        let mut sessions = self.sessions.lock().unwrap();
+        // Process ids are only reused once they wrap around, if they are free by then.
+        let pid = loop {
+            let pid = self
+                .last_pid
+                .fetch_add(1, Ordering::Relaxed)
+                .wrapping_add(1);
+            if pid > 0 && !sessions.contains_key(&pid) {
+                break pid;
+            }
+        };
+        sessions.insert(pid, (secret, interrupt));
+
+        CancelKey {
+            pid,
+            secret,
+            registry: self.clone(),
+        }
+    }
+
+    /// Interrupts the query of the connection with process id `pid`, if `secret` is its key.
+    /// Returns whether the key matched a connection.
+    pub fn cancel(&self, pid: i32, secret: i32) -> bool {
+        let interrupt = match self.sessions.lock().unwrap().get(&pid) {
+            Some((key, interrupt)) if *key == secret => interrupt.clone(),
+            _ => return false,
+        };
+        interrupt();
+
+        true
+    }
+}
+
+/// The process id and secret key of a registered connection. The connection is unregistered on
+/// drop.
+pub struct CancelKey {
+    pub pid: i32,
+    pub secret: i32,
+    registry: Arc<CancelRegistry>,
+}
+
+impl Drop for CancelKey {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.pid);
+    }
+}
@@ -5,25 +5,49 @@ use pgwire::error::{ErrorInfo, PgWireError};
 use crate::query::{ErrorCode, QueryError};
 
 pub mod authenticator;
+mod cancel;
+mod params;
 mod proto;
 pub mod service;
 
+/// SQLSTATE codes sent to clients, so that they can tell errors apart.
+const INTERNAL_ERROR: &str = "XX000";
+const INTEGRITY_CONSTRAINT_VIOLATION: &str = "23000";
+const NOT_NULL_VIOLATION: &str = "23502";
+const FOREIGN_KEY_VIOLATION: &str = "23503";
+const UNIQUE_VIOLATION: &str = "23505";
+const CHECK_VIOLATION: &str = "23514";
+const LOCK_NOT_AVAILABLE: &str = "55P03";
+const IDLE_IN_TRANSACTION_SESSION_TIMEOUT: &str = "25P03";
+const OBJECT_NOT_IN_PREREQUISITE_STATE: &str = "55000";
+const FEATURE_NOT_SUPPORTED: &str = "0A000";
+const INVALID_TEXT_REPRESENTATION: &str = "22P02";
+const INVALID_BINARY_REPRESENTATION: &str = "22P03";
+const PROTOCOL_VIOLATION: &str = "08P01";
+const INVALID_CURSOR_NAME: &str = "34000";
+const INVALID_SQL_STATEMENT_NAME: &str = "26000";
+const QUERY_CANCELED: &str = "57014";
+
+fn user_error(sqlstate: &str, msg: String) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        sqlstate.to_owned(),
+        msg,
+    )))
+}
+
 impl From<QueryError> for PgWireError {
     fn from(other: QueryError) -> Self {
         match other.code {
-            ErrorCode::SQLError => PgWireError::UserError(Box::new(ErrorInfo::new(
-                "ERROR".to_owned(),
-                "XX000".to_owned(),
-                other.msg,
-            ))),
-            ErrorCode::TxBusy => {
-                PgWireError::IoError(io::Error::new(io::ErrorKind::WouldBlock, other.msg))
-            }
-            ErrorCode::TxTimeout => PgWireError::UserError(Box::new(ErrorInfo::new(
-                "ERROR".to_owned(),
-                "XX000".to_owned(),
-                other.msg,
-            ))),
+            ErrorCode::SQLError => user_error(INTERNAL_ERROR, other.msg),
+            ErrorCode::ConstraintViolation => user_error(INTEGRITY_CONSTRAINT_VIOLATION, other.msg),
+            ErrorCode::UniqueViolation => user_error(UNIQUE_VIOLATION, other.msg),
+            ErrorCode::ForeignKeyViolation => user_error(FOREIGN_KEY_VIOLATION, other.msg),
+            ErrorCode::NotNullViolation => user_error(NOT_NULL_VIOLATION, other.msg),
+            ErrorCode::CheckViolation => user_error(CHECK_VIOLATION, other.msg),
+            ErrorCode::TxBusy => user_error(LOCK_NOT_AVAILABLE, other.msg),
+            ErrorCode::TxTimeout => user_error(IDLE_IN_TRANSACTION_SESSION_TIMEOUT, other.msg),
+            ErrorCode::QueryCanceled => user_error(QUERY_CANCELED, other.msg),
             ErrorCode::Internal => {
                 PgWireError::IoError(io::Error::new(io::ErrorKind::Other, other.msg))
             }
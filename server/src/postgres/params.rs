@@ -0,0 +1,433 @@
+//! Decoding of the parameters bound to a portal, in the text or binary format of their type.
+
+use std::fmt::Write;
+
+use pgwire::api::portal::Portal;
+use pgwire::api::Type;
+use pgwire::error::PgWireResult;
+
+use crate::query::Value;
+
+use super::{
+    user_error, FEATURE_NOT_SUPPORTED, INVALID_BINARY_REPRESENTATION, INVALID_TEXT_REPRESENTATION,
+};
+
+/// Microseconds in a day.
+const USECS_PER_DAY: i64 = 86_400_000_000;
+/// Days from the Unix epoch to the PostgreSQL epoch, 2000-01-01.
+const POSTGRES_EPOCH_DAYS: i64 = 10_957;
+
+/// Decodes the parameters bound to `portal`. Parameters of an unspecified type are bound as text.
+pub fn decode_params(portal: &Portal) -> PgWireResult<Vec<Value>> {
+    let mut params = Vec::with_capacity(portal.parameter_len());
+    for (i, data) in portal.parameters().iter().enumerate() {
+        let ty = portal.parameter_types().get(i).unwrap_or(&Type::UNKNOWN);
+        let binary = portal.parameter_format().is_binary(i);
+        let value = match data {
+            None => Value::Null,
+            Some(data) if binary => decode_binary(data, ty).map_err(|e| {
+                let sqlstate = match e {
+                    DecodeError::Unsupported => FEATURE_NOT_SUPPORTED,
+                    DecodeError::Invalid => INVALID_BINARY_REPRESENTATION,
+                };
+                user_error(sqlstate, format!("{} for parameter ${}", e.msg(ty), i + 1))
+            })?,
+            Some(data) => decode_text(data, ty).map_err(|e| {
+                user_error(
+                    INVALID_TEXT_REPRESENTATION,
+                    format!("{} for parameter ${}", e.msg(ty), i + 1),
+                )
+            })?,
+        };
+        params.push(value);
+    }
+
+    Ok(params)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DecodeError {
+    /// The value can't be decoded in this format for its type.
+    Unsupported,
+    /// The value is malformed.
+    Invalid,
+}
+
+impl DecodeError {
+    fn msg(self, ty: &Type) -> String {
+        match self {
+            DecodeError::Unsupported => format!("binary format of type {ty} is not supported"),
+            DecodeError::Invalid => format!("invalid value of type {ty}"),
+        }
+    }
+}
+
+fn is_int(ty: &Type) -> bool {
+    ty == &Type::INT2 || ty == &Type::INT4 || ty == &Type::INT8 || ty == &Type::OID
+}
+
+fn is_float(ty: &Type) -> bool {
+    ty == &Type::FLOAT4 || ty == &Type::FLOAT8
+}
+
+/// Types whose values are text in both formats.
+fn is_text(ty: &Type) -> bool {
+    ty == &Type::TEXT
+        || ty == &Type::VARCHAR
+        || ty == &Type::BPCHAR
+        || ty == &Type::NAME
+        || ty == &Type::UNKNOWN
+        || ty == &Type::JSON
+        || ty == &Type::XML
+}
+
+fn decode_text(data: &[u8], ty: &Type) -> Result<Value, DecodeError> {
+    let text = std::str::from_utf8(data).map_err(|_| DecodeError::Invalid)?;
+    let value = if ty == &Type::BOOL {
+        match text.trim().to_lowercase().as_str() {
+            "t" | "true" | "y" | "yes" | "on" | "1" => Value::Integer(1),
+            "f" | "false" | "n" | "no" | "off" | "0" => Value::Integer(0),
+            _ => return Err(DecodeError::Invalid),
+        }
+    } else if is_int(ty) {
+        Value::Integer(text.trim().parse().map_err(|_| DecodeError::Invalid)?)
+    } else if is_float(ty) {
+        Value::Real(parse_float(text.trim()).ok_or(DecodeError::Invalid)?)
+    } else if ty == &Type::NUMERIC {
+        numeric_value(text.trim()).ok_or(DecodeError::Invalid)?
+    } else if ty == &Type::BYTEA {
+        Value::Blob(decode_bytea(text).ok_or(DecodeError::Invalid)?)
+    } else {
+        // Dates, times and any other type are bound as they are written, which is how SQLite
+        // stores them.
+        Value::Text(text.to_owned())
+    };
+
+    Ok(value)
+}
+
+fn decode_binary(data: &[u8], ty: &Type) -> Result<Value, DecodeError> {
+    let value = if ty == &Type::BOOL {
+        match data {
+            [b] => Value::Integer(i64::from(*b != 0)),
+            _ => return Err(DecodeError::Invalid),
+        }
+    } else if ty == &Type::INT2 {
+        Value::Integer(i16::from_be_bytes(be_bytes(data)?).into())
+    } else if ty == &Type::INT4 {
+        Value::Integer(i32::from_be_bytes(be_bytes(data)?).into())
+    } else if ty == &Type::OID {
+        Value::Integer(u32::from_be_bytes(be_bytes(data)?).into())
+    } else if ty == &Type::INT8 {
+        Value::Integer(i64::from_be_bytes(be_bytes(data)?))
+    } else if ty == &Type::FLOAT4 {
+        Value::Real(f32::from_be_bytes(be_bytes(data)?).into())
+    } else if ty == &Type::FLOAT8 {
+        Value::Real(f64::from_be_bytes(be_bytes(data)?))
+    } else if ty == &Type::NUMERIC {
+        let text = decode_numeric(data).ok_or(DecodeError::Invalid)?;
+        numeric_value(&text).ok_or(DecodeError::Invalid)?
+    } else if ty == &Type::BYTEA {
+        Value::Blob(data.to_vec())
+    } else if is_text(ty) {
+        Value::Text(utf8(data)?)
+    } else if ty == &Type::JSONB {
+        // The text of the document follows a version number.
+        match data.split_first() {
+            Some((1, json)) => Value::Text(utf8(json)?),
+            _ => return Err(DecodeError::Invalid),
+        }
+    } else if ty == &Type::DATE {
+        let days = i32::from_be_bytes(be_bytes(data)?);
+        Value::Text(match days {
+            i32::MAX => "infinity".to_owned(),
+            i32::MIN => "-infinity".to_owned(),
+            days => format_date(days as i64, None),
+        })
+    } else if ty == &Type::TIME {
+        Value::Text(format_time(i64::from_be_bytes(be_bytes(data)?)))
+    } else if ty == &Type::TIMESTAMP || ty == &Type::TIMESTAMPTZ {
+        let usecs = i64::from_be_bytes(be_bytes(data)?);
+        Value::Text(match usecs {
+            i64::MAX => "infinity".to_owned(),
+            i64::MIN => "-infinity".to_owned(),
+            usecs => {
+                let days = usecs.div_euclid(USECS_PER_DAY);
+                let mut time = format_time(usecs.rem_euclid(USECS_PER_DAY));
+                // Timestamps with a time zone are sent in UTC.
+                if ty == &Type::TIMESTAMPTZ {
+                    time.push_str("+00:00");
+                }
+                format_date(days, Some(&time))
+            }
+        })
+    } else if ty == &Type::UUID {
+        let hex = hex::encode(<[u8; 16]>::try_from(data).map_err(|_| DecodeError::Invalid)?);
+        Value::Text(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        ))
+    } else {
+        return Err(DecodeError::Unsupported);
+    };
+
+    Ok(value)
+}
+
+fn be_bytes<const N: usize>(data: &[u8]) -> Result<[u8; N], DecodeError> {
+    data.try_into().map_err(|_| DecodeError::Invalid)
+}
+
+fn utf8(data: &[u8]) -> Result<String, DecodeError> {
+    String::from_utf8(data.to_vec()).map_err(|_| DecodeError::Invalid)
+}
+
+/// Parses a float, as written by PostgreSQL, including its special values.
+fn parse_float(text: &str) -> Option<f64> {
+    match text.to_lowercase().as_str() {
+        "infinity" | "inf" | "+infinity" | "+inf" => Some(f64::INFINITY),
+        "-infinity" | "-inf" => Some(f64::NEG_INFINITY),
+        "nan" => Some(f64::NAN),
+        text => text.parse().ok(),
+    }
+}
+
+/// Numbers without a fractional part are bound as integers, as long as they fit, and as reals
+/// otherwise.
+fn numeric_value(text: &str) -> Option<Value> {
+    match text.parse() {
+        Ok(i) => Some(Value::Integer(i)),
+        Err(_) => parse_float(text).map(Value::Real),
+    }
+}
+
+/// Decodes the text format of BYTEA: either `\x` followed by hex digits, or the escape format,
+/// where backslashes start an octal escape.
+fn decode_bytea(text: &str) -> Option<Vec<u8>> {
+    if let Some(hex) = text.strip_prefix("\\x") {
+        return hex::decode(hex).ok();
+    }
+
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b != b'\\' {
+            bytes.push(b);
+            rest = tail;
+        } else if let Some(tail) = tail.strip_prefix(b"\\") {
+            bytes.push(b'\\');
+            rest = tail;
+        } else {
+            let digits = std::str::from_utf8(tail.get(..3)?).ok()?;
+            bytes.push(u8::from_str_radix(digits, 8).ok()?);
+            rest = &tail[3..];
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Decodes the binary format of NUMERIC into its decimal representation. The value is made of
+/// base 10000 digits, along with the weight of the first digit, the sign, and the number of
+/// decimal digits.
+fn decode_numeric(data: &[u8]) -> Option<String> {
+    let word = |i: usize| {
+        Some(u16::from_be_bytes(
+            data.get(2 * i..2 * i + 2)?.try_into().ok()?,
+        ))
+    };
+    let ndigits = word(0)? as usize;
+    let weight = word(1)? as i16 as i64;
+    let sign = word(2)?;
+    let dscale = word(3)? as i64;
+    let digits = (0..ndigits)
+        .map(|i| word(4 + i))
+        .collect::<Option<Vec<_>>>()?;
+    // The digit of weight `w`, counted in base 10000 digits from the decimal point.
+    let digit = |w: i64| {
+        let i = weight - w;
+        if i < 0 {
+            0
+        } else {
+            digits.get(i as usize).copied().unwrap_or(0)
+        }
+    };
+
+    let mut text = match sign {
+        0x0000 => String::new(),
+        0x4000 => "-".to_owned(),
+        0xC000 => return Some("NaN".to_owned()),
+        0xD000 => return Some("Infinity".to_owned()),
+        0xF000 => return Some("-Infinity".to_owned()),
+        _ => return None,
+    };
+    if weight < 0 {
+        text.push('0');
+    } else {
+        write!(text, "{}", digit(weight)).ok()?;
+        for w in (0..weight).rev() {
+            write!(text, "{:04}", digit(w)).ok()?;
+        }
+    }
+    if dscale > 0 {
+        let mut fraction = String::new();
+        for w in 1..=(dscale + 3) / 4 {
+            write!(fraction, "{:04}", digit(-w)).ok()?;
+        }
+        text.push('.');
+        text.push_str(&fraction[..dscale as usize]);
+    }
+
+    Some(text)
+}
+
+/// Formats a number of days since the PostgreSQL epoch as a `YYYY-MM-DD` date, followed by `time`,
+/// if any. Dates before the year 1 are followed by `BC`, as PostgreSQL writes them.
+fn format_date(days: i64, time: Option<&str>) -> String {
+    // Converts days since the Unix epoch to a date of the proleptic Gregorian calendar, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + POSTGRES_EPOCH_DAYS + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    // There is no year 0: the year before 1 AD is 1 BC.
+    let mut date = format!(
+        "{:04}-{month:02}-{day:02}",
+        if year > 0 { year } else { 1 - year }
+    );
+    if let Some(time) = time {
+        date.push(' ');
+        date.push_str(time);
+    }
+    if year <= 0 {
+        date.push_str(" BC");
+    }
+
+    date
+}
+
+/// Formats a number of microseconds since midnight as a `HH:MM:SS` time, with a fractional part
+/// if needed.
+fn format_time(usecs: i64) -> String {
+    let secs = usecs / 1_000_000;
+    let mut time = format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+    let fraction = usecs % 1_000_000;
+    if fraction != 0 {
+        let fraction = format!(".{fraction:06}");
+        time.push_str(fraction.trim_end_matches('0'));
+    }
+
+    time
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Encodes NUMERIC words, a header followed by base 10000 digits, in the binary format.
+    fn numeric(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn decode_numeric_values() {
+        let decode = |words: &[u16]| decode_numeric(&numeric(words)).unwrap();
+        assert_eq!(decode(&[0, 0, 0, 0]), "0");
+        assert_eq!(decode(&[1, 0, 0, 0, 42]), "42");
+        assert_eq!(decode(&[1, 1, 0, 0, 1]), "10000");
+        assert_eq!(decode(&[3, 2, 0, 0, 1, 2345, 6789]), "123456789");
+        assert_eq!(decode(&[2, 0, 0x4000, 2, 1, 5000]), "-1.50");
+        assert_eq!(decode(&[1, -1i16 as u16, 0, 2, 100]), "0.01");
+        assert_eq!(decode(&[1, -2i16 as u16, 0, 8, 1]), "0.00000001");
+        assert_eq!(decode(&[1, 0, 0, 3, 7]), "7.000");
+    }
+
+    #[test]
+    fn decode_numeric_special_values() {
+        let decode = |words: &[u16]| decode_numeric(&numeric(words)).unwrap();
+        assert_eq!(decode(&[0, 0, 0xC000, 0]), "NaN");
+        assert_eq!(decode(&[0, 0, 0xD000, 0]), "Infinity");
+        assert_eq!(decode(&[0, 0, 0xF000, 0]), "-Infinity");
+    }
+
+    #[test]
+    fn decode_numeric_invalid() {
+        // Truncated header, missing digit and unknown sign.
+        assert!(decode_numeric(&numeric(&[0, 0, 0])).is_none());
+        assert!(decode_numeric(&numeric(&[2, 0, 0, 0, 1])).is_none());
+        assert!(decode_numeric(&numeric(&[0, 0, 0x1234, 0])).is_none());
+        assert!(decode_numeric(&[]).is_none());
+    }
+
+    #[test]
+    fn format_dates() {
+        assert_eq!(format_date(0, None), "2000-01-01");
+        assert_eq!(format_date(-1, None), "1999-12-31");
+        assert_eq!(format_date(-10_957, None), "1970-01-01");
+        assert_eq!(format_date(59, None), "2000-02-29");
+        assert_eq!(format_date(8_766, Some("12:30:00")), "2024-01-01 12:30:00");
+    }
+
+    #[test]
+    fn format_dates_before_christ() {
+        // 0001-01-01 is 730119 days before the PostgreSQL epoch.
+        assert_eq!(format_date(-730_119, None), "0001-01-01");
+        assert_eq!(format_date(-730_120, None), "0001-12-31 BC");
+        assert_eq!(
+            format_date(-730_120, Some("23:00:00+00:00")),
+            "0001-12-31 23:00:00+00:00 BC"
+        );
+        assert_eq!(format_date(-730_120 - 366, None), "0002-12-31 BC");
+    }
+
+    #[test]
+    fn decode_infinite_dates() {
+        let decode = |days: i32| match decode_binary(&days.to_be_bytes(), &Type::DATE) {
+            Ok(Value::Text(date)) => date,
+            value => panic!("unexpected {value:?}"),
+        };
+        assert_eq!(decode(i32::MAX), "infinity");
+        assert_eq!(decode(i32::MIN), "-infinity");
+        assert_eq!(decode(0), "2000-01-01");
+    }
+
+    #[test]
+    fn decode_timestamps() {
+        let decode = |usecs: i64, ty| match decode_binary(&usecs.to_be_bytes(), ty) {
+            Ok(Value::Text(timestamp)) => timestamp,
+            value => panic!("unexpected {value:?}"),
+        };
+        assert_eq!(decode(1_500_000, &Type::TIMESTAMP), "2000-01-01 00:00:01.5");
+        assert_eq!(
+            decode(-1, &Type::TIMESTAMPTZ),
+            "1999-12-31 23:59:59.999999+00:00"
+        );
+        assert_eq!(decode(i64::MAX, &Type::TIMESTAMP), "infinity");
+        assert_eq!(decode(i64::MIN, &Type::TIMESTAMP), "-infinity");
+    }
+
+    #[test]
+    fn decode_bytea_formats() {
+        assert_eq!(decode_bytea("").unwrap(), b"");
+        assert_eq!(decode_bytea("\\x").unwrap(), b"");
+        assert_eq!(
+            decode_bytea("\\xdeadBEEF").unwrap(),
+            [0xde, 0xad, 0xbe, 0xef]
+        );
+        assert_eq!(decode_bytea("abc").unwrap(), b"abc");
+        assert_eq!(decode_bytea("a\\\\b\\000\\377").unwrap(), b"a\\b\0\xff");
+        assert!(decode_bytea("\\xabc").is_none());
+        assert!(decode_bytea("\\12").is_none());
+        assert!(decode_bytea("\\999").is_none());
+    }
+}
@@ -1,29 +1,31 @@
 use std::borrow::Cow;
 use std::future::poll_fn;
+use std::time::Duration;
 
 use bytes::Buf;
 use futures::{io, SinkExt};
-use once_cell::sync::Lazy;
 use pgwire::api::portal::Portal;
-use pgwire::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
+use pgwire::api::query::ExtendedQueryHandler;
 use pgwire::api::results::Response;
-use pgwire::api::{ClientInfo, Type};
+use pgwire::api::ClientInfo;
 use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
 use pgwire::messages::response::{ReadyForQuery, READY_STATUS_IDLE};
 use pgwire::messages::startup::SslRequest;
 use pgwire::messages::PgWireBackendMessage;
 use pgwire::tokio::PgWireMessageServerCodec;
-use regex::Regex;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::Mutex;
 use tokio_util::codec::Framed;
 use tower::Service;
 
-use crate::query::{Query, QueryError, QueryResponse, Value};
+use crate::query::{
+    Description, ErrorCode, Params, Query, QueryError, QueryResponse, QueryResult, Value,
+};
+use crate::query_analysis::rewrite_placeholders;
 use crate::server::AsyncPeekable;
 
-// TODO: more robust parsing
-static VAR_REPLACE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\$(?P<digits>\d*)"#).unwrap());
+use super::params::decode_params;
+use super::{user_error, PROTOCOL_VIOLATION};
 
 /// This is a dummy handler, it's sole role is to send the response back to the client.
 pub struct QueryHandler<'a, S>(Mutex<&'a mut S>);
@@ -33,44 +35,98 @@ impl<'a, S> QueryHandler<'a, S> {
         Self(Mutex::new(s))
     }
 
-    async fn handle_query(&self, query: Cow<'_, str>, params: Vec<Value>) -> PgWireResult<Response>
+    /// Runs `query`, and returns the result of each statement.
+    pub async fn execute(&self, query: &str, params: Vec<Value>) -> PgWireResult<Vec<QueryResult>>
     where
-        S: Service<Query, Response = QueryResponse, Error = QueryError> + Sync + Send,
+        S: Service<Query, Response = Vec<QueryResult>, Error = QueryError> + Sync + Send,
         S::Future: Send,
     {
-        let query = Query::SimpleQuery(query.into_owned(), params);
+        let request = Query::SimpleQuery(query.to_string(), Params::Positional(params));
         let mut s = self.0.lock().await;
         //TODO: handle poll_ready error
         poll_fn(|cx| s.poll_ready(cx)).await.unwrap();
-        match s.call(query).await {
-            Ok(resp) => match resp {
-                QueryResponse::ResultSet(set) => Ok(set.into()),
-            },
-            Err(e) => Err(e.into()),
-        }
+        Ok(s.call(request).await?)
     }
-}
 
-#[async_trait::async_trait]
-impl<'a, S> SimpleQueryHandler for QueryHandler<'a, S>
-where
-    S: Service<Query, Response = QueryResponse, Error = QueryError> + Sync + Send,
-    S::Future: Send,
-{
-    async fn do_query<C>(&self, _client: &C, query: &str) -> PgWireResult<Vec<Response>>
+    /// Runs the statement of `portal`, and returns its result, if the statement isn't empty.
+    pub async fn execute_portal(&self, portal: &Portal) -> PgWireResult<Option<QueryResult>>
     where
-        C: ClientInfo + Unpin + Send + Sync,
+        S: Service<Query, Response = Vec<QueryResult>, Error = QueryError> + Sync + Send,
+        S::Future: Send,
     {
-        self.handle_query(Cow::Borrowed(query), Vec::new())
-            .await
-            .map(|r| vec![r])
+        // SQLite tells how many parameters the statement takes, whatever its placeholders.
+        let param_count = self.describe(portal.statement()).await?.param_count;
+        let (statement, params) = portal_query(portal, param_count)?;
+        // The extended query protocol only allows a single statement.
+        let mut results = self.execute(&statement, params).await?;
+        Ok(results.pop())
+    }
+
+    /// Prepares `statement` without executing it, and describes it.
+    pub async fn describe(&self, statement: &str) -> PgWireResult<Description>
+    where
+        S: Service<Query, Response = Vec<QueryResult>, Error = QueryError> + Sync + Send,
+        S::Future: Send,
+    {
+        let (statement, _) = rewrite_placeholders(statement);
+        let mut s = self.0.lock().await;
+        //TODO: handle poll_ready error
+        poll_fn(|cx| s.poll_ready(cx)).await.unwrap();
+        let mut results = s.call(Query::Describe(statement.into_owned())).await?;
+        match results.pop() {
+            Some(Ok(QueryResponse::Description(description))) => Ok(description),
+            Some(Err(e)) => Err(e.into()),
+            _ => {
+                Err(QueryError::new(ErrorCode::Internal, "the statement was not described").into())
+            }
+        }
+    }
+}
+
+/// Returns the statement of `portal`, with its parameters renamed for SQLite, and the values bound
+/// to them. The statement takes `param_count` parameters.
+fn portal_query(portal: &Portal, param_count: usize) -> PgWireResult<(Cow<'_, str>, Vec<Value>)> {
+    let (statement, _) = rewrite_placeholders(portal.statement());
+    let supplied = portal.parameter_len();
+    if param_count != supplied {
+        return Err(user_error(
+            PROTOCOL_VIOLATION,
+            format!(
+                "bind message supplies {supplied} parameters, \
+                but prepared statement requires {param_count}"
+            ),
+        ));
+    }
+    let params = decode_params(portal)?;
+
+    Ok((statement, params))
+}
+
+/// Builds the response to `stmt`. `binary` tells whether the client asked for the binary format
+/// for the column at an index.
+pub fn into_response(
+    stmt: &str,
+    result: QueryResult,
+    binary: impl Fn(usize) -> bool,
+) -> PgWireResult<Response> {
+    match result {
+        Ok(QueryResponse::ResultSet(set)) => Ok(set.into_response(stmt, binary)),
+        Ok(QueryResponse::Description(_)) => {
+            unreachable!("statements are described by the connection")
+        }
+        // The error is reported in place of the statement that failed, after the results of the
+        // statements before it. Connection level errors abort the whole query.
+        Err(e) => match PgWireError::from(e) {
+            PgWireError::UserError(info) => Ok(Response::Error(info)),
+            e => Err(e),
+        },
     }
 }
 
 #[async_trait::async_trait]
 impl<'a, S> ExtendedQueryHandler for QueryHandler<'a, S>
 where
-    S: Service<Query, Response = QueryResponse, Error = QueryError> + Sync + Send,
+    S: Service<Query, Response = Vec<QueryResult>, Error = QueryError> + Sync + Send,
     S::Future: Send,
 {
     async fn do_query<C>(
@@ -82,31 +138,14 @@ where
     where
         C: ClientInfo + Unpin + Send + Sync,
     {
-        debug_assert_eq!(portal.parameter_types().len(), portal.parameter_len());
-        let statement = VAR_REPLACE_RE.replace(portal.statement(), "?$digits");
-        let mut params = Vec::with_capacity(portal.parameter_len());
-        for (val, ty) in portal.parameters().iter().zip(portal.parameter_types()) {
-            let value = if val.is_none() {
-                Value::Null
-            } else if ty == &Type::VARCHAR {
-                let s = String::from_utf8(val.as_ref().unwrap().to_vec()).unwrap();
-                Value::Text(s)
-            } else if ty == &Type::INT8 {
-                let v = i64::from_be_bytes((val.as_ref().unwrap()[..8]).try_into().unwrap());
-                Value::Integer(v)
-            } else if ty == &Type::BYTEA {
-                Value::Blob(val.as_ref().unwrap().to_vec())
-            } else if ty == &Type::FLOAT8 {
-                let val = f64::from_be_bytes(val.as_ref().unwrap()[..8].try_into().unwrap());
-                Value::Real(val)
-            } else {
-                unimplemented!("unsupported type")
-            };
-
-            params.push(value);
+        // Portals are run by the connection, which names their completion tag after the command
+        // reported by the database, and suspends them once their row limit is reached.
+        match self.execute_portal(portal).await? {
+            Some(result) => into_response(portal.statement(), result, |i| {
+                portal.result_column_format().is_binary(i)
+            }),
+            None => Ok(Response::EmptyQuery),
         }
-
-        self.handle_query(statement, params).await
     }
 }
 
@@ -143,6 +182,17 @@ where
                 .await?;
             socket.flush().await?;
         }
+        PgWireError::InvalidPassword(user) => {
+            let error_info = ErrorInfo::new(
+                "FATAL".to_owned(),
+                "28P01".to_owned(),
+                format!("password authentication failed for user \"{user}\""),
+            );
+            socket
+                .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+                .await?;
+            socket.close().await?;
+        }
         _ => {
             // Internal error
             let error_info =
@@ -157,6 +207,42 @@ where
     Ok(())
 }
 
+/// Length of a CancelRequest, and the code it starts with in place of a protocol version.
+const CANCEL_REQUEST_SIZE: usize = 16;
+const CANCEL_REQUEST_CODE: i32 = 80877102;
+/// Delay before peeking again at a socket that holds only part of the first message header.
+const PEEK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Reads a CancelRequest, if the client opened the connection to send one, and returns the
+/// process id and secret key it carries. Cancel requests are sent in the clear, before any TLS
+/// negotiation.
+pub async fn peek_for_cancel_request<I>(socket: &mut I) -> Result<Option<(i32, i32)>, io::Error>
+where
+    I: AsyncRead + AsyncPeekable + Unpin,
+{
+    let mut buf = [0u8; CANCEL_REQUEST_SIZE];
+    loop {
+        let size = socket.peek(&mut buf[..8]).await?;
+        if size == 0 {
+            return Ok(None);
+        }
+        if size == 8 {
+            break;
+        }
+        // The socket stays readable until the bytes are read: peeking again right away would spin
+        // until the rest of the header arrives.
+        tokio::time::sleep(PEEK_RETRY_DELAY).await;
+    }
+    let mut header = &buf[..8];
+    if header.get_i32() != CANCEL_REQUEST_SIZE as i32 || header.get_i32() != CANCEL_REQUEST_CODE {
+        return Ok(None);
+    }
+
+    socket.read_exact(&mut buf).await?;
+    let mut body = &buf[8..];
+    Ok(Some((body.get_i32(), body.get_i32())))
+}
+
 pub async fn peek_for_sslrequest<I>(socket: &mut I, ssl_supported: bool) -> Result<bool, io::Error>
 where
     I: AsyncWrite + AsyncRead + AsyncPeekable + Unpin,
@@ -190,3 +276,97 @@ where
 
     Ok(ssl)
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use postgres_protocol::message::frontend;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::server::tls::test::{acceptor, connect};
+    use crate::server::NetStream;
+
+    /// Returns both ends of a TCP connection.
+    async fn connection() -> (TcpStream, NetStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        (client, NetStream::Tcp { stream })
+    }
+
+    #[tokio::test]
+    async fn cancel_request() {
+        let (mut client, mut server) = connection().await;
+        let mut buf = BytesMut::new();
+        frontend::cancel_request(12, 34, &mut buf);
+
+        // the request arrives in pieces.
+        let peek = tokio::spawn(async move {
+            let request = peek_for_cancel_request(&mut server).await.unwrap();
+            (request, server)
+        });
+        client.write_all(&buf[..4]).await.unwrap();
+        tokio::time::sleep(PEEK_RETRY_DELAY * 3).await;
+        client.write_all(&buf[4..]).await.unwrap();
+
+        let (request, mut server) = peek.await.unwrap();
+        assert_eq!(request, Some((12, 34)));
+        // the request was consumed.
+        client.shutdown().await.unwrap();
+        assert_eq!(server.read(&mut [0; 16]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn not_a_cancel_request() {
+        let (mut client, mut server) = connection().await;
+        let mut buf = BytesMut::new();
+        frontend::startup_message([("user", "test")], &mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+
+        assert_eq!(peek_for_cancel_request(&mut server).await.unwrap(), None);
+        // the startup message is left for the connection to read.
+        let mut read = vec![0; buf.len()];
+        server.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, buf);
+    }
+
+    #[tokio::test]
+    async fn ssl_request() {
+        let (mut client, mut server) = connection().await;
+        let mut buf = BytesMut::new();
+        frontend::ssl_request(&mut buf);
+        client.write_all(&buf).await.unwrap();
+
+        assert!(peek_for_sslrequest(&mut server, true).await.unwrap());
+        let mut answer = [0; 1];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(&answer, b"S");
+
+        // The client then negotiates TLS.
+        let acceptor = acceptor();
+        let (server, mut client) = tokio::join!(server.upgrade_tls(&acceptor), connect(client));
+        let mut server = server.unwrap();
+        assert!(server.is_secure());
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn ssl_not_supported() {
+        let (mut client, mut server) = connection().await;
+        let mut buf = BytesMut::new();
+        frontend::ssl_request(&mut buf);
+        client.write_all(&buf).await.unwrap();
+
+        assert!(!peek_for_sslrequest(&mut server, false).await.unwrap());
+        let mut answer = [0; 1];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(&answer, b"N");
+    }
+}
@@ -1,42 +1,120 @@
-use std::future::Future;
+use std::collections::HashMap;
+use std::future::{poll_fn, Future};
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 
-use futures::StreamExt;
-use pgwire::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
-use pgwire::api::PgWireConnectionState;
-use pgwire::error::PgWireError;
+use futures::stream::Peekable;
+use futures::{SinkExt, StreamExt};
+use pgwire::api::query::ExtendedQueryHandler;
+use pgwire::api::store::PortalStore;
+use pgwire::api::{ClientInfo, ClientPortalStore, PgWireConnectionState, Type, DEFAULT_NAME};
+use pgwire::error::{ErrorInfo, PgWireError};
+use pgwire::messages::data::{FieldDescription, NoData, ParameterDescription, RowDescription};
+use pgwire::messages::extendedquery::{Describe, Execute, PortalSuspended};
+use pgwire::messages::response::{EmptyQueryResponse, ReadyForQuery, READY_STATUS_IDLE};
+use pgwire::messages::startup::{BackendKeyData, ParameterStatus};
+use pgwire::messages::PgWireBackendMessage;
 use pgwire::tokio::PgWireMessageServerCodec;
 use pgwire::{api::ClientInfoHolder, messages::PgWireFrontendMessage};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::TlsAcceptor;
 use tokio_util::codec::{Decoder, Framed};
+use tokio_util::sync::CancellationToken;
 use tower::MakeService;
 use tower::Service;
 
-use crate::postgres::authenticator::PgAuthenticator;
-use crate::query::{Query, QueryError, QueryResponse};
-use crate::server::AsyncPeekable;
+use crate::database::service::Interrupt;
+use crate::postgres::authenticator::{AuthMethod, Credentials, PgAuthenticator};
+use crate::query::{
+    column_formats, encode_row, Column, ColumnFormat, Command, ErrorCode, Params, Query,
+    QueryError, QueryResponse, QueryResult, ResultSet, RowStream,
+};
+use crate::query_analysis::{split_statements, State, Statements, TXN_TIMEOUT_VAR};
+use crate::server::NetStream;
 
-use super::proto::{peek_for_sslrequest, process_error, QueryHandler};
+use super::cancel::CancelRegistry;
+use super::proto::{peek_for_cancel_request, peek_for_sslrequest, process_error, QueryHandler};
+use super::{
+    user_error, INTERNAL_ERROR, INVALID_CURSOR_NAME, INVALID_SQL_STATEMENT_NAME,
+    OBJECT_NOT_IN_PREREQUISITE_STATE,
+};
+
+/// The parameter status that reports the rowid of the last row inserted by a statement.
+const LAST_INSERT_ROWID: &str = "last_insert_rowid";
+
+/// The rows of a statement, as they are sent to the client.
+struct StatementRows {
+    /// The statement, that names the command in its completion tag.
+    stmt: String,
+    rows: Peekable<RowStream>,
+    columns: Vec<Column>,
+    formats: Vec<ColumnFormat>,
+    /// Number of rows sent so far.
+    sent: usize,
+    command: Command,
+    affected_row_count: u64,
+    last_insert_rowid: Option<i64>,
+}
+
+impl StatementRows {
+    /// `binary` tells whether the client asked for the binary format for the column at an index.
+    fn new(stmt: &str, set: ResultSet, binary: impl Fn(usize) -> bool) -> Self {
+        Self {
+            stmt: stmt.to_owned(),
+            formats: column_formats(&set.columns, binary),
+            rows: set.rows.peekable(),
+            columns: set.columns,
+            sent: 0,
+            command: set.command,
+            affected_row_count: set.affected_row_count,
+            last_insert_rowid: set.last_insert_rowid,
+        }
+    }
+}
+
+/// A portal whose execution was suspended, because the client asked for fewer rows than it
+/// returns.
+enum SuspendedPortal {
+    /// The rows that are left.
+    Rows(StatementRows),
+    /// The portal can't be resumed: another query ran while it was suspended.
+    Closed,
+}
 
 /// Manages a postgres wire connection.
 pub struct PgWireConnection<T, S> {
     socket: Framed<T, PgWireMessageServerCodec>,
-    authenticator: Arc<PgAuthenticator>,
     service: S,
+    /// Cancelled when the server shuts down.
+    shutdown: CancellationToken,
+    /// The suspended portals, by name.
+    suspended: HashMap<String, SuspendedPortal>,
+    /// The transaction of the session, as left by the statements that ran.
+    txn: State,
+    /// Whether a message of the current extended query failed.
+    ignore_till_sync: bool,
 }
 
 impl<T, S> PgWireConnection<T, S>
 where
-    S: Service<Query, Response = QueryResponse, Error = QueryError> + Sync + Send,
+    S: Service<Query, Response = Vec<QueryResult>, Error = QueryError> + Sync + Send,
     T: AsyncRead + AsyncWrite + Unpin + Send + Sync,
     S::Future: Send,
 {
     async fn run(&mut self) {
         loop {
-            let result = match self.socket.next().await {
+            // A query that is being processed is allowed to complete, but we stop waiting for new
+            // ones on shutdown.
+            let msg = tokio::select! {
+                msg = self.socket.next() => msg,
+                _ = self.shutdown.cancelled() => {
+                    let _ = terminate(&mut self.socket).await;
+                    break;
+                }
+            };
+            let result = match msg {
                 // TODO: handle error correctly
                 Some(Ok(msg)) => self.handle_message(msg).await,
                 Some(Err(error)) => Err(error),
@@ -54,49 +132,370 @@ where
         }
     }
 
+    /// Completes the startup of the authenticated client, once its session is ready: sends the
+    /// process id and secret key it cancels its queries with, and applies its startup parameters.
+    async fn start(&mut self, (pid, secret): (i32, i32)) -> Result<(), PgWireError> {
+        self.socket
+            .feed(PgWireBackendMessage::BackendKeyData(BackendKeyData::new(
+                pid, secret,
+            )))
+            .await?;
+        self.socket
+            .send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                READY_STATUS_IDLE,
+            )))
+            .await?;
+        self.apply_startup_parameters().await
+    }
+
     async fn handle_message(&mut self, msg: PgWireFrontendMessage) -> Result<bool, PgWireError> {
-        match self.socket.codec().client_info().state() {
-            PgWireConnectionState::AwaitingStartup
-            | PgWireConnectionState::AuthenticationInProgress => {
-                self.authenticator
-                    .authenticate(&mut self.socket, msg)
-                    .await?;
+        match msg {
+            PgWireFrontendMessage::Query(q) => self.simple_query(q.query()).await?,
+            PgWireFrontendMessage::Sync(s) => {
+                self.ignore_till_sync = false;
+                // Sync ends the implicit transaction the portals run in, which would otherwise
+                // stay open for as long as their rows aren't read. The portals of an explicit
+                // transaction live until it ends.
+                if !self.in_transaction() {
+                    self.close_suspended_portals(None);
+                }
+                let handler = QueryHandler::new(&mut self.service);
+                handler.on_sync(&mut self.socket, s).await?;
+            }
+            PgWireFrontendMessage::Terminate(_) => return Ok(false),
+            // These messages are handled by the authenticator on startup.
+            PgWireFrontendMessage::Startup(_)
+            | PgWireFrontendMessage::PasswordMessageFamily(_)
+            | PgWireFrontendMessage::Password(_)
+            | PgWireFrontendMessage::SASLInitialResponse(_)
+            | PgWireFrontendMessage::SASLResponse(_) => (),
+            // Once a message of an extended query failed, the rest of the query is skipped.
+            _ if self.ignore_till_sync => (),
+            msg => {
+                if let Err(e) = self.extended_query_message(msg).await {
+                    self.fail_extended_query(e).await?;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Handles a message of an extended query.
+    async fn extended_query_message(
+        &mut self,
+        msg: PgWireFrontendMessage,
+    ) -> Result<(), PgWireError> {
+        match msg {
+            // Portals are run by the connection, that sends their rows and completes them, and
+            // suspends portals whose rows are limited.
+            PgWireFrontendMessage::Execute(e) => self.execute_portal(e).await?,
+            PgWireFrontendMessage::Describe(d) => self.describe(d).await?,
+            PgWireFrontendMessage::Parse(p) => {
+                let handler = QueryHandler::new(&mut self.service);
+                handler.on_parse(&mut self.socket, p).await?;
             }
-            _ => {
+            PgWireFrontendMessage::Bind(b) => {
+                self.suspended.remove(stored_name(b.portal_name()));
                 let handler = QueryHandler::new(&mut self.service);
-                match msg {
-                    PgWireFrontendMessage::Query(q) => {
-                        handler.on_query(&mut self.socket, q).await?;
-                    }
-                    PgWireFrontendMessage::Parse(p) => {
-                        handler.on_parse(&mut self.socket, p).await?;
-                    }
-                    PgWireFrontendMessage::Close(c) => {
-                        handler.on_close(&mut self.socket, c).await?;
+                handler.on_bind(&mut self.socket, b).await?;
+            }
+            PgWireFrontendMessage::Close(c) => {
+                if *c.target_type() == b'P' {
+                    self.suspended.remove(stored_name(c.name()));
+                }
+                let handler = QueryHandler::new(&mut self.service);
+                handler.on_close(&mut self.socket, c).await?;
+            }
+            _ => unreachable!("not a message of an extended query"),
+        }
+        Ok(())
+    }
+
+    /// Reports the error of an extended query to the client, without ReadyForQuery: the messages
+    /// that follow are ignored until the client syncs. Errors that break the connection are
+    /// returned instead.
+    async fn fail_extended_query(&mut self, error: PgWireError) -> Result<(), PgWireError> {
+        let info = match error {
+            PgWireError::UserError(info) => *info,
+            PgWireError::ApiError(e) => {
+                ErrorInfo::new("ERROR".to_owned(), INTERNAL_ERROR.to_owned(), e.to_string())
+            }
+            PgWireError::PortalNotFound(name) => ErrorInfo::new(
+                "ERROR".to_owned(),
+                INVALID_CURSOR_NAME.to_owned(),
+                format!("portal \"{name}\" does not exist"),
+            ),
+            PgWireError::StatementNotFound(name) => ErrorInfo::new(
+                "ERROR".to_owned(),
+                INVALID_SQL_STATEMENT_NAME.to_owned(),
+                format!("prepared statement \"{name}\" does not exist"),
+            ),
+            e => return Err(e),
+        };
+        self.socket
+            .feed(PgWireBackendMessage::ErrorResponse(info.into()))
+            .await?;
+        self.ignore_till_sync = true;
+        Ok(())
+    }
+
+    /// Runs the statements of a simple query, and sends the result of each of them, in the text
+    /// format. Execution stops at the first statement that fails.
+    async fn simple_query(&mut self, query: &str) -> Result<(), PgWireError> {
+        self.close_suspended_portals(None);
+        let handler = QueryHandler::new(&mut self.service);
+        let results = handler.execute(query, Vec::new()).await?;
+        if results.is_empty() {
+            self.socket
+                .feed(PgWireBackendMessage::EmptyQueryResponse(
+                    EmptyQueryResponse::new(),
+                ))
+                .await?;
+        }
+        for (stmt, result) in split_statements(query).into_iter().zip(results) {
+            match result {
+                Ok(QueryResponse::ResultSet(set)) => {
+                    self.track_transaction(stmt);
+                    let mut rows = StatementRows::new(stmt, set, |_| false);
+                    if !rows.columns.is_empty() {
+                        let description = row_description(rows.columns.clone(), &rows.formats);
+                        self.socket.feed(description).await?;
                     }
-                    PgWireFrontendMessage::Bind(b) => {
-                        handler.on_bind(&mut self.socket, b).await?;
+                    self.send_rows(&mut rows, usize::MAX).await?;
+                    self.complete(rows).await?;
+                }
+                Ok(QueryResponse::Description(_)) => {
+                    unreachable!("statements are described by the connection")
+                }
+                // The error is reported in place of the statement that failed, after the results
+                // of the statements before it.
+                Err(e) => {
+                    self.send_error(e).await?;
+                    break;
+                }
+            }
+        }
+
+        self.socket
+            .send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                READY_STATUS_IDLE,
+            )))
+            .await?;
+        Ok(())
+    }
+
+    /// Executes a portal, sending at most the number of rows the client asked for. The portal is
+    /// then suspended until the client executes it again, to read the rest of its rows.
+    async fn execute_portal(&mut self, execute: Execute) -> Result<(), PgWireError> {
+        let name = stored_name(execute.name()).to_owned();
+        let max_rows = match *execute.max_rows() {
+            0 => usize::MAX,
+            n => n as usize,
+        };
+        self.close_suspended_portals(Some(&name));
+
+        let mut rows = match self.suspended.remove(&name) {
+            Some(SuspendedPortal::Rows(rows)) => rows,
+            Some(SuspendedPortal::Closed) => {
+                return Err(user_error(
+                    OBJECT_NOT_IN_PREREQUISITE_STATE,
+                    format!("portal \"{name}\" was closed by another query"),
+                ));
+            }
+            None => {
+                let portal = self
+                    .socket
+                    .portal_store()
+                    .get_portal(&name)
+                    .ok_or_else(|| PgWireError::PortalNotFound(name.clone()))?;
+                let handler = QueryHandler::new(&mut self.service);
+                match handler.execute_portal(&portal).await? {
+                    Some(Ok(QueryResponse::ResultSet(set))) => {
+                        self.track_transaction(portal.statement());
+                        let format = portal.result_column_format();
+                        StatementRows::new(portal.statement(), set, |i| format.is_binary(i))
                     }
-                    PgWireFrontendMessage::Describe(d) => {
-                        handler.on_describe(&mut self.socket, d).await?;
+                    Some(Ok(QueryResponse::Description(_))) => {
+                        unreachable!("statements are described by the connection")
                     }
-                    PgWireFrontendMessage::Execute(e) => {
-                        handler.on_execute(&mut self.socket, e).await?;
+                    Some(Err(e)) => {
+                        self.send_error(e).await?;
+                        self.ignore_till_sync = true;
+                        return Ok(());
                     }
-                    PgWireFrontendMessage::Sync(s) => {
-                        handler.on_sync(&mut self.socket, s).await?;
+                    None => {
+                        self.socket
+                            .feed(PgWireBackendMessage::EmptyQueryResponse(
+                                EmptyQueryResponse::new(),
+                            ))
+                            .await?;
+                        return Ok(());
                     }
-                    PgWireFrontendMessage::Terminate(_) => return Ok(false),
-                    // These messages are handled by the connection service on startup.
-                    PgWireFrontendMessage::Startup(_)
-                    | PgWireFrontendMessage::PasswordMessageFamily(_)
-                    | PgWireFrontendMessage::Password(_)
-                    | PgWireFrontendMessage::SASLInitialResponse(_)
-                    | PgWireFrontendMessage::SASLResponse(_) => (),
                 }
             }
+        };
+
+        if self.send_rows(&mut rows, max_rows).await? {
+            self.suspended.insert(name, SuspendedPortal::Rows(rows));
+            self.socket
+                .feed(PgWireBackendMessage::PortalSuspended(PortalSuspended::new()))
+                .await?;
+        } else {
+            self.complete(rows).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends at most `max_rows` of the rows of a statement. Returns whether rows are left.
+    async fn send_rows(
+        &mut self,
+        rows: &mut StatementRows,
+        max_rows: usize,
+    ) -> Result<bool, PgWireError> {
+        for _ in 0..max_rows {
+            let Some(row) = rows.rows.next().await else { return Ok(false) };
+            let row = encode_row(row?, &rows.formats)?;
+            self.socket.feed(PgWireBackendMessage::DataRow(row)).await?;
+            rows.sent += 1;
+        }
+
+        Ok(Pin::new(&mut rows.rows).peek().await.is_some())
+    }
+
+    /// Completes a statement, once all its rows were sent. The rowid of the last row inserted by
+    /// the statement is reported as a parameter status, before its completion tag.
+    async fn complete(&mut self, rows: StatementRows) -> Result<(), PgWireError> {
+        if let (Command::Insert, Some(rowid)) = (rows.command, rows.last_insert_rowid) {
+            let status = ParameterStatus::new(LAST_INSERT_ROWID.to_owned(), rowid.to_string());
+            self.socket
+                .feed(PgWireBackendMessage::ParameterStatus(status))
+                .await?;
+        }
+        let returned = (!rows.columns.is_empty()).then_some(rows.sent);
+        let tag = rows
+            .command
+            .tag(&rows.stmt, rows.affected_row_count, returned);
+        self.socket
+            .feed(PgWireBackendMessage::CommandComplete(tag.into()))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Reports the error of a statement to the client. Errors that aren't caused by the statement
+    /// are returned instead.
+    async fn send_error(&mut self, error: QueryError) -> Result<(), PgWireError> {
+        // The transaction was rolled back once it timed out.
+        if matches!(error.code, ErrorCode::TxTimeout) {
+            self.txn = State::Start;
+        }
+        match PgWireError::from(error) {
+            PgWireError::UserError(info) => {
+                self.socket
+                    .feed(PgWireBackendMessage::ErrorResponse((*info).into()))
+                    .await?;
+                Ok(())
+            }
+            e => Err(e),
+        }
+    }
+
+    /// Describes a statement, with the types of its parameters and the columns of its rows, or a
+    /// portal, with the columns of its rows. The statement is prepared by the database, but not
+    /// executed.
+    async fn describe(&mut self, describe: Describe) -> Result<(), PgWireError> {
+        let name = stored_name(describe.name()).to_owned();
+        let store = self.socket.portal_store();
+        if *describe.target_type() == b'P' {
+            let portal = store
+                .get_portal(&name)
+                .ok_or_else(|| PgWireError::PortalNotFound(name.clone()))?;
+            let format = portal.result_column_format();
+            let columns = match self.suspended.get(&name) {
+                // The database is busy with the rows of the portal.
+                Some(SuspendedPortal::Rows(rows)) => rows.columns.clone(),
+                _ => {
+                    self.close_suspended_portals(None);
+                    let handler = QueryHandler::new(&mut self.service);
+                    handler.describe(portal.statement()).await?.columns
+                }
+            };
+            let formats = column_formats(&columns, |i| format.is_binary(i));
+            self.socket.feed(row_description(columns, &formats)).await?;
+        } else {
+            let statement = store
+                .get_statement(&name)
+                .ok_or_else(|| PgWireError::StatementNotFound(name.clone()))?;
+            self.close_suspended_portals(None);
+            let handler = QueryHandler::new(&mut self.service);
+            let description = handler.describe(statement.statement()).await?;
+            // SQLite doesn't infer the types of parameters: the types given by the client, if
+            // any, are reported back.
+            let types = (0..description.param_count)
+                .map(|i| {
+                    let ty = statement.parameter_types().get(i);
+                    ty.unwrap_or(&Type::UNKNOWN).oid()
+                })
+                .collect();
+            self.socket
+                .feed(PgWireBackendMessage::ParameterDescription(
+                    ParameterDescription::new(types),
+                ))
+                .await?;
+            // The formats of the rows are only known once the statement is bound.
+            let formats = column_formats(&description.columns, |_| false);
+            self.socket
+                .feed(row_description(description.columns, &formats))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Follows the transaction of the session through `stmt`, that ran successfully.
+    fn track_transaction(&mut self, stmt: &str) {
+        let Ok(stmts) = Statements::parse(stmt.to_owned()) else { return };
+        self.txn = match stmts.state(self.txn.clone()) {
+            State::TxnClosed => State::Start,
+            state => state,
+        };
+    }
+
+    /// Whether the session is in an explicit transaction.
+    fn in_transaction(&self) -> bool {
+        matches!(self.txn, State::TxnOpened { .. })
+    }
+
+    /// Closes the suspended portals, but `except`. The database is busy until the rows of a
+    /// suspended portal are read, so they are dropped before another query runs.
+    fn close_suspended_portals(&mut self, except: Option<&str>) {
+        for (name, portal) in self.suspended.iter_mut() {
+            if except != Some(name.as_str()) {
+                *portal = SuspendedPortal::Closed;
+            }
         }
-        Ok(true)
+    }
+
+    /// Applies the session settings passed as startup parameters, once the client is
+    /// authenticated.
+    async fn apply_startup_parameters(&mut self) -> Result<(), PgWireError> {
+        let metadata = self.socket.codec().client_info().metadata();
+        let Some(timeout) = metadata.get(TXN_TIMEOUT_VAR) else {
+            return Ok(());
+        };
+        // The value is validated when the statement is executed.
+        let stmt = format!("SET {TXN_TIMEOUT_VAR} = '{}'", timeout.replace('\'', "''"));
+        poll_fn(|cx| self.service.poll_ready(cx)).await?;
+        let results = self
+            .service
+            .call(Query::SimpleQuery(stmt, Params::empty()))
+            .await?;
+        for result in results {
+            result?;
+        }
+
+        Ok(())
     }
 
     async fn handle_error(&mut self, error: PgWireError) -> Result<(), io::Error> {
@@ -104,27 +503,129 @@ where
     }
 }
 
+/// Authenticates the client of `socket`. Returns whether it is ready for queries, or `false` if
+/// it hung up, or if the server shut down in the meantime.
+async fn authenticate<T>(
+    socket: &mut Framed<T, PgWireMessageServerCodec>,
+    authenticator: &PgAuthenticator,
+    shutdown: &CancellationToken,
+) -> Result<bool, io::Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    while !matches!(
+        socket.codec().client_info().state(),
+        PgWireConnectionState::ReadyForQuery
+    ) {
+        let msg = tokio::select! {
+            msg = socket.next() => msg,
+            _ = shutdown.cancelled() => {
+                let _ = terminate(socket).await;
+                return Ok(false);
+            }
+        };
+        let result = match msg {
+            Some(Ok(msg)) => authenticator.authenticate(socket, msg).await,
+            Some(Err(error)) => Err(error),
+            None => return Ok(false),
+        };
+        if let Err(e) = result {
+            process_error(socket, e).await?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Notifies the client that the connection is closed because the server is shutting down.
+async fn terminate<T>(socket: &mut Framed<T, PgWireMessageServerCodec>) -> Result<(), io::Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    let error_info = ErrorInfo::new(
+        "FATAL".to_owned(),
+        "57P01".to_owned(),
+        "terminating connection due to administrator command".to_owned(),
+    );
+    socket
+        .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+        .await
+}
+
+/// Describes the columns of the rows of a statement, or tells that it returns none.
+fn row_description(columns: Vec<Column>, formats: &[ColumnFormat]) -> PgWireBackendMessage {
+    if columns.is_empty() {
+        return PgWireBackendMessage::NoData(NoData::new());
+    }
+
+    let fields = columns
+        .into_iter()
+        .zip(formats)
+        .map(|(col, format)| {
+            // Only the sizes of the fixed-size types are reported.
+            let size = if format.ty == Type::INT8 || format.ty == Type::FLOAT8 {
+                8
+            } else {
+                -1
+            };
+            FieldDescription::new(
+                col.name,
+                0,
+                0,
+                format.ty.oid(),
+                size,
+                -1,
+                format.binary as i16,
+            )
+        })
+        .collect();
+    PgWireBackendMessage::RowDescription(RowDescription::new(fields))
+}
+
+/// Returns the name a statement or a portal is stored under. Unnamed ones have a reserved name.
+fn stored_name(name: &Option<String>) -> &str {
+    name.as_deref().unwrap_or(DEFAULT_NAME)
+}
+
 /// A connection factory that takes a stream, and a ServiceFactory, and creates a PgWireConnection
 pub struct PgConnectionFactory<S> {
-    authenticator: Arc<PgAuthenticator>,
+    auth_method: AuthMethod,
+    credentials: Arc<Credentials>,
+    tls: Option<TlsAcceptor>,
+    shutdown: CancellationToken,
+    /// The connections whose queries can be cancelled.
+    cancel: Arc<CancelRegistry>,
     factory: S,
 }
 
 impl<S> PgConnectionFactory<S> {
-    pub fn new(inner: S) -> Self {
+    pub fn new(
+        inner: S,
+        auth_method: AuthMethod,
+        credentials: Arc<Credentials>,
+        tls: Option<TlsAcceptor>,
+        shutdown: CancellationToken,
+    ) -> Self {
         Self {
-            authenticator: Arc::new(PgAuthenticator),
+            auth_method,
+            credentials,
+            tls,
+            shutdown,
+            cancel: Arc::default(),
             factory: inner,
         }
     }
 }
 
-impl<T, F, S> Service<(T, SocketAddr)> for PgConnectionFactory<F>
+impl<F, S> Service<(NetStream, SocketAddr)> for PgConnectionFactory<F>
 where
-    T: AsyncRead + AsyncWrite + AsyncPeekable + Unpin + Send + Sync + 'static,
-    F: MakeService<(), Query, MakeError = anyhow::Error, Service = S> + Sync,
+    F: MakeService<(), Query, MakeError = anyhow::Error, Service = S>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
     F::Future: 'static + Send + Sync,
-    S: Service<Query, Response = QueryResponse, Error = QueryError> + Sync + Send,
+    S: Service<Query, Response = Vec<QueryResult>, Error = QueryError> + Interrupt + Sync + Send,
     S::Future: Send,
 {
     type Response = ();
@@ -138,26 +639,55 @@ where
         Ok(()).into()
     }
 
-    fn call(&mut self, (mut stream, addr): (T, SocketAddr)) -> Self::Future {
-        let client_info = ClientInfoHolder::new(addr, false);
-        let svc_fut = self.factory.make_service(());
-        let authenticator = self.authenticator.clone();
+    fn call(&mut self, (mut stream, addr): (NetStream, SocketAddr)) -> Self::Future {
+        let mut factory = self.factory.clone();
+        let auth_method = self.auth_method;
+        let credentials = self.credentials.clone();
+        // Streams that are already encrypted (wss) must not negotiate TLS a second time.
+        let tls = self.tls.clone().filter(|_| !stream.is_secure());
+        let shutdown = self.shutdown.clone();
+        let cancel = self.cancel.clone();
         Box::pin(async move {
-            let service = svc_fut.await.unwrap();
-            peek_for_sslrequest(&mut stream, false).await?;
-            let decoder = PgWireMessageServerCodec::new(client_info);
-            let socket = decoder.framed(stream);
+            // The server closes the connection of a cancel request without replying, whether a
+            // query was cancelled or not.
+            if let Some((pid, secret)) = peek_for_cancel_request(&mut stream).await? {
+                if cancel.cancel(pid, secret) {
+                    tracing::debug!("cancelled query of connection {pid}");
+                }
+                stream.shutdown().await?;
+                return Ok(());
+            }
 
-            let mut connection = PgWireConnection {
-                socket,
-                authenticator,
-                service,
-            };
+            let ssl = peek_for_sslrequest(&mut stream, tls.is_some()).await?;
+            if let (true, Some(acceptor)) = (ssl, &tls) {
+                stream = stream.upgrade_tls(acceptor).await?;
+            }
+            let client_info = ClientInfoHolder::new(addr, stream.is_secure());
+            let decoder = PgWireMessageServerCodec::new(client_info);
+            let mut socket = decoder.framed(stream);
 
-            connection.run().await;
+            // The session of the client is only created once it is authenticated.
+            let authenticator = PgAuthenticator::new(auth_method, credentials);
+            if authenticate(&mut socket, &authenticator, &shutdown).await? {
+                let service = factory.make_service(()).await?;
+                let key = cancel.register(service.interrupt_handle());
+                let mut connection = PgWireConnection {
+                    socket,
+                    service,
+                    shutdown,
+                    suspended: HashMap::new(),
+                    txn: State::Start,
+                    ignore_till_sync: false,
+                };
+                if let Err(e) = connection.start((key.pid, key.secret)).await {
+                    connection.handle_error(e).await?;
+                }
+                connection.run().await;
+                socket = connection.socket;
+            }
 
             // cleanup socket
-            let mut socket = connection.socket.into_inner();
+            let mut socket = socket.into_inner();
             socket.flush().await?;
             socket.shutdown().await?;
 
@@ -165,3 +695,459 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use futures::{future, stream};
+    use postgres_protocol::message::{backend, frontend};
+    use postgres_protocol::IsNull;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    use super::*;
+    use crate::query::{Description, Row, Type as ColumnType, Value};
+
+    /// Number of rows returned by every query.
+    const ROWS: i64 = 5;
+
+    /// Returns the integers from 0 to `ROWS` to any query, but inserts, that insert a single row.
+    struct Numbers;
+
+    impl Service<Query> for Numbers {
+        type Response = Vec<QueryResult>;
+        type Error = QueryError;
+        type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(
+            &mut self,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            Ok(()).into()
+        }
+
+        fn call(&mut self, query: Query) -> Self::Future {
+            let columns = vec![Column {
+                name: "n".into(),
+                decltype: Some("integer".into()),
+                ty: Some(ColumnType::Integer),
+            }];
+            let response = match query {
+                Query::SimpleQuery(sql, _) if sql.starts_with("INSERT") => {
+                    QueryResponse::ResultSet(ResultSet {
+                        affected_row_count: 1,
+                        last_insert_rowid: Some(42),
+                        command: Command::Insert,
+                        ..ResultSet::empty()
+                    })
+                }
+                Query::SimpleQuery(..) => {
+                    let rows = (0..ROWS).map(|n| {
+                        Ok(Row {
+                            values: vec![Value::Integer(n)],
+                        })
+                    });
+                    QueryResponse::ResultSet(ResultSet {
+                        columns,
+                        rows: Box::pin(stream::iter(rows)),
+                        affected_row_count: 0,
+                        last_insert_rowid: None,
+                        command: Command::Other,
+                    })
+                }
+                // Like SQLite, counts the anonymous parameters of the statement.
+                Query::Describe(sql) => QueryResponse::Description(Description {
+                    param_count: sql.matches('?').count(),
+                    columns,
+                }),
+            };
+            future::ready(Ok(vec![Ok(response)]))
+        }
+    }
+
+    /// The client end of a connection to a `Numbers` service.
+    struct Client {
+        stream: DuplexStream,
+        buf: BytesMut,
+    }
+
+    impl Client {
+        async fn connect() -> Self {
+            let (client, server) = tokio::io::duplex(4096);
+            let client_info = ClientInfoHolder::new(([127, 0, 0, 1], 5432).into(), false);
+            let mut socket = PgWireMessageServerCodec::new(client_info).framed(server);
+            tokio::spawn(async move {
+                let authenticator = PgAuthenticator::new(AuthMethod::Trust, Arc::default());
+                let shutdown = CancellationToken::new();
+                assert!(authenticate(&mut socket, &authenticator, &shutdown)
+                    .await
+                    .unwrap());
+                let mut connection = PgWireConnection {
+                    socket,
+                    service: Numbers,
+                    shutdown,
+                    suspended: HashMap::new(),
+                    txn: State::Start,
+                    ignore_till_sync: false,
+                };
+                connection.start((0, 0)).await.unwrap();
+                connection.run().await
+            });
+
+            let mut client = Self {
+                stream: client,
+                buf: BytesMut::new(),
+            };
+            client
+                .send(|buf| frontend::startup_message([("user", "test")], buf).unwrap())
+                .await;
+            client.responses().await;
+            client
+        }
+
+        async fn send(&mut self, write: impl FnOnce(&mut BytesMut)) {
+            let mut buf = BytesMut::new();
+            write(&mut buf);
+            self.stream.write_all(&buf).await.unwrap();
+        }
+
+        /// Prepares `stmt` as `name`.
+        async fn parse(&mut self, name: &str, stmt: &str) {
+            self.send(|buf| frontend::parse(name, stmt, [], buf).unwrap())
+                .await;
+        }
+
+        /// Binds `portal` to the statement `name`, that takes no parameters.
+        async fn bind(&mut self, portal: &str, name: &str) {
+            self.send(|buf| {
+                frontend::bind(portal, name, [], [], |(), _| unreachable!(), [], buf).unwrap()
+            })
+            .await;
+        }
+
+        /// Binds `portal` to the statement `name`, with the text `params`.
+        async fn bind_params(&mut self, portal: &str, name: &str, params: &[&str]) {
+            self.send(|buf| {
+                let write = |param: &&str, buf: &mut BytesMut| {
+                    buf.extend_from_slice(param.as_bytes());
+                    Ok(IsNull::No)
+                };
+                frontend::bind(portal, name, [], params, write, [], buf).unwrap()
+            })
+            .await;
+        }
+
+        async fn execute(&mut self, portal: &str, max_rows: i32) {
+            self.send(|buf| frontend::execute(portal, max_rows, buf).unwrap())
+                .await;
+        }
+
+        async fn sync(&mut self) {
+            self.send(frontend::sync).await;
+        }
+
+        /// Reads the messages sent by the server, until it is ready for a new query, and names
+        /// them. Data rows are named after their value.
+        async fn responses(&mut self) -> Vec<String> {
+            let mut responses = Vec::new();
+            loop {
+                let msg = match backend::Message::parse(&mut self.buf).unwrap() {
+                    Some(msg) => msg,
+                    None => {
+                        let n = self.stream.read_buf(&mut self.buf).await.unwrap();
+                        assert_ne!(n, 0, "the connection was closed");
+                        continue;
+                    }
+                };
+                let response = match msg {
+                    backend::Message::ParseComplete => "ParseComplete".to_owned(),
+                    backend::Message::BindComplete => "BindComplete".to_owned(),
+                    backend::Message::RowDescription(_) => "RowDescription".to_owned(),
+                    // The single column of the row, after its length.
+                    backend::Message::DataRow(row) => {
+                        String::from_utf8(row.buffer()[4..].to_vec()).unwrap()
+                    }
+                    backend::Message::PortalSuspended => "PortalSuspended".to_owned(),
+                    backend::Message::CommandComplete(body) => body.tag().unwrap().to_owned(),
+                    backend::Message::ParameterStatus(status) => {
+                        format!("{}={}", status.name().unwrap(), status.value().unwrap())
+                    }
+                    backend::Message::ErrorResponse(_) => "ErrorResponse".to_owned(),
+                    backend::Message::ReadyForQuery(_) => return responses,
+                    _ => continue,
+                };
+                responses.push(response);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn simple_query() {
+        let mut client = Client::connect().await;
+        client
+            .send(|buf| frontend::query("SELECT n FROM numbers", buf).unwrap())
+            .await;
+        assert_eq!(
+            client.responses().await,
+            ["RowDescription", "0", "1", "2", "3", "4", "SELECT 5"]
+        );
+
+        client
+            .send(|buf| frontend::query("INSERT INTO t VALUES (1)", buf).unwrap())
+            .await;
+        assert_eq!(
+            client.responses().await,
+            ["last_insert_rowid=42", "INSERT 0 1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_insert() {
+        let mut client = Client::connect().await;
+        client.parse("", "INSERT INTO t VALUES (1)").await;
+        client.bind("", "").await;
+        client.execute("", 0).await;
+        client.sync().await;
+
+        assert_eq!(
+            client.responses().await,
+            [
+                "ParseComplete",
+                "BindComplete",
+                "last_insert_rowid=42",
+                "INSERT 0 1"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn bind_anonymous_parameters() {
+        let mut client = Client::connect().await;
+        client
+            .parse("stmt", "SELECT n FROM numbers WHERE n > ?")
+            .await;
+        client.bind_params("", "stmt", &["2"]).await;
+        client.execute("", 0).await;
+        client.sync().await;
+        assert_eq!(
+            client.responses().await,
+            [
+                "ParseComplete",
+                "BindComplete",
+                "0",
+                "1",
+                "2",
+                "3",
+                "4",
+                "SELECT 5"
+            ]
+        );
+
+        client.bind_params("", "stmt", &["1", "2"]).await;
+        client.execute("", 0).await;
+        client.sync().await;
+        assert_eq!(client.responses().await, ["BindComplete", "ErrorResponse"]);
+    }
+
+    #[tokio::test]
+    async fn describe_suspended_portal() {
+        let mut client = Client::connect().await;
+        client.parse("", "SELECT n FROM numbers").await;
+        client.bind("", "").await;
+        client.execute("", 2).await;
+        client
+            .send(|buf| frontend::describe(b'P', "", buf).unwrap())
+            .await;
+        client.execute("", 0).await;
+        client.sync().await;
+
+        assert_eq!(
+            client.responses().await,
+            [
+                "ParseComplete",
+                "BindComplete",
+                "0",
+                "1",
+                "PortalSuspended",
+                "RowDescription",
+                "2",
+                "3",
+                "4",
+                "SELECT 5",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn resume_suspended_portal() {
+        let mut client = Client::connect().await;
+        client.parse("", "SELECT n FROM numbers").await;
+        client.bind("", "").await;
+        client.execute("", 2).await;
+        client.execute("", 2).await;
+        client.execute("", 2).await;
+        client.sync().await;
+
+        assert_eq!(
+            client.responses().await,
+            [
+                "ParseComplete",
+                "BindComplete",
+                "0",
+                "1",
+                "PortalSuspended",
+                "2",
+                "3",
+                "PortalSuspended",
+                "4",
+                "SELECT 5",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn complete_portal_at_row_limit() {
+        let mut client = Client::connect().await;
+        client.parse("", "SELECT n FROM numbers").await;
+        client.bind("", "").await;
+        client.execute("", ROWS as i32).await;
+        client.sync().await;
+
+        assert_eq!(
+            client.responses().await,
+            [
+                "ParseComplete",
+                "BindComplete",
+                "0",
+                "1",
+                "2",
+                "3",
+                "4",
+                "SELECT 5",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_closes_other_suspended_portals() {
+        let mut client = Client::connect().await;
+        client.parse("", "SELECT n FROM numbers").await;
+        client.bind("a", "").await;
+        client.bind("b", "").await;
+        client.execute("a", 2).await;
+        client.execute("b", 1).await;
+        client.execute("a", 1).await;
+        client.sync().await;
+
+        assert_eq!(
+            client.responses().await,
+            [
+                "ParseComplete",
+                "BindComplete",
+                "BindComplete",
+                "0",
+                "1",
+                "PortalSuspended",
+                "0",
+                "PortalSuspended",
+                "ErrorResponse",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_closes_suspended_portal() {
+        let mut client = Client::connect().await;
+        client.parse("", "SELECT n FROM numbers").await;
+        client.bind("", "").await;
+        client.execute("", 2).await;
+        client.sync().await;
+        assert_eq!(
+            client.responses().await,
+            ["ParseComplete", "BindComplete", "0", "1", "PortalSuspended"]
+        );
+
+        client.execute("", 2).await;
+        client.sync().await;
+        assert_eq!(client.responses().await, ["ErrorResponse"]);
+    }
+
+    #[tokio::test]
+    async fn skip_extended_query_after_error() {
+        let mut client = Client::connect().await;
+        client.parse("stmt", "SELECT n FROM numbers").await;
+        client.execute("missing", 0).await;
+        // Skipped, until the client syncs.
+        client.bind("", "stmt").await;
+        client.execute("", 0).await;
+        client.sync().await;
+        assert_eq!(client.responses().await, ["ParseComplete", "ErrorResponse"]);
+
+        client.bind("", "stmt").await;
+        client.execute("", 1).await;
+        client.sync().await;
+        assert_eq!(
+            client.responses().await,
+            ["BindComplete", "0", "PortalSuspended"]
+        );
+    }
+
+    #[tokio::test]
+    async fn suspended_portal_lives_until_transaction_ends() {
+        let mut client = Client::connect().await;
+        client
+            .send(|buf| frontend::query("BEGIN", buf).unwrap())
+            .await;
+        client.responses().await;
+
+        client.parse("", "SELECT n FROM numbers").await;
+        client.bind("", "").await;
+        client.execute("", 2).await;
+        client.sync().await;
+        assert_eq!(
+            client.responses().await,
+            ["ParseComplete", "BindComplete", "0", "1", "PortalSuspended"]
+        );
+        client.execute("", 2).await;
+        client.sync().await;
+        assert_eq!(client.responses().await, ["2", "3", "PortalSuspended"]);
+
+        client
+            .send(|buf| frontend::query("COMMIT", buf).unwrap())
+            .await;
+        client.responses().await;
+        client.bind("", "").await;
+        client.execute("", 2).await;
+        client.sync().await;
+        client.responses().await;
+        client.execute("", 2).await;
+        client.sync().await;
+        assert_eq!(client.responses().await, ["ErrorResponse"]);
+    }
+
+    #[tokio::test]
+    async fn bind_resets_suspended_portal() {
+        let mut client = Client::connect().await;
+        client.parse("stmt", "SELECT n FROM numbers").await;
+        client.bind("portal", "stmt").await;
+        client.execute("portal", 2).await;
+        // Binding the portal again runs its statement from the start.
+        client.bind("portal", "stmt").await;
+        client.execute("portal", 2).await;
+        client.sync().await;
+
+        assert_eq!(
+            client.responses().await,
+            [
+                "ParseComplete",
+                "BindComplete",
+                "0",
+                "1",
+                "PortalSuspended",
+                "BindComplete",
+                "0",
+                "1",
+                "PortalSuspended",
+            ]
+        );
+    }
+}
@@ -1,15 +1,21 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::fmt;
+use std::pin::Pin;
 use std::str::FromStr;
 
-use futures::stream;
-use pgwire::api::results::{text_query_response, FieldInfo, Response, TextDataRowEncoder};
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use pgwire::api::results::{text_query_response, FieldInfo, Response, Tag};
 use pgwire::api::Type as PgType;
 use pgwire::{error::PgWireResult, messages::data::DataRow};
 use serde::{Deserialize, Serialize};
 
+use crate::query_analysis::first_keyword;
 use crate::rpc::proxy::proxy_rpc::{
-    error::ErrorCode as RpcErrorCode, Column as RpcColumn, Error as RpcError, ResultRows,
-    Row as RpcRow, Type as RpcType, Value as RpcValue,
+    error::ErrorCode as RpcErrorCode, parameter::Value as RpcParameterValue, Column as RpcColumn,
+    Command as RpcCommand, Description as RpcDescription, Error as RpcError, Null as RpcNull,
+    Parameter as RpcParameter, ResultRows, Row as RpcRow, Type as RpcType, Value as RpcValue,
 };
 
 pub type QueryResult = Result<QueryResponse, QueryError>;
@@ -17,6 +23,8 @@ pub type QueryResult = Result<QueryResponse, QueryError>;
 #[derive(Debug, Clone)]
 pub struct Column {
     pub name: String,
+    /// Declared type of the column, as written in the table definition
+    pub decltype: Option<String>,
     pub ty: Option<Type>,
 }
 
@@ -25,6 +33,17 @@ impl From<Column> for RpcColumn {
         RpcColumn {
             name: other.name,
             ty: other.ty.map(|ty| RpcType::from(ty).into()),
+            decltype: other.decltype,
+        }
+    }
+}
+
+impl From<RpcColumn> for Column {
+    fn from(other: RpcColumn) -> Self {
+        Column {
+            ty: Some(other.ty().into()),
+            name: other.name,
+            decltype: other.decltype,
         }
     }
 }
@@ -103,7 +122,10 @@ impl FromStr for Type {
             "text" | "character" | "varchar" | "varying character" | "nchar"
             | "native character" | "nvarchar" | "clob" => Type::Text,
             "blob" => Type::Blob,
-            "numeric" | "decimal" | "boolean" | "date" | "datetime" => Type::Numeric,
+            "numeric" | "decimal" => Type::Numeric,
+            // SQLite stores booleans, dates and times as they were written, which doesn't fit the
+            // binary format of NUMERIC: they are sent as text.
+            "boolean" | "date" | "datetime" => Type::Text,
             _ => Type::Unknown,
         })
     }
@@ -150,102 +172,439 @@ impl From<rusqlite::types::Value> for Value {
     }
 }
 
-#[derive(Debug)]
-pub struct ResultSet {
-    pub columns: Vec<Column>,
-    pub rows: Vec<Row>,
+impl From<Value> for RpcParameter {
+    fn from(other: Value) -> Self {
+        let value = match other {
+            Value::Null => RpcParameterValue::Null(RpcNull {}),
+            Value::Integer(i) => RpcParameterValue::Integer(i),
+            Value::Real(x) => RpcParameterValue::Real(x),
+            Value::Text(s) => RpcParameterValue::Text(s),
+            Value::Blob(b) => RpcParameterValue::Blob(b),
+        };
+
+        RpcParameter { value: Some(value) }
+    }
 }
 
-fn encode_row(row: Row) -> PgWireResult<DataRow> {
-    let mut encoder = TextDataRowEncoder::new(row.values.len());
-    for value in row.values {
-        match value {
-            Value::Null => {
-                encoder.append_field(None::<&u8>)?;
-            }
-            Value::Integer(i) => {
-                encoder.append_field(Some(&i))?;
-            }
-            Value::Real(f) => {
-                encoder.append_field(Some(&f))?;
-            }
-            Value::Text(t) => {
-                encoder.append_field(Some(&t))?;
-            }
-            Value::Blob(b) => {
-                encoder.append_field(Some(&hex::encode(b)))?;
-            }
+impl From<RpcParameter> for Value {
+    fn from(other: RpcParameter) -> Self {
+        match other.value {
+            Some(RpcParameterValue::Null(_)) | None => Self::Null,
+            Some(RpcParameterValue::Integer(i)) => Self::Integer(i),
+            Some(RpcParameterValue::Real(x)) => Self::Real(x),
+            Some(RpcParameterValue::Text(s)) => Self::Text(s),
+            Some(RpcParameterValue::Blob(b)) => Self::Blob(b),
         }
     }
-    encoder.finish()
 }
 
-impl From<ResultSet> for Response {
-    fn from(ResultSet { columns, rows }: ResultSet) -> Self {
-        let field_infos = columns.into_iter().map(Into::into).collect();
-        let data_row_stream = stream::iter(rows.into_iter().map(encode_row));
-        Response::Query(text_query_response(field_infos, data_row_stream))
+/// Parameters bound to a query.
+#[derive(Debug, Clone)]
+pub enum Params {
+    /// Bound by position, to `?` and `?NNN` placeholders
+    Positional(Vec<Value>),
+    /// Bound by name, to `:AAA`, `@AAA` and `$AAA` placeholders. Names may omit the prefix.
+    Named(HashMap<String, Value>),
+}
+
+impl Params {
+    pub fn empty() -> Self {
+        Self::Positional(Vec::new())
+    }
+}
+
+/// The rows of a result set, as the statement steps through them. Dropping the stream stops the
+/// statement.
+pub type RowStream = Pin<Box<dyn Stream<Item = Result<Row, QueryError>> + Send>>;
+
+/// The command a statement ran, as SQLite reports it when the statement is prepared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Command {
+    /// A statement that doesn't modify rows.
+    #[default]
+    Other,
+    Insert,
+    Update,
+    Delete,
+}
+
+impl Command {
+    /// Builds the completion tag of `stmt`, that ran this command. `rows` is the number of rows
+    /// the statement returned, if it returns rows.
+    pub fn tag(self, stmt: &str, affected_row_count: u64, rows: Option<usize>) -> Tag {
+        let affected_row_count = Some(affected_row_count as usize);
+        match (self, rows) {
+            // The tag is `INSERT <oid> <rows>`, where PostgreSQL used to report the OID of the
+            // inserted row, and now always reports 0.
+            (Command::Insert, _) => Tag::new_for_execution("INSERT 0", affected_row_count),
+            (Command::Update, _) => Tag::new_for_execution("UPDATE", affected_row_count),
+            (Command::Delete, _) => Tag::new_for_execution("DELETE", affected_row_count),
+            (Command::Other, Some(rows)) => Tag::new_for_query(rows),
+            (Command::Other, None) => {
+                let keyword = first_keyword(stmt).unwrap_or_default().to_uppercase();
+                Tag::new_for_execution(&keyword, None)
+            }
+        }
     }
 }
 
-impl From<ResultSet> for ResultRows {
-    fn from(other: ResultSet) -> Self {
-        let column_descriptions = other.columns.into_iter().map(Into::into).collect();
-        let rows = other
-            .rows
-            .iter()
-            .map(|row| RpcRow {
-                values: row
-                    .values
-                    .iter()
-                    .map(|v| bincode::serialize(v).unwrap())
-                    .map(|data| RpcValue { data })
-                    .collect(),
-            })
-            .collect();
+impl From<Command> for RpcCommand {
+    fn from(other: Command) -> Self {
+        match other {
+            Command::Other => Self::Other,
+            Command::Insert => Self::Insert,
+            Command::Update => Self::Update,
+            Command::Delete => Self::Delete,
+        }
+    }
+}
 
-        ResultRows {
-            column_descriptions,
-            rows,
+impl From<RpcCommand> for Command {
+    fn from(other: RpcCommand) -> Self {
+        match other {
+            RpcCommand::Other => Self::Other,
+            RpcCommand::Insert => Self::Insert,
+            RpcCommand::Update => Self::Update,
+            RpcCommand::Delete => Self::Delete,
         }
     }
 }
 
-impl From<ResultRows> for ResultSet {
-    fn from(rows: ResultRows) -> Self {
-        let columns = rows
+pub struct ResultSet {
+    pub columns: Vec<Column>,
+    pub rows: RowStream,
+    /// Number of rows inserted, updated or deleted by the statement.
+    pub affected_row_count: u64,
+    /// Rowid of the last inserted row, for statements that modify rows.
+    pub last_insert_rowid: Option<i64>,
+    pub command: Command,
+}
+
+impl fmt::Debug for ResultSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResultSet")
+            .field("columns", &self.columns)
+            .field("affected_row_count", &self.affected_row_count)
+            .field("last_insert_rowid", &self.last_insert_rowid)
+            .field("command", &self.command)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResultSet {
+    pub fn empty() -> Self {
+        Self {
+            columns: Vec::new(),
+            rows: Box::pin(stream::empty()),
+            affected_row_count: 0,
+            last_insert_rowid: None,
+            command: Command::Other,
+        }
+    }
+
+    /// Splits the result set into its RPC representation, without the rows, and its rows, that are
+    /// sent separately.
+    pub fn into_rpc(self) -> (ResultRows, RowStream) {
+        let header = ResultRows {
+            column_descriptions: self.columns.into_iter().map(Into::into).collect(),
+            rows: Vec::new(),
+            affected_row_count: self.affected_row_count,
+            last_insert_rowid: self.last_insert_rowid,
+            command: RpcCommand::from(self.command).into(),
+        };
+
+        (header, self.rows)
+    }
+
+    /// Builds a result set from its RPC representation, and the rows that were sent separately.
+    pub fn from_rpc(header: ResultRows, rows: RowStream) -> Self {
+        let columns = header
             .column_descriptions
             .into_iter()
-            .map(|c| Column {
-                ty: Some(c.ty().into()),
-                name: c.name,
-            })
+            .map(Into::into)
             .collect();
 
-        let rows = rows
-            .rows
-            .into_iter()
-            .map(|row| {
-                row.values
-                    .iter()
-                    .map(|v| bincode::deserialize(&v.data).unwrap())
-                    .collect::<Vec<_>>()
-            })
-            .map(|values| Row { values })
-            .collect();
+        Self {
+            columns,
+            rows,
+            command: header.command().into(),
+            affected_row_count: header.affected_row_count,
+            last_insert_rowid: header.last_insert_rowid,
+        }
+    }
+
+    /// Builds the response to a PostgreSQL client. `query` is the statement that produced the
+    /// result set, which names the command in the completion tag of statements returning no rows.
+    /// `binary` tells whether the client asked for the binary format for the column at an index.
+    pub fn into_response(self, query: &str, binary: impl Fn(usize) -> bool) -> Response {
+        if !self.columns.is_empty() {
+            let formats = column_formats(&self.columns, binary);
+            let field_infos = self.columns.into_iter().map(Into::into).collect();
+            let data_row_stream = self.rows.map(move |row| encode_row(row?, &formats));
+            // The fields are only described as text in the response to a simple query, which is
+            // always in the text format.
+            return Response::Query(text_query_response(field_infos, data_row_stream));
+        }
+
+        Response::Execution(self.command.tag(query, self.affected_row_count, None))
+    }
+}
+
+/// How the values of a column are sent to a PostgreSQL client.
+#[derive(Debug, Clone)]
+pub struct ColumnFormat {
+    /// The type the column is described with.
+    pub ty: PgType,
+    /// Whether the client asked for the binary format of the type, rather than text.
+    pub binary: bool,
+}
+
+/// Returns how each column is sent to a PostgreSQL client. `binary` tells whether the client asked
+/// for the binary format for the column at an index.
+pub fn column_formats(columns: &[Column], binary: impl Fn(usize) -> bool) -> Vec<ColumnFormat> {
+    columns
+        .iter()
+        .enumerate()
+        .map(|(i, col)| ColumnFormat {
+            ty: col.ty.map(PgType::from).unwrap_or(PgType::UNKNOWN),
+            binary: binary(i),
+        })
+        .collect()
+}
+
+/// Encodes a row, each value in the format of its column.
+pub fn encode_row(row: Row, formats: &[ColumnFormat]) -> PgWireResult<DataRow> {
+    let fields = row
+        .values
+        .into_iter()
+        .zip(formats)
+        .map(|(value, format)| match format.binary {
+            true => encode_binary(value, &format.ty),
+            false => Ok(encode_text(value)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(DataRow::new(fields))
+}
 
-        Self { columns, rows }
+fn format_real(x: f64) -> String {
+    match x {
+        f64::INFINITY => "Infinity".to_owned(),
+        f64::NEG_INFINITY => "-Infinity".to_owned(),
+        x => x.to_string(),
+    }
+}
+
+/// The text format of a value does not depend on the type of its column.
+fn text_of(value: Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Real(x) => Some(format_real(x)),
+        Value::Text(s) => Some(s),
+        Value::Blob(b) => Some(format!("\\x{}", hex::encode(b))),
+    }
+}
+
+fn encode_text(value: Value) -> Option<Bytes> {
+    text_of(value).map(Bytes::from)
+}
+
+/// Encodes a value in the binary format of the type of its column. Since SQLite doesn't enforce
+/// the declared types of columns, the value is converted to the type of its column first.
+fn encode_binary(value: Value, ty: &PgType) -> Result<Option<Bytes>, QueryError> {
+    let mismatch = |value: &Value| {
+        QueryError::new(
+            ErrorCode::SQLError,
+            format!("cannot encode {value:?} in the binary format of type {ty}"),
+        )
+    };
+    let data = match value {
+        Value::Null => return Ok(None),
+        value if ty == &PgType::INT8 => {
+            let i = match value {
+                Value::Integer(i) => Some(i),
+                Value::Real(x)
+                    if x.fract() == 0.0 && x >= i64::MIN as f64 && x < i64::MAX as f64 =>
+                {
+                    Some(x as i64)
+                }
+                Value::Text(ref s) => s.trim().parse().ok(),
+                _ => None,
+            };
+            i.ok_or_else(|| mismatch(&value))?.to_be_bytes().to_vec()
+        }
+        value if ty == &PgType::FLOAT8 => {
+            let x = match value {
+                Value::Integer(i) => Some(i as f64),
+                Value::Real(x) => Some(x),
+                Value::Text(ref s) => s.trim().parse().ok(),
+                _ => None,
+            };
+            x.ok_or_else(|| mismatch(&value))?.to_be_bytes().to_vec()
+        }
+        value if ty == &PgType::NUMERIC => {
+            let numeric = match value {
+                Value::Integer(i) => encode_numeric(&i.to_string()),
+                Value::Real(x) => encode_numeric(&format_real(x)),
+                Value::Text(ref s) => encode_numeric(s.trim()),
+                _ => None,
+            };
+            numeric.ok_or_else(|| mismatch(&value))?
+        }
+        Value::Blob(b) if ty == &PgType::BYTEA => b,
+        // The binary format of text is the text itself.
+        value => text_of(value).unwrap_or_default().into_bytes(),
+    };
+
+    Ok(Some(Bytes::from(data)))
+}
+
+/// Encodes a decimal number in the binary format of NUMERIC: base 10000 digits, along with the
+/// weight of the first digit, the sign, and the number of decimal digits.
+fn encode_numeric(decimal: &str) -> Option<Vec<u8>> {
+    let special = match decimal {
+        "NaN" => Some(0xC000),
+        "Infinity" => Some(0xD000),
+        "-Infinity" => Some(0xF000),
+        _ => None,
+    };
+    if let Some(sign) = special {
+        return Some(
+            [0u16, 0, sign, 0]
+                .iter()
+                .flat_map(|w| w.to_be_bytes())
+                .collect(),
+        );
+    }
+
+    let (negative, decimal) = match decimal.strip_prefix('-') {
+        Some(decimal) => (true, decimal),
+        None => (false, decimal.strip_prefix('+').unwrap_or(decimal)),
+    };
+    let (int, fraction) = decimal.split_once('.').unwrap_or((decimal, ""));
+    if int.is_empty() && fraction.is_empty()
+        || !int
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    // The digits are grouped by 4 on each side of the decimal point.
+    let int_padding = (4 - int.len() % 4) % 4;
+    let fraction_padding = (4 - fraction.len() % 4) % 4;
+    let digits = format!(
+        "{}{int}{fraction}{}",
+        "0".repeat(int_padding),
+        "0".repeat(fraction_padding)
+    );
+    let mut groups = digits
+        .as_bytes()
+        .chunks(4)
+        .map(|group| group.iter().fold(0u16, |n, d| n * 10 + (d - b'0') as u16))
+        .collect::<Vec<_>>();
+    let mut weight = ((int_padding + int.len()) / 4) as i16 - 1;
+    while groups.first() == Some(&0) {
+        groups.remove(0);
+        weight -= 1;
+    }
+    while groups.last() == Some(&0) {
+        groups.pop();
+    }
+    if groups.is_empty() {
+        weight = 0;
+    }
+    let sign: u16 = if negative && !groups.is_empty() {
+        0x4000
+    } else {
+        0
+    };
+
+    let header = [
+        groups.len() as u16,
+        weight as u16,
+        sign,
+        fraction.len() as u16,
+    ];
+    Some(
+        header
+            .iter()
+            .chain(&groups)
+            .flat_map(|w| w.to_be_bytes())
+            .collect(),
+    )
+}
+
+impl From<Row> for RpcRow {
+    fn from(row: Row) -> Self {
+        RpcRow {
+            values: row
+                .values
+                .iter()
+                .map(|v| bincode::serialize(v).unwrap())
+                .map(|data| RpcValue { data })
+                .collect(),
+        }
+    }
+}
+
+impl From<RpcRow> for Row {
+    fn from(row: RpcRow) -> Self {
+        Row {
+            values: row
+                .values
+                .iter()
+                .map(|v| bincode::deserialize(&v.data).unwrap())
+                .collect(),
+        }
+    }
+}
+
+/// The description of a prepared statement.
+#[derive(Debug, Clone)]
+pub struct Description {
+    /// Number of parameters of the statement.
+    pub param_count: usize,
+    /// The columns of the rows the statement returns.
+    pub columns: Vec<Column>,
+}
+
+impl From<Description> for RpcDescription {
+    fn from(other: Description) -> Self {
+        RpcDescription {
+            column_descriptions: other.columns.into_iter().map(Into::into).collect(),
+            param_count: other.param_count as u64,
+        }
+    }
+}
+
+impl From<RpcDescription> for Description {
+    fn from(other: RpcDescription) -> Self {
+        Description {
+            param_count: other.param_count as usize,
+            columns: other
+                .column_descriptions
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        }
     }
 }
 
 #[derive(Debug)]
 pub enum QueryResponse {
     ResultSet(ResultSet),
+    /// The response to `Query::Describe`.
+    Description(Description),
 }
 
 #[derive(Debug)]
 pub enum Query {
-    SimpleQuery(String, Vec<Value>),
+    SimpleQuery(String, Params),
+    /// Prepares a single statement, without executing it, to describe it.
+    Describe(String),
 }
 
 #[derive(Debug, Clone)]
@@ -261,12 +620,40 @@ impl From<RpcError> for QueryError {
             RpcErrorCode::TxBusy => ErrorCode::TxBusy,
             RpcErrorCode::TxTimeout => ErrorCode::TxTimeout,
             RpcErrorCode::Internal => ErrorCode::Internal,
+            RpcErrorCode::ConstraintViolation => ErrorCode::ConstraintViolation,
+            RpcErrorCode::UniqueViolation => ErrorCode::UniqueViolation,
+            RpcErrorCode::ForeignKeyViolation => ErrorCode::ForeignKeyViolation,
+            RpcErrorCode::NotNullViolation => ErrorCode::NotNullViolation,
+            RpcErrorCode::CheckViolation => ErrorCode::CheckViolation,
+            RpcErrorCode::QueryCanceled => ErrorCode::QueryCanceled,
         };
 
         Self::new(code, other.message)
     }
 }
 
+impl From<QueryError> for RpcError {
+    fn from(other: QueryError) -> Self {
+        let code = match other.code {
+            ErrorCode::SQLError => RpcErrorCode::SqlError,
+            ErrorCode::TxBusy => RpcErrorCode::TxBusy,
+            ErrorCode::TxTimeout => RpcErrorCode::TxTimeout,
+            ErrorCode::Internal => RpcErrorCode::Internal,
+            ErrorCode::ConstraintViolation => RpcErrorCode::ConstraintViolation,
+            ErrorCode::UniqueViolation => RpcErrorCode::UniqueViolation,
+            ErrorCode::ForeignKeyViolation => RpcErrorCode::ForeignKeyViolation,
+            ErrorCode::NotNullViolation => RpcErrorCode::NotNullViolation,
+            ErrorCode::CheckViolation => RpcErrorCode::CheckViolation,
+            ErrorCode::QueryCanceled => RpcErrorCode::QueryCanceled,
+        };
+
+        RpcError {
+            code: code.into(),
+            message: other.msg,
+        }
+    }
+}
+
 impl QueryError {
     pub fn new(code: ErrorCode, msg: impl ToString) -> Self {
         Self {
@@ -278,7 +665,34 @@ impl QueryError {
 
 impl From<rusqlite::Error> for QueryError {
     fn from(other: rusqlite::Error) -> Self {
-        Self::new(ErrorCode::SQLError, other)
+        use rusqlite::ffi::{self, ErrorCode as SqliteErrorCode};
+
+        let code = match &other {
+            rusqlite::Error::SqliteFailure(e, _) => match e.code {
+                SqliteErrorCode::ConstraintViolation => match e.extended_code {
+                    ffi::SQLITE_CONSTRAINT_UNIQUE | ffi::SQLITE_CONSTRAINT_PRIMARYKEY => {
+                        ErrorCode::UniqueViolation
+                    }
+                    ffi::SQLITE_CONSTRAINT_FOREIGNKEY => ErrorCode::ForeignKeyViolation,
+                    ffi::SQLITE_CONSTRAINT_NOTNULL => ErrorCode::NotNullViolation,
+                    ffi::SQLITE_CONSTRAINT_CHECK => ErrorCode::CheckViolation,
+                    _ => ErrorCode::ConstraintViolation,
+                },
+                SqliteErrorCode::DatabaseBusy | SqliteErrorCode::DatabaseLocked => {
+                    ErrorCode::TxBusy
+                }
+                // Statements are only interrupted when a client cancels them.
+                SqliteErrorCode::OperationInterrupted => {
+                    return Self::new(
+                        ErrorCode::QueryCanceled,
+                        "canceling statement due to user request",
+                    )
+                }
+                _ => ErrorCode::SQLError,
+            },
+            _ => ErrorCode::SQLError,
+        };
+        Self::new(code, other)
     }
 }
 
@@ -288,4 +702,137 @@ pub enum ErrorCode {
     TxBusy,
     TxTimeout,
     Internal,
+    /// A statement violated a constraint that has no more specific code, e.g. a trigger that
+    /// raised an `ABORT`.
+    ConstraintViolation,
+    /// A statement violated a `UNIQUE` or `PRIMARY KEY` constraint.
+    UniqueViolation,
+    ForeignKeyViolation,
+    NotNullViolation,
+    CheckViolation,
+    /// The statement was interrupted, because the client cancelled it.
+    QueryCanceled,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Encodes `decimal` as a NUMERIC, and returns the header and the digits of the result.
+    fn numeric_words(decimal: &str) -> Vec<u16> {
+        encode_numeric(decimal)
+            .unwrap()
+            .chunks(2)
+            .map(|w| u16::from_be_bytes([w[0], w[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn encode_numeric_integers() {
+        assert_eq!(numeric_words("0"), [0, 0, 0, 0]);
+        assert_eq!(numeric_words("-0"), [0, 0, 0, 0]);
+        assert_eq!(numeric_words("42"), [1, 0, 0, 0, 42]);
+        assert_eq!(numeric_words("+42"), [1, 0, 0, 0, 42]);
+        assert_eq!(numeric_words("10000"), [1, 1, 0, 0, 1]);
+        assert_eq!(numeric_words("123456789"), [3, 2, 0, 0, 1, 2345, 6789]);
+    }
+
+    #[test]
+    fn encode_numeric_negative() {
+        assert_eq!(numeric_words("-42"), [1, 0, 0x4000, 0, 42]);
+        assert_eq!(numeric_words("-1.50"), [2, 0, 0x4000, 2, 1, 5000]);
+    }
+
+    #[test]
+    fn encode_numeric_fractions() {
+        assert_eq!(numeric_words("1.5"), [2, 0, 0, 1, 1, 5000]);
+        assert_eq!(numeric_words("0.01"), [1, -1i16 as u16, 0, 2, 100]);
+        assert_eq!(numeric_words(".5"), [1, -1i16 as u16, 0, 1, 5000]);
+        assert_eq!(numeric_words("12345.678"), [3, 1, 0, 3, 1, 2345, 6780]);
+    }
+
+    #[test]
+    fn encode_numeric_special_values() {
+        assert_eq!(numeric_words("NaN"), [0, 0, 0xC000, 0]);
+        assert_eq!(numeric_words("Infinity"), [0, 0, 0xD000, 0]);
+        assert_eq!(numeric_words("-Infinity"), [0, 0, 0xF000, 0]);
+    }
+
+    #[test]
+    fn encode_numeric_invalid() {
+        assert!(encode_numeric("").is_none());
+        assert!(encode_numeric("-").is_none());
+        assert!(encode_numeric("1e5").is_none());
+        assert!(encode_numeric("1.2.3").is_none());
+        assert!(encode_numeric("nan").is_none());
+    }
+
+    #[test]
+    fn encode_binary_as_column_type() {
+        let encode = |value, ty| encode_binary(value, &ty).unwrap().unwrap().to_vec();
+        assert_eq!(encode(Value::Integer(1), PgType::INT8), 1i64.to_be_bytes());
+        assert_eq!(
+            encode(Value::Text("2".into()), PgType::INT8),
+            2i64.to_be_bytes()
+        );
+        assert_eq!(
+            encode(Value::Integer(1), PgType::FLOAT8),
+            1f64.to_be_bytes()
+        );
+        assert_eq!(
+            encode(Value::Real(1.5), PgType::NUMERIC),
+            encode_numeric("1.5").unwrap()
+        );
+        assert_eq!(encode(Value::Integer(1), PgType::TEXT), b"1");
+        assert!(encode_binary(Value::Text("a".into()), &PgType::INT8).is_err());
+        assert!(encode_binary(Value::Null, &PgType::INT8).unwrap().is_none());
+    }
+
+    #[test]
+    fn booleans_and_dates_are_text() {
+        for decltype in ["BOOLEAN", "date", "DateTime"] {
+            let ty = decltype.parse::<Type>().unwrap();
+            assert_eq!(PgType::from(ty), PgType::TEXT);
+        }
+    }
+
+    #[test]
+    fn constraint_violations_are_told_apart() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+             CREATE TABLE parent (id INTEGER PRIMARY KEY);
+             CREATE TABLE child (
+                 id INTEGER PRIMARY KEY,
+                 name TEXT UNIQUE NOT NULL,
+                 parent INTEGER REFERENCES parent(id),
+                 n INTEGER CHECK (n > 0)
+             );
+             INSERT INTO parent VALUES (1);
+             INSERT INTO child VALUES (1, 'a', 1, 1);",
+        )
+        .unwrap();
+
+        let code = |sql: &str| QueryError::from(conn.execute(sql, []).unwrap_err()).code;
+        assert!(matches!(
+            code("INSERT INTO child VALUES (1, 'b', 1, 1)"),
+            ErrorCode::UniqueViolation
+        ));
+        assert!(matches!(
+            code("INSERT INTO child VALUES (2, 'a', 1, 1)"),
+            ErrorCode::UniqueViolation
+        ));
+        assert!(matches!(
+            code("INSERT INTO child VALUES (2, 'b', 2, 1)"),
+            ErrorCode::ForeignKeyViolation
+        ));
+        assert!(matches!(
+            code("INSERT INTO child VALUES (2, NULL, 1, 1)"),
+            ErrorCode::NotNullViolation
+        ));
+        assert!(matches!(
+            code("INSERT INTO child VALUES (2, 'b', 1, 0)"),
+            ErrorCode::CheckViolation
+        ));
+    }
 }
@@ -1,11 +1,19 @@
+use std::borrow::Cow;
+use std::ffi::CString;
 use std::fmt;
+use std::time::Duration;
 
 use anyhow::Result;
-use sqlparser::{ast::Statement, dialect::SQLiteDialect, parser::Parser};
+use sqlparser::ast::{Expr, Statement, Value};
+use sqlparser::dialect::SQLiteDialect;
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Token, Tokenizer};
 
 /// A group of statements to be executed together.
 pub struct Statements {
     pub stmts: String,
+    /// The text of each statement, as SQLite splits them.
+    parts: Vec<String>,
     kinds: Vec<StmtKind>,
 }
 
@@ -15,44 +23,138 @@ impl fmt::Debug for Statements {
     }
 }
 
+/// The session variable holding the transaction timeout.
+pub const TXN_TIMEOUT_VAR: &str = "txn_timeout";
+
 /// Classify statement in categories of interest.
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone)]
 enum StmtKind {
     /// The begining of a transaction
     TxnBegin,
     /// The end of a transaction
     TxnEnd,
+    /// `SAVEPOINT <name>`
+    Savepoint(String),
+    /// `RELEASE [SAVEPOINT] <name>`
+    Release(String),
+    /// `ROLLBACK [TRANSACTION] TO [SAVEPOINT] <name>`
+    RollbackTo(String),
+    /// `SET txn_timeout = <seconds>`, with the value it was given.
+    SetTxnTimeout(Expr),
     Read,
     Write,
     Other,
 }
 
 impl StmtKind {
+    /// Classifies a single statement.
+    fn parse(sql: &str) -> Self {
+        let tokens = match Tokenizer::new(&SQLiteDialect {}, sql).tokenize() {
+            Ok(tokens) => tokens,
+            Err(_) => return Self::Other,
+        };
+        let significant = tokens
+            .iter()
+            .filter(|t| !matches!(t, Token::Whitespace(_) | Token::SemiColon))
+            .collect::<Vec<_>>();
+        Self::from_savepoint_tokens(&significant)
+            .or_else(|| {
+                let stmts = Parser::parse_sql(&SQLiteDialect {}, sql).ok()?;
+                stmts.first().map(Self::kind)
+            })
+            // We don't really care about `StmtKind::Other`, we keep it for conceptual simplicity.
+            .unwrap_or(Self::Other)
+    }
+
     fn kind(stmt: &Statement) -> Self {
         match stmt {
             Statement::StartTransaction { .. } => Self::TxnBegin,
-            Statement::SetTransaction { .. } => todo!("handle set txn"),
             Statement::Rollback { .. } | Statement::Commit { .. } => Self::TxnEnd,
-            Statement::Savepoint { .. } => todo!("handle savepoint"),
+            Statement::Savepoint { name } => Self::Savepoint(savepoint_name(&name.value)),
+            Statement::SetVariable {
+                variable, value, ..
+            } if variable.0.len() == 1
+                && variable.0[0].value.eq_ignore_ascii_case(TXN_TIMEOUT_VAR) =>
+            {
+                match value.as_slice() {
+                    [value] => Self::SetTxnTimeout(value.clone()),
+                    _ => Self::Other,
+                }
+            }
 
             Statement::Query(_) => Self::Read,
 
             Statement::Insert { .. } | Statement::Update { .. } | Statement::Delete { .. } => {
                 Self::Write
             }
-            Statement::Prepare { .. } => todo!(),
+            // `SET TRANSACTION` only changes the characteristics of the current transaction, and
+            // `PREPARE` doesn't run anything: neither affects the transaction state.
+            Statement::SetTransaction { .. } | Statement::Prepare { .. } => Self::Other,
             // FIXME: this contains lots of dialect specific nodes, when porting to Postges, check what's
             // in there.
             _ => Self::Other,
         }
     }
+
+    /// Classify the `RELEASE` and `ROLLBACK TO` statements, that sqlparser doesn't know how to
+    /// parse. `tokens` must not contain whitespaces.
+    fn from_savepoint_tokens(tokens: &[&Token]) -> Option<Self> {
+        let (first, rest) = tokens.split_first()?;
+        if is_keyword(first, "RELEASE") {
+            parse_savepoint_name(skip_keyword(rest, "SAVEPOINT")).map(Self::Release)
+        } else if is_keyword(first, "ROLLBACK") {
+            let rest = skip_keyword(rest, "TRANSACTION");
+            let (to, rest) = rest.split_first()?;
+            if !is_keyword(to, "TO") {
+                return None;
+            }
+            parse_savepoint_name(skip_keyword(rest, "SAVEPOINT")).map(Self::RollbackTo)
+        } else {
+            None
+        }
+    }
+}
+
+fn is_keyword(token: &Token, keyword: &str) -> bool {
+    match token {
+        Token::Word(w) => w.quote_style.is_none() && w.value.eq_ignore_ascii_case(keyword),
+        _ => false,
+    }
+}
+
+/// Skips the optional `keyword` at the start of `tokens`.
+fn skip_keyword<'a>(tokens: &'a [&'a Token], keyword: &str) -> &'a [&'a Token] {
+    match tokens.split_first() {
+        Some((first, rest)) if is_keyword(first, keyword) => rest,
+        _ => tokens,
+    }
+}
+
+/// Parses the savepoint name that must make up the rest of the statement.
+fn parse_savepoint_name(tokens: &[&Token]) -> Option<String> {
+    match tokens {
+        [Token::Word(w)] => Some(savepoint_name(&w.value)),
+        [Token::SingleQuotedString(s)] => Some(savepoint_name(s)),
+        _ => None,
+    }
+}
+
+/// Savepoint names are case insensitive.
+fn savepoint_name(name: &str) -> String {
+    name.to_lowercase()
 }
 
 /// The state of a transaction for a series of statement
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum State {
     /// The txn in an opened state
-    TxnOpened,
+    TxnOpened {
+        /// The names of the active savepoints, from the outermost to the innermost.
+        savepoints: Vec<String>,
+        /// Whether the txn was opened by its outermost savepoint rather than by a `BEGIN`, in
+        /// which case releasing that savepoint commits the txn.
+        implicit: bool,
+    },
     /// The txn in a closed state
     TxnClosed,
     /// This is the initial state of the state machine
@@ -61,14 +163,40 @@ pub enum State {
     Invalid,
 }
 
+impl State {
+    fn opened() -> Self {
+        Self::TxnOpened {
+            savepoints: Vec::new(),
+            implicit: false,
+        }
+    }
+}
+
 impl Statements {
     pub fn parse(s: String) -> Result<Self> {
-        // We don't really care about `StmtKind::Other`, we keep it for conceptual simplicity.
-        let kinds = Parser::parse_sql(&SQLiteDialect {}, &s)
-            .map(|statements| statements.iter().map(StmtKind::kind).collect())
-            .unwrap_or_else(|_| vec![StmtKind::Other]);
+        let parts = split_statements(&s)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>();
+        let kinds = parts.iter().map(|part| StmtKind::parse(part)).collect();
+
+        Ok(Self {
+            stmts: s,
+            parts,
+            kinds,
+        })
+    }
 
-        Ok(Self { stmts: s, kinds })
+    /// Splits the group into its statements, in order.
+    pub fn split(&self) -> impl Iterator<Item = Statements> + '_ {
+        self.parts
+            .iter()
+            .zip(&self.kinds)
+            .map(|(part, kind)| Statements {
+                stmts: part.clone(),
+                parts: vec![part.clone()],
+                kinds: vec![kind.clone()],
+            })
     }
 
     /// Given an initial state, returns the final state a transaction should be in after running these
@@ -77,24 +205,414 @@ impl Statements {
         self.kinds
             .iter()
             .fold(state, |old_state, current| match (old_state, current) {
-                (State::TxnOpened, StmtKind::TxnBegin) | (State::TxnClosed, StmtKind::TxnEnd) => {
-                    State::Invalid
+                (State::TxnOpened { .. }, StmtKind::TxnBegin)
+                | (State::TxnClosed, StmtKind::TxnEnd) => State::Invalid,
+                (State::TxnOpened { .. }, StmtKind::TxnEnd) => State::TxnClosed,
+                (State::TxnClosed | State::Start, StmtKind::TxnBegin) => State::opened(),
+                (State::TxnClosed | State::Start, StmtKind::Savepoint(name)) => State::TxnOpened {
+                    savepoints: vec![name.clone()],
+                    implicit: true,
+                },
+                (
+                    State::TxnOpened {
+                        mut savepoints,
+                        implicit,
+                    },
+                    StmtKind::Savepoint(name),
+                ) => {
+                    savepoints.push(name.clone());
+                    State::TxnOpened {
+                        savepoints,
+                        implicit,
+                    }
                 }
-                (State::TxnOpened, StmtKind::TxnEnd) => State::TxnClosed,
-                (State::TxnClosed, StmtKind::TxnBegin) => State::TxnOpened,
-                (state, StmtKind::Other | StmtKind::Write | StmtKind::Read) => state,
+                // Releasing a savepoint also releases all the savepoints opened after it.
+                (
+                    State::TxnOpened {
+                        mut savepoints,
+                        implicit,
+                    },
+                    StmtKind::Release(name),
+                ) => match savepoints.iter().rposition(|s| s == name) {
+                    Some(0) if implicit => State::TxnClosed,
+                    Some(i) => {
+                        savepoints.truncate(i);
+                        State::TxnOpened {
+                            savepoints,
+                            implicit,
+                        }
+                    }
+                    // no such savepoint: the statement fails, and leaves the txn untouched.
+                    None => State::TxnOpened {
+                        savepoints,
+                        implicit,
+                    },
+                },
+                // Rolling back to a savepoint cancels all the savepoints opened after it, but
+                // leaves it, and the txn, open.
+                (
+                    State::TxnOpened {
+                        mut savepoints,
+                        implicit,
+                    },
+                    StmtKind::RollbackTo(name),
+                ) => {
+                    if let Some(i) = savepoints.iter().rposition(|s| s == name) {
+                        savepoints.truncate(i + 1);
+                    }
+                    State::TxnOpened {
+                        savepoints,
+                        implicit,
+                    }
+                }
+                // There is no savepoint outside of a txn: these statements fail.
+                (
+                    state @ (State::TxnClosed | State::Start),
+                    StmtKind::Release(_) | StmtKind::RollbackTo(_),
+                ) => state,
+                (
+                    state,
+                    StmtKind::Other | StmtKind::Write | StmtKind::Read | StmtKind::SetTxnTimeout(_),
+                ) => state,
                 (State::Invalid, _) => State::Invalid,
-                (State::Start, StmtKind::TxnBegin) => State::TxnOpened,
                 (State::Start, StmtKind::TxnEnd) => State::TxnClosed,
             })
     }
 
+    /// If these statements are a single `SET txn_timeout = <seconds>`, returns the requested
+    /// transaction timeout, or an error if the value is not a positive number of seconds.
+    pub fn txn_timeout(&self) -> Option<Result<Duration>> {
+        let [StmtKind::SetTxnTimeout(value)] = self.kinds.as_slice() else {
+            return None;
+        };
+
+        let secs = match value {
+            Expr::Value(Value::Number(n, _)) => n.parse::<u64>().ok(),
+            Expr::Value(Value::SingleQuotedString(s)) => s.parse::<u64>().ok(),
+            _ => None,
+        };
+
+        Some(match secs {
+            Some(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+            _ => Err(anyhow::anyhow!(
+                "invalid {TXN_TIMEOUT_VAR} `{value}`: expected a positive number of seconds"
+            )),
+        })
+    }
+
     pub fn is_read_only(&self) -> bool {
         let state = self.state(State::Start);
-        let is_only_reads = self
-            .kinds
-            .iter()
-            .all(|k| matches!(k, StmtKind::Read | StmtKind::TxnEnd | StmtKind::TxnBegin));
+        let is_only_reads = self.kinds.iter().all(|k| {
+            matches!(
+                k,
+                StmtKind::Read
+                    | StmtKind::TxnEnd
+                    | StmtKind::TxnBegin
+                    | StmtKind::Savepoint(_)
+                    | StmtKind::Release(_)
+                    | StmtKind::RollbackTo(_)
+            )
+        });
         (state == State::Start || state == State::TxnClosed) && is_only_reads
     }
 }
+
+/// Splits `sql` into its statements, the way SQLite runs them: a statement ends at the first `;`
+/// that completes it, which excludes the `;` within literals, comments, and trigger bodies. Blank
+/// statements are skipped.
+pub fn split_statements(sql: &str) -> Vec<&str> {
+    let mut stmts = Vec::new();
+    let mut start = 0;
+    for (end, _) in sql.match_indices(';') {
+        let stmt = &sql[start..=end];
+        if is_complete(stmt) {
+            stmts.push(stmt);
+            start = end + 1;
+        }
+    }
+    stmts.push(&sql[start..]);
+    stmts.retain(|stmt| !is_blank(stmt));
+    stmts
+}
+
+fn is_complete(stmt: &str) -> bool {
+    match CString::new(stmt) {
+        Ok(stmt) => unsafe { rusqlite::ffi::sqlite3_complete(stmt.as_ptr()) != 0 },
+        // SQLite would stop at the NUL byte anyway.
+        Err(_) => false,
+    }
+}
+
+/// Whether `stmt` is only made of whitespaces, comments and semicolons.
+fn is_blank(stmt: &str) -> bool {
+    match Tokenizer::new(&SQLiteDialect {}, stmt).tokenize() {
+        Ok(tokens) => tokens
+            .iter()
+            .all(|t| matches!(t, Token::Whitespace(_) | Token::SemiColon)),
+        Err(_) => false,
+    }
+}
+
+/// Returns the first keyword of `stmt`, after any whitespace and comment.
+pub fn first_keyword(stmt: &str) -> Option<String> {
+    let tokens = Tokenizer::new(&SQLiteDialect {}, stmt).tokenize().ok()?;
+    let first = tokens
+        .into_iter()
+        .find(|t| !matches!(t, Token::Whitespace(_)))?;
+    match first {
+        Token::Word(w) => Some(w.value),
+        _ => None,
+    }
+}
+
+/// Rewrites the `$N` placeholders of PostgreSQL into SQLite's `?N`. Placeholders are only
+/// recognized outside of literals, quoted identifiers and comments. Returns the rewritten
+/// statement, and the number of parameters it takes, which is the highest placeholder index.
+pub fn rewrite_placeholders(sql: &str) -> (Cow<'_, str>, usize) {
+    let bytes = sql.as_bytes();
+    let mut rewritten = String::new();
+    // Start of the part of `sql` that is not copied to `rewritten` yet.
+    let mut copied = 0;
+    let mut param_count = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        i = match bytes[i] {
+            quote @ (b'\'' | b'"' | b'`') => skip_quoted(bytes, i, quote),
+            b'[' => find(bytes, i + 1, b"]"),
+            b'-' if bytes.get(i + 1) == Some(&b'-') => find(bytes, i + 2, b"\n"),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => find(bytes, i + 2, b"*/"),
+            b'$' if bytes.get(i + 1).map_or(false, u8::is_ascii_digit) => {
+                let end = skip_while(bytes, i + 1, |b| b.is_ascii_digit());
+                let index = sql[i + 1..end].parse().unwrap_or(usize::MAX);
+                param_count = param_count.max(index);
+                rewritten.push_str(&sql[copied..i]);
+                rewritten.push('?');
+                copied = i + 1;
+                end
+            }
+            // A `$` within an identifier or a keyword is not a placeholder.
+            b if is_ident_byte(b) => skip_while(bytes, i, is_ident_byte),
+            _ => i + 1,
+        };
+    }
+
+    if copied == 0 {
+        return (Cow::Borrowed(sql), param_count);
+    }
+    rewritten.push_str(&sql[copied..]);
+    (Cow::Owned(rewritten), param_count)
+}
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || !b.is_ascii()
+}
+
+fn skip_while(bytes: &[u8], start: usize, f: impl Fn(u8) -> bool) -> usize {
+    bytes[start..]
+        .iter()
+        .position(|b| !f(*b))
+        .map_or(bytes.len(), |n| start + n)
+}
+
+/// Returns the index past the first occurrence of `pattern` from `start`, or the end of `bytes`.
+fn find(bytes: &[u8], start: usize, pattern: &[u8]) -> usize {
+    bytes[start.min(bytes.len())..]
+        .windows(pattern.len())
+        .position(|w| w == pattern)
+        .map_or(bytes.len(), |n| start + n + pattern.len())
+}
+
+/// Returns the index past the quoted literal or identifier starting at `start`. Quotes within it
+/// are escaped by doubling them.
+fn skip_quoted(bytes: &[u8], start: usize, quote: u8) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if bytes[i] == quote {
+            if bytes.get(i + 1) != Some(&quote) {
+                return i + 1;
+            }
+            i += 1;
+        }
+        i += 1;
+    }
+
+    bytes.len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state_after(stmts: &[&str]) -> State {
+        stmts.iter().fold(State::Start, |state, s| {
+            Statements::parse(s.to_string()).unwrap().state(state)
+        })
+    }
+
+    #[test]
+    fn rewrite_pg_placeholders() {
+        let (sql, param_count) = rewrite_placeholders(
+            "select $1, 'it''s $2', \"$3\", a$4, [$5] -- $6\n/* $7 */ $10, $1",
+        );
+        assert_eq!(
+            sql,
+            "select ?1, 'it''s $2', \"$3\", a$4, [$5] -- $6\n/* $7 */ ?10, ?1"
+        );
+        assert_eq!(param_count, 10);
+
+        let (sql, param_count) = rewrite_placeholders("select $name, '$1");
+        assert_eq!(sql, "select $name, '$1");
+        assert_eq!(param_count, 0);
+    }
+
+    #[test]
+    fn classify_savepoint_statements() {
+        let stmts = Statements::parse(
+            "savepoint a; release savepoint A; rollback transaction to \"b\"; select 1".into(),
+        )
+        .unwrap();
+        assert_eq!(
+            stmts.kinds,
+            vec![
+                StmtKind::Savepoint("a".into()),
+                StmtKind::Release("a".into()),
+                StmtKind::RollbackTo("b".into()),
+                StmtKind::Read,
+            ]
+        );
+    }
+
+    #[test]
+    fn savepoint_opens_implicit_txn() {
+        assert_eq!(
+            state_after(&["savepoint a", "savepoint b"]),
+            State::TxnOpened {
+                savepoints: vec!["a".into(), "b".into()],
+                implicit: true,
+            }
+        );
+        assert_eq!(
+            state_after(&["savepoint a", "savepoint b", "release a"]),
+            State::TxnClosed
+        );
+        assert_eq!(
+            state_after(&["savepoint a", "savepoint b", "rollback"]),
+            State::TxnClosed
+        );
+    }
+
+    #[test]
+    fn nested_savepoints_in_txn() {
+        assert_eq!(
+            state_after(&[
+                "begin",
+                "savepoint a",
+                "savepoint b",
+                "savepoint c",
+                "rollback to b"
+            ]),
+            State::TxnOpened {
+                savepoints: vec!["a".into(), "b".into()],
+                implicit: false,
+            }
+        );
+        assert_eq!(
+            state_after(&["begin", "savepoint a", "savepoint b", "release a"]),
+            State::opened()
+        );
+        assert_eq!(
+            state_after(&["begin", "savepoint a", "release a", "commit"]),
+            State::TxnClosed
+        );
+    }
+
+    #[test]
+    fn unknown_savepoint_leaves_state_untouched() {
+        assert_eq!(state_after(&["release a"]), State::Start);
+        assert_eq!(
+            state_after(&["savepoint a", "rollback to b"]),
+            State::TxnOpened {
+                savepoints: vec!["a".into()],
+                implicit: true,
+            }
+        );
+    }
+
+    #[test]
+    fn set_txn_timeout() {
+        let timeout = |s: &str| {
+            Statements::parse(s.into())
+                .unwrap()
+                .txn_timeout()
+                .map(|r| r.ok())
+        };
+        assert_eq!(
+            timeout("SET txn_timeout = 30"),
+            Some(Some(Duration::from_secs(30)))
+        );
+        assert_eq!(
+            timeout("set TXN_TIMEOUT to '2'"),
+            Some(Some(Duration::from_secs(2)))
+        );
+        assert_eq!(timeout("set txn_timeout = 0"), Some(None));
+        assert_eq!(timeout("set txn_timeout = 'abc'"), Some(None));
+        assert_eq!(timeout("set txn_timeout = 30; select 1"), None);
+        assert_eq!(timeout("select 1"), None);
+    }
+
+    #[test]
+    fn split_batches() {
+        assert_eq!(
+            split_statements("insert into t values ('a;b'); -- c;\n select 1; ;"),
+            vec!["insert into t values ('a;b');", " -- c;\n select 1;"]
+        );
+        assert_eq!(
+            split_statements(
+                "create trigger tr after insert on t begin delete from u; end; select \"x;\""
+            ),
+            vec![
+                "create trigger tr after insert on t begin delete from u; end;",
+                " select \"x;\""
+            ]
+        );
+        assert!(split_statements(" /* nothing */ ").is_empty());
+
+        let stmts = Statements::parse("begin; insert into t values (1); commit".into()).unwrap();
+        assert_eq!(
+            stmts.split().map(|s| s.kinds).collect::<Vec<_>>(),
+            vec![
+                vec![StmtKind::TxnBegin],
+                vec![StmtKind::Write],
+                vec![StmtKind::TxnEnd]
+            ]
+        );
+    }
+
+    #[test]
+    fn first_keywords() {
+        assert_eq!(
+            first_keyword("create table t (x)").as_deref(),
+            Some("create")
+        );
+        assert_eq!(
+            first_keyword("-- comment\n /* block */ BEGIN").as_deref(),
+            Some("BEGIN")
+        );
+        assert_eq!(first_keyword(" /* nothing */ "), None);
+        assert_eq!(first_keyword("(select 1)"), None);
+    }
+
+    #[test]
+    fn set_transaction_and_prepare() {
+        assert_eq!(
+            state_after(&["begin", "set transaction read only"]),
+            State::opened()
+        );
+        assert_eq!(
+            Statements::parse("prepare foo as select 1".into())
+                .unwrap()
+                .kinds,
+            vec![StmtKind::Other]
+        );
+    }
+}
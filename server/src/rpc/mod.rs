@@ -1,6 +1,9 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 
+use tokio_util::sync::CancellationToken;
+
 use crate::database::service::DbFactory;
 use crate::rpc::proxy::proxy_rpc::proxy_server::ProxyServer;
 use crate::rpc::proxy::ProxyService;
@@ -15,6 +18,8 @@ pub async fn run_rpc_server<F>(
     addr: SocketAddr,
     factory: F,
     logger: Arc<WalLogger>,
+    db_path: PathBuf,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<()>
 where
     F: DbFactory + 'static,
@@ -22,13 +27,13 @@ where
     F::Future: Sync,
 {
     let proxy_service = ProxyService::new(factory);
-    let logger_service = WalLogService::new(logger);
+    let logger_service = WalLogService::new(logger, db_path, shutdown.clone());
 
     tracing::info!("serving write proxy server at {addr}");
     tonic::transport::Server::builder()
         .add_service(ProxyServer::new(proxy_service))
         .add_service(WalLogServer::new(logger_service))
-        .serve(addr)
+        .serve_with_shutdown(addr, shutdown.cancelled())
         .await?;
 
     Ok(())
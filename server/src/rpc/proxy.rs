@@ -1,16 +1,20 @@
 use std::collections::HashMap;
+use std::future::ready;
+use std::pin::Pin;
 
 use async_lock::{RwLock, RwLockUpgradableReadGuard};
+use futures::{stream, Stream, StreamExt};
 use uuid::Uuid;
 
 use crate::database::service::DbFactory;
 use crate::database::Database;
-use crate::query::{ErrorCode, QueryResponse, QueryResult};
+use crate::query::{Params, QueryResponse, QueryResult};
 use crate::query_analysis::Statements;
 use proxy_rpc::proxy_server::Proxy;
 use proxy_rpc::{
-    error::ErrorCode as RpcErrorCode, query_result::Result as RpcResult, Ack, DisconnectMessage,
-    Error as RpcError, QueryResult as RpcQueryResult, SimpleQuery,
+    describe_result::DescribeResult as DescribeOutcome, query_response_message::Message,
+    query_result::Result as RpcResult, Ack, DescribeRequest, DescribeResult, DisconnectMessage,
+    InterruptMessage, QueryResponseMessage, QueryResult as RpcQueryResult, ResultRows, SimpleQuery,
 };
 
 pub mod proxy_rpc {
@@ -30,40 +34,88 @@ impl<F: DbFactory> ProxyService<F> {
             factory,
         }
     }
+
+    /// Returns the database session of a client, creating it on its first request.
+    async fn client_db(&self, client_id: Uuid) -> F::Db
+    where
+        F::Db: Clone,
+    {
+        let lock = self.clients.upgradable_read().await;
+        match lock.get(&client_id) {
+            Some(db) => db.clone(),
+            None => {
+                let db = self.factory.create().await.unwrap();
+                tracing::debug!("connected: {client_id}");
+                let mut lock = RwLockUpgradableReadGuard::upgrade(lock).await;
+                lock.insert(client_id, db.clone());
+                db
+            }
+        }
+    }
+}
+
+/// Maximum number of rows sent in a single message, when streaming the rows of a result set.
+const MAX_ROWS_PER_MESSAGE: usize = 256;
+
+type QueryResponseStream =
+    Pin<Box<dyn Stream<Item = Result<QueryResponseMessage, tonic::Status>> + Send>>;
+
+fn response_message(message: Message) -> Result<QueryResponseMessage, tonic::Status> {
+    Ok(QueryResponseMessage {
+        message: Some(message),
+    })
 }
 
-impl From<QueryResult> for RpcQueryResult {
-    fn from(other: QueryResult) -> Self {
-        match other {
-            Ok(QueryResponse::ResultSet(q)) => {
-                let rows = q.into();
-                RpcQueryResult {
-                    error: None,
-                    rows: Some(rows),
-                    result: RpcResult::Ok.into(),
+/// Streams the results of a query: each result is followed by its rows, sent in batches as they
+/// are read.
+fn stream_results(results: Vec<QueryResult>) -> QueryResponseStream {
+    let messages = stream::iter(results).flat_map(|result| match result {
+        Ok(QueryResponse::ResultSet(set)) => {
+            let (header, rows) = set.into_rpc();
+            let result = RpcQueryResult {
+                error: None,
+                rows: Some(header),
+                result: RpcResult::Ok.into(),
+            };
+            let rows = rows.ready_chunks(MAX_ROWS_PER_MESSAGE).flat_map(|chunk| {
+                let mut rows = Vec::with_capacity(chunk.len());
+                let mut messages = Vec::new();
+                for row in chunk {
+                    match row {
+                        Ok(row) => rows.push(row.into()),
+                        Err(e) => {
+                            messages.push(Message::Error(e.into()));
+                            break;
+                        }
+                    }
                 }
-            }
-            Err(e) => {
-                let code = match e.code {
-                    ErrorCode::SQLError => RpcErrorCode::SqlError,
-                    ErrorCode::TxBusy => RpcErrorCode::TxBusy,
-                    ErrorCode::TxTimeout => RpcErrorCode::TxTimeout,
-                    ErrorCode::Internal => RpcErrorCode::Internal,
-                };
-
-                let err = RpcError {
-                    code: code.into(),
-                    message: e.msg,
-                };
-
-                RpcQueryResult {
-                    error: Some(err),
-                    rows: None,
-                    result: RpcResult::Err.into(),
+                if !rows.is_empty() {
+                    let rows = ResultRows {
+                        rows,
+                        ..Default::default()
+                    };
+                    messages.insert(0, Message::Rows(rows));
                 }
-            }
+                stream::iter(messages.into_iter().map(response_message))
+            });
+            stream::once(ready(response_message(Message::Result(result))))
+                .chain(rows)
+                .boxed()
         }
-    }
+        Ok(QueryResponse::Description(_)) => {
+            unreachable!("executing statements does not describe them")
+        }
+        Err(e) => {
+            let result = RpcQueryResult {
+                error: Some(e.into()),
+                rows: None,
+                result: RpcResult::Err.into(),
+            };
+            stream::once(ready(response_message(Message::Result(result)))).boxed()
+        }
+    });
+
+    Box::pin(messages)
 }
 
 #[tonic::async_trait]
@@ -73,30 +125,70 @@ where
     F::Db: Send + Sync + Clone,
     F::Future: Send + Sync,
 {
+    type QueryStream = QueryResponseStream;
+
     async fn query(
         &self,
         req: tonic::Request<SimpleQuery>,
-    ) -> Result<tonic::Response<RpcQueryResult>, tonic::Status> {
-        let SimpleQuery { client_id, q } = req.into_inner();
+    ) -> Result<tonic::Response<Self::QueryStream>, tonic::Status> {
+        let SimpleQuery {
+            client_id,
+            q,
+            params,
+            named_params,
+        } = req.into_inner();
         let client_id = Uuid::from_slice(&client_id).unwrap();
-
-        let lock = self.clients.upgradable_read().await;
-        let db = match lock.get(&client_id) {
-            Some(db) => db.clone(),
-            None => {
-                let db = self.factory.create().await.unwrap();
-                tracing::debug!("connected: {client_id}");
-                let mut lock = RwLockUpgradableReadGuard::upgrade(lock).await;
-                lock.insert(client_id, db.clone());
-                db
-            }
-        };
+        let db = self.client_db(client_id).await;
 
         tracing::debug!("executing request for {client_id}: {q}");
         let stmts = Statements::parse(q).unwrap();
-        let result = db.execute(stmts, Vec::new()).await;
+        let params = if named_params.is_empty() {
+            Params::Positional(params.into_iter().map(Into::into).collect())
+        } else {
+            Params::Named(
+                named_params
+                    .into_iter()
+                    .map(|(k, v)| (k, v.into()))
+                    .collect(),
+            )
+        };
+        let results = db.execute(stmts, params).await;
+
+        Ok(tonic::Response::new(stream_results(results)))
+    }
 
-        Ok(tonic::Response::new(result.into()))
+    async fn describe(
+        &self,
+        req: tonic::Request<DescribeRequest>,
+    ) -> Result<tonic::Response<DescribeResult>, tonic::Status> {
+        let DescribeRequest { client_id, stmt } = req.into_inner();
+        let client_id = Uuid::from_slice(&client_id).unwrap();
+        let db = self.client_db(client_id).await;
+
+        tracing::debug!("describing statement for {client_id}: {stmt}");
+        let describe_result = match db.describe(stmt).await {
+            Ok(description) => DescribeOutcome::Description(description.into()),
+            Err(e) => DescribeOutcome::Error(e.into()),
+        };
+
+        Ok(tonic::Response::new(DescribeResult {
+            describe_result: Some(describe_result),
+        }))
+    }
+
+    async fn interrupt(
+        &self,
+        msg: tonic::Request<InterruptMessage>,
+    ) -> Result<tonic::Response<Ack>, tonic::Status> {
+        let InterruptMessage { client_id } = msg.into_inner();
+        let client_id = Uuid::from_slice(&client_id).unwrap();
+
+        tracing::debug!("interrupting statement of {client_id}");
+        if let Some(db) = self.clients.read().await.get(&client_id) {
+            db.interrupt();
+        }
+
+        Ok(tonic::Response::new(Ack {}))
     }
 
     //TODO: also handle cleanup on peer disconnect
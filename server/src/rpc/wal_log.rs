@@ -3,24 +3,74 @@ pub mod wal_log_rpc {
     tonic::include_proto!("wal_log");
 }
 
+use std::fs::File;
+use std::os::unix::prelude::FileExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use crate::wal_logger::{WalLogEntry, WalLogger};
+use crate::wal_logger::{Compression, WalLogEntry, WalLogger};
 
+use anyhow::ensure;
+use bytes::{Bytes, BytesMut};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags};
+use tokio::sync::mpsc::Sender;
 use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
 use tonic::Status;
+use uuid::Uuid;
 use wal_log_rpc::wal_log_server::WalLog;
 
-use self::wal_log_rpc::{wal_log_entry::Payload, Frame, LogOffset, WalLogEntry as RpcWalLogEntry};
+use self::wal_log_rpc::{
+    wal_log_entry::Payload, Compression as RpcCompression, DatabaseInfo, DatabaseInfoRequest,
+    Frame, LogOffset, SnapshotRequest, WalLogEntry as RpcWalLogEntry,
+};
+
+/// Page size of the databases SQLite creates.
+const DEFAULT_PAGE_SIZE: usize = 4096;
 
 pub struct WalLogService {
     logger: Arc<WalLogger>,
+    db_path: PathBuf,
+    /// Cancelled when the server shuts down, to close the log streams, that never end otherwise.
+    shutdown: CancellationToken,
+}
+
+impl From<RpcCompression> for Compression {
+    fn from(other: RpcCompression) -> Self {
+        match other {
+            RpcCompression::None => Compression::None,
+            RpcCompression::Zstd => Compression::Zstd,
+        }
+    }
 }
 
-impl From<(u64, WalLogEntry)> for RpcWalLogEntry {
-    fn from((index, entry): (u64, WalLogEntry)) -> Self {
+impl From<Compression> for RpcCompression {
+    fn from(other: Compression) -> Self {
+        match other {
+            Compression::None => RpcCompression::None,
+            Compression::Zstd => RpcCompression::Zstd,
+        }
+    }
+}
+
+impl RpcWalLogEntry {
+    /// Builds the RPC representation of the log entry at `index`, sent when `last_log_index` was
+    /// the index of the last entry of the log. The page of a frame is compressed with
+    /// `compression`.
+    fn new(index: u64, last_log_index: u64, entry: WalLogEntry, compression: Compression) -> Self {
         let payload = match entry {
-            WalLogEntry::Frame { page_no, data } => Payload::Frame(Frame { page_no, data }),
+            WalLogEntry::Frame { page_no, data } => {
+                let (compression, data) = match compression.compress(&data) {
+                    Some(compressed) => (compression, compressed.into()),
+                    None => (Compression::None, data),
+                };
+                Payload::Frame(Frame {
+                    page_no,
+                    data,
+                    compression: RpcCompression::from(compression).into(),
+                })
+            }
             WalLogEntry::Commit {
                 page_size,
                 size_after,
@@ -36,35 +86,213 @@ impl From<(u64, WalLogEntry)> for RpcWalLogEntry {
         Self {
             index,
             payload: Some(payload),
+            last_log_index,
         }
     }
 }
 
 impl WalLogService {
-    pub fn new(logger: Arc<WalLogger>) -> Self {
-        Self { logger }
+    pub fn new(logger: Arc<WalLogger>, db_path: PathBuf, shutdown: CancellationToken) -> Self {
+        Self {
+            logger,
+            db_path,
+            shutdown,
+        }
     }
 
-    fn stream_pages(&self, start_offset: usize) -> ReceiverStream<Result<RpcWalLogEntry, Status>> {
+    /// Streams log entries starting at `start_offset`, with their pages compressed with
+    /// `compression`. The stream is kept open once it has caught up with the log, and new entries
+    /// are pushed as soon as they are committed.
+    fn stream_pages(
+        &self,
+        start_offset: usize,
+        compression: Compression,
+    ) -> ReceiverStream<Result<RpcWalLogEntry, Status>> {
         let logger = self.logger.clone();
+        let shutdown = self.shutdown.clone();
         let (sender, receiver) = tokio::sync::mpsc::channel(64);
-        tokio::task::spawn_blocking(move || {
+        tokio::spawn(async move {
+            // subscribe before reading, so that no commit can be missed.
+            let mut new_frames = logger.subscribe();
             let mut offset = start_offset;
             loop {
-                match logger.get_entry(offset) {
-                    Ok(None) => break,
+                // reading and compressing an entry blocks: it is done on the blocking pool, one
+                // entry at a time.
+                let read = tokio::task::spawn_blocking({
+                    let logger = logger.clone();
+                    move || {
+                        let entry = logger.get_entry(offset)?.map(|entry| {
+                            let last_log_index = logger.next_offset() as u64 - 1;
+                            RpcWalLogEntry::new(offset as u64, last_log_index, entry, compression)
+                        });
+                        anyhow::Ok(entry)
+                    }
+                });
+                match read
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|entry| entry)
+                {
+                    Ok(None) if offset < logger.start_offset() => {
+                        let _ = sender.send(Err(compacted_error(offset))).await;
+                        break;
+                    }
+                    Ok(None) => {
+                        // wait for new entries to be committed. Stop if the logger goes away, if
+                        // the replica hangs up, or if the server shuts down in the meantime.
+                        let has_new_frames = tokio::select! {
+                            changed = new_frames.changed() => changed.is_ok(),
+                            _ = sender.closed() => false,
+                            _ = shutdown.cancelled() => false,
+                        };
+                        if !has_new_frames {
+                            break;
+                        }
+                    }
                     Ok(Some(entry)) => {
-                        let entry: RpcWalLogEntry = (offset as u64, entry).into();
-                        let _ = sender.blocking_send(Ok(entry));
+                        if sender.send(Ok(entry)).await.is_err() {
+                            // the replica hung up.
+                            break;
+                        }
                         offset += 1;
                     }
-                    Err(_) => todo!(),
+                    Err(e) => {
+                        let _ = sender.send(Err(Status::internal(e.to_string()))).await;
+                        break;
+                    }
                 }
             }
         });
 
         ReceiverStream::new(receiver)
     }
+
+    fn stream_snapshot(
+        &self,
+        compression: Compression,
+    ) -> ReceiverStream<Result<RpcWalLogEntry, Status>> {
+        let logger = self.logger.clone();
+        let db_path = self.db_path.clone();
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = send_snapshot(&logger, &db_path, compression, &sender) {
+                tracing::error!("failed to send database snapshot: {e}");
+                let _ = sender.blocking_send(Err(Status::internal(e.to_string())));
+            }
+        });
+
+        ReceiverStream::new(receiver)
+    }
+}
+
+fn compacted_error(offset: usize) -> Status {
+    Status::out_of_range(format!(
+        "log entry {offset} was compacted, a snapshot is required"
+    ))
+}
+
+/// Sends a snapshot of the database as a single transaction, whose entries all carry the index of
+/// the last log entry included in the snapshot.
+///
+/// The snapshot contains exactly the transactions logged up to that index: a replica must not
+/// replay a transaction on top of a snapshot that already contains it, or its readers could see
+/// the pages written by later transactions next to the pages of earlier ones.
+fn send_snapshot(
+    logger: &WalLogger,
+    db_path: &Path,
+    compression: Compression,
+    sender: &Sender<Result<RpcWalLogEntry, Status>>,
+) -> anyhow::Result<()> {
+    let snapshot_path = db_path.with_extension(format!("snapshot-{}", Uuid::new_v4()));
+    let result = take_snapshot(logger, db_path, &snapshot_path).and_then(|(page_size, index)| {
+        send_snapshot_pages(&snapshot_path, page_size, index, compression, sender)
+    });
+    let _ = std::fs::remove_file(&snapshot_path);
+
+    result
+}
+
+/// Returns the page size of the database at `db_path`. A database that wasn't created yet will
+/// have the default page size.
+fn database_page_size(db_path: &Path) -> anyhow::Result<usize> {
+    if !db_path.exists() {
+        return Ok(DEFAULT_PAGE_SIZE);
+    }
+    let db = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let page_size = db.pragma_query_value(None, "page_size", |row| row.get(0))?;
+
+    Ok(page_size)
+}
+
+/// Copies the database at `db_path` to `snapshot_path`, and returns its page size along with the
+/// index of the last log entry included in the copy.
+fn take_snapshot(
+    logger: &WalLogger,
+    db_path: &Path,
+    snapshot_path: &Path,
+) -> anyhow::Result<(usize, u64)> {
+    let db = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    // The read transaction the copy is made from is started while no transaction is being
+    // committed, so that it sees the same transactions as the log.
+    let next_offset = logger.with_logged_commits(|next_offset| {
+        db.execute_batch("BEGIN")?;
+        db.query_row("SELECT count(*) FROM sqlite_schema", [], |_| Ok(()))?;
+        anyhow::Ok(next_offset)
+    })?;
+    ensure!(
+        next_offset > 0,
+        "the log is empty, there is nothing to snapshot"
+    );
+    let mut snapshot = Connection::open(snapshot_path)?;
+    {
+        // copy all the pages in a single step, for the snapshot to be consistent.
+        let backup = Backup::new(&db, &mut snapshot)?;
+        ensure!(
+            backup.step(-1)? == StepResult::Done,
+            "failed to copy the database"
+        );
+    }
+    let page_size: usize = snapshot.pragma_query_value(None, "page_size", |row| row.get(0))?;
+
+    Ok((page_size, next_offset as u64 - 1))
+}
+
+fn send_snapshot_pages(
+    snapshot_path: &Path,
+    page_size: usize,
+    index: u64,
+    compression: Compression,
+    sender: &Sender<Result<RpcWalLogEntry, Status>>,
+) -> anyhow::Result<()> {
+    let file = File::open(snapshot_path)?;
+    let len = file.metadata()?.len() as usize;
+    ensure!(len % page_size == 0, "invalid snapshot size: {len}");
+    let page_count = len / page_size;
+
+    for i in 0..page_count {
+        let mut data = BytesMut::zeroed(page_size);
+        file.read_exact_at(&mut data, (i * page_size) as _)?;
+        let frame = WalLogEntry::Frame {
+            page_no: i as u32 + 1,
+            data: data.freeze(),
+        };
+        let frame = RpcWalLogEntry::new(index, index, frame, compression);
+        if sender.blocking_send(Ok(frame)).is_err() {
+            // the replica hung up.
+            return Ok(());
+        }
+    }
+
+    let commit = WalLogEntry::Commit {
+        page_size: page_size as _,
+        size_after: page_count as _,
+        is_commit: true,
+        sync_flags: 0,
+    };
+    let commit = RpcWalLogEntry::new(index, index, commit, compression);
+    let _ = sender.blocking_send(Ok(commit));
+
+    Ok(())
 }
 
 #[tonic::async_trait]
@@ -74,8 +302,38 @@ impl WalLog for WalLogService {
         &self,
         req: tonic::Request<LogOffset>,
     ) -> Result<tonic::Response<Self::LogEntriesStream>, Status> {
-        let start_offset = req.into_inner().start_offset;
-        let stream = self.stream_pages(start_offset as _);
+        let req = req.into_inner();
+        let start_offset = req.start_offset as usize;
+        if start_offset < self.logger.start_offset() {
+            return Err(compacted_error(start_offset));
+        }
+        let stream = self.stream_pages(start_offset, req.compression().into());
         Ok(tonic::Response::new(stream))
     }
+
+    type SnapshotStream = ReceiverStream<Result<RpcWalLogEntry, Status>>;
+    async fn snapshot(
+        &self,
+        req: tonic::Request<SnapshotRequest>,
+    ) -> Result<tonic::Response<Self::SnapshotStream>, Status> {
+        let compression = req.into_inner().compression().into();
+        Ok(tonic::Response::new(self.stream_snapshot(compression)))
+    }
+
+    async fn database_info(
+        &self,
+        _req: tonic::Request<DatabaseInfoRequest>,
+    ) -> Result<tonic::Response<DatabaseInfo>, Status> {
+        // The page size of the log is the one of the database, once a transaction was logged.
+        let page_size = match self.logger.page_size() {
+            Some(page_size) => page_size,
+            None => database_page_size(&self.db_path)
+                .map_err(|e| Status::internal(format!("failed to read the page size: {e}")))?,
+        };
+
+        Ok(tonic::Response::new(DatabaseInfo {
+            page_size: page_size as _,
+            database_id: Bytes::copy_from_slice(&self.logger.database_id().to_le_bytes()),
+        }))
+    }
 }
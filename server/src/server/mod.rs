@@ -2,6 +2,7 @@ use std::future::poll_fn;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
+use std::time::Duration;
 use std::{fmt, io};
 
 use anyhow::Result;
@@ -10,14 +11,19 @@ use futures::{stream::FuturesUnordered, StreamExt};
 use futures::{Future, Stream};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
 use tower::Service;
 
+use crate::metrics;
 use crate::server::ws::WsStreamAdapter;
 
 use self::tcp::TcpAdapter;
 use self::ws::WsAdapter;
 
 mod tcp;
+pub mod tls;
 mod ws;
 
 type Listener = Box<dyn Stream<Item = io::Result<(NetStream, SocketAddr)>> + Unpin>;
@@ -40,15 +46,26 @@ impl Server {
         Ok(self)
     }
 
-    pub async fn bind_ws(&mut self, addr: impl ToSocketAddrs) -> Result<&mut Self> {
+    /// Binds a websocket listener. If a TLS acceptor is passed, connections are served over wss.
+    pub async fn bind_ws(
+        &mut self,
+        addr: impl ToSocketAddrs,
+        tls: Option<TlsAcceptor>,
+    ) -> Result<&mut Self> {
         let listener = TcpListener::bind(addr).await?;
-        self.listeners.push(Box::new(WsAdapter::new(listener)));
+        self.listeners.push(Box::new(WsAdapter::new(listener, tls)));
 
         Ok(self)
     }
 
-    pub async fn serve<S>(self, mut make_svc: S)
-    where
+    /// Serves connections until `shutdown` is cancelled. The listeners are then closed, and open
+    /// connections are given `grace_period` to terminate before they are dropped.
+    pub async fn serve<S>(
+        self,
+        mut make_svc: S,
+        shutdown: CancellationToken,
+        grace_period: Duration,
+    ) where
         S: Service<(NetStream, SocketAddr)>,
         S::Future: Send,
         S::Error: fmt::Display,
@@ -57,6 +74,7 @@ impl Server {
         let mut listeners = select_all(self.listeners);
         loop {
             tokio::select! {
+                _ = shutdown.cancelled() => break,
                 conn = listeners.next() => {
                     match conn {
                         Some(Ok((stream, addr))) => {
@@ -67,6 +85,7 @@ impl Server {
                             tracing::info!("new connection: {addr}");
                             let fut = make_svc.call((stream , addr));
                             connections.push(fut);
+                            metrics::ACTIVE_CONNECTIONS.set(connections.len() as _);
                         }
                         _ => break,
                     }
@@ -75,8 +94,26 @@ impl Server {
                     if let Some(Err(e)) = disconnect {
                         tracing::error!("connection exited with error: {e}")
                     }
+                    metrics::ACTIVE_CONNECTIONS.set(connections.len() as _);
+                }
+            }
+        }
+
+        drop(listeners);
+        tracing::info!("waiting for {} connections to terminate", connections.len());
+        let drain = async {
+            while let Some(disconnect) = connections.next().await {
+                if let Err(e) = disconnect {
+                    tracing::error!("connection exited with error: {e}")
                 }
+                metrics::ACTIVE_CONNECTIONS.set(connections.len() as _);
             }
+        };
+        if tokio::time::timeout(grace_period, drain).await.is_err() {
+            tracing::warn!(
+                "dropping {} connections after the shutdown grace period",
+                connections.len()
+            );
         }
     }
 }
@@ -94,8 +131,38 @@ pin_project_lite::pin_project! {
         Ws {
             #[pin]
             stream: WsStreamAdapter<TcpStream>,
+        },
+        Tls {
+            #[pin]
+            stream: Box<TlsStream<TcpStream>>,
+        },
+        Wss {
+            #[pin]
+            stream: Box<WsStreamAdapter<TlsStream<TcpStream>>>,
+        },
+    }
+}
+
+impl NetStream {
+    /// Performs the server side of a TLS handshake over a plain TCP stream.
+    pub async fn upgrade_tls(self, acceptor: &TlsAcceptor) -> io::Result<Self> {
+        match self {
+            NetStream::Tcp { stream } => {
+                let stream = acceptor.accept(stream).await?;
+                Ok(NetStream::Tls {
+                    stream: Box::new(stream),
+                })
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only plain TCP streams can be upgraded to TLS",
+            )),
         }
     }
+
+    pub fn is_secure(&self) -> bool {
+        matches!(self, NetStream::Tls { .. } | NetStream::Wss { .. })
+    }
 }
 
 impl AsyncRead for NetStream {
@@ -107,6 +174,8 @@ impl AsyncRead for NetStream {
         match self.project() {
             NetStreamProj::Tcp { stream } => stream.poll_read(cx, buf),
             NetStreamProj::Ws { stream } => stream.poll_read(cx, buf),
+            NetStreamProj::Tls { stream } => stream.poll_read(cx, buf),
+            NetStreamProj::Wss { stream } => stream.poll_read(cx, buf),
         }
     }
 }
@@ -120,6 +189,8 @@ impl AsyncWrite for NetStream {
         match self.project() {
             NetStreamProj::Tcp { stream } => stream.poll_write(cx, buf),
             NetStreamProj::Ws { stream } => stream.poll_write(cx, buf),
+            NetStreamProj::Tls { stream } => stream.poll_write(cx, buf),
+            NetStreamProj::Wss { stream } => stream.poll_write(cx, buf),
         }
     }
 
@@ -127,6 +198,8 @@ impl AsyncWrite for NetStream {
         match self.project() {
             NetStreamProj::Tcp { stream } => stream.poll_flush(cx),
             NetStreamProj::Ws { stream } => stream.poll_flush(cx),
+            NetStreamProj::Tls { stream } => stream.poll_flush(cx),
+            NetStreamProj::Wss { stream } => stream.poll_flush(cx),
         }
     }
 
@@ -137,6 +210,8 @@ impl AsyncWrite for NetStream {
         match self.project() {
             NetStreamProj::Tcp { stream } => stream.poll_shutdown(cx),
             NetStreamProj::Ws { stream } => stream.poll_shutdown(cx),
+            NetStreamProj::Tls { stream } => stream.poll_shutdown(cx),
+            NetStreamProj::Wss { stream } => stream.poll_shutdown(cx),
         }
     }
 }
@@ -181,6 +256,12 @@ impl AsyncPeekable for NetStream {
         match self {
             NetStream::Tcp { stream } => stream.poll_peek(cx, buf),
             NetStream::Ws { stream } => stream.poll_peek(cx, buf),
+            NetStream::Wss { stream } => stream.poll_peek(cx, buf),
+            // Peeking is only needed to detect an SSLRequest, before the stream is upgraded.
+            NetStream::Tls { .. } => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot peek into a TLS stream",
+            ))),
         }
     }
 }
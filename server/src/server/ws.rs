@@ -9,7 +9,8 @@ use bytes::Buf;
 use futures::stream::FuturesUnordered;
 use futures::{FutureExt, SinkExt, Stream, StreamExt};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::tungstenite::error::Error as WsError;
 use tokio_tungstenite::tungstenite::protocol::Role;
 use tokio_tungstenite::tungstenite::Message;
@@ -148,17 +149,19 @@ impl<S> WsStreamAdapter<S> {
     }
 }
 
-type WsAdapterInitFut = Pin<Box<dyn Future<Output = (WebSocketStream<TcpStream>, SocketAddr)>>>;
+type WsAdapterInitFut = Pin<Box<dyn Future<Output = io::Result<(NetStream, SocketAddr)>>>>;
 
 pub struct WsAdapter {
     listener: TcpListener,
+    tls: Option<TlsAcceptor>,
     init: FuturesUnordered<WsAdapterInitFut>,
 }
 
 impl WsAdapter {
-    pub fn new(listener: TcpListener) -> Self {
+    pub fn new(listener: TcpListener, tls: Option<TlsAcceptor>) -> Self {
         Self {
             listener,
+            tls,
             init: FuturesUnordered::new(),
         }
     }
@@ -178,23 +181,44 @@ impl Stream for WsAdapter {
     type Item = io::Result<(NetStream, SocketAddr)>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if let Poll::Ready(conn) = self.as_mut().listener.poll_accept(cx) {
+        while let Poll::Ready(conn) = self.as_mut().listener.poll_accept(cx) {
             match conn {
                 Ok((stream, addr)) => {
-                    let init_fut = WebSocketStream::from_raw_socket(stream, Role::Server, None);
-                    self.init
-                        .push(Box::pin(init_fut.map(move |stream| (stream, addr))));
+                    let init_fut = match self.tls.clone() {
+                        Some(acceptor) => async move {
+                            let stream = acceptor.accept(stream).await?;
+                            let stream =
+                                WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+                            let stream = NetStream::Wss {
+                                stream: Box::new(WsStreamAdapter::new(stream)),
+                            };
+                            Ok((stream, addr))
+                        }
+                        .boxed_local(),
+                        None => async move {
+                            let stream =
+                                WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+                            let stream = NetStream::Ws {
+                                stream: WsStreamAdapter::new(stream),
+                            };
+                            Ok((stream, addr))
+                        }
+                        .boxed_local(),
+                    };
+                    self.init.push(init_fut);
                 }
                 Err(e) => return Poll::Ready(Some(Err(e))),
             }
         }
 
-        let Some((stream, addr)) = ready!(self.init.poll_next_unpin(cx)) else { return Poll::Pending };
-        let stream = NetStream::Ws {
-            stream: WsStreamAdapter::new(stream),
-        };
-
-        Poll::Ready(Some(Ok((stream, addr))))
+        loop {
+            match ready!(self.init.poll_next_unpin(cx)) {
+                Some(Ok(conn)) => return Poll::Ready(Some(Ok(conn))),
+                // A failed handshake only concerns that client, keep accepting the others.
+                Some(Err(e)) => tracing::warn!("websocket handshake failed: {e}"),
+                None => return Poll::Pending,
+            }
+        }
     }
 }
 
@@ -1,20 +1,25 @@
 use std::ffi::{c_int, c_void};
 use std::fs::{File, OpenOptions};
-use std::io::{Cursor, Read, Write};
-use std::ops::DerefMut;
+use std::io::Cursor;
 use std::os::unix::prelude::FileExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::thread::JoinHandle;
 
-use bytes::{Bytes, BytesMut};
+use anyhow::ensure;
+use bytes::{BufMut, Bytes, BytesMut};
 use parking_lot::Mutex;
+use rusqlite::ffi::SQLITE_IOERR;
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use uuid::Uuid;
 
 use crate::libsql::ffi::{
     types::{XWalFrameFn, XWalUndoFn},
     PgHdr, Wal,
 };
 use crate::libsql::{ffi::PageHdrIter, wal_hook::WalHook};
+use crate::metrics;
 
 // Clone is necessary only because opening a database may fail, and we need to clone the empty
 // struct.
@@ -36,24 +41,36 @@ unsafe impl WalHook for WalLoggerHook {
         sync_flags: c_int,
         orig: XWalFrameFn,
     ) -> c_int {
-        assert_eq!(page_size, 4096);
+        // Snapshots wait for commits to be logged, see `WalLogger::with_logged_commits`.
+        let _commit = (is_commit != 0).then(|| self.logger.commit_lock.lock());
 
-        for (page_no, data) in PageHdrIter::new(page_headers, page_size as _) {
-            self.write_frame(page_no, data)
+        // The frames are only logged if the page size is the one of the log: the transaction
+        // fails otherwise, before it is written to the WAL.
+        if let Err(e) = self.logger.set_page_size(page_size as _) {
+            tracing::error!("cannot log transaction: {e}");
+            return SQLITE_IOERR;
         }
 
-        if is_commit != 0 {
-            self.commit(page_size, ntruncate, is_commit != 0, sync_flags);
+        for (page_no, data) in PageHdrIter::new(page_headers, page_size as _) {
+            self.write_frame(page_no, data)
         }
 
-        orig(
+        let ret = orig(
             wal,
             page_size,
             page_headers,
             ntruncate,
             is_commit,
             sync_flags,
-        )
+        );
+
+        // Transactions are only logged once they are committed to the WAL: the database then
+        // always contains every transaction present in the log, which is what snapshots rely on.
+        if ret == 0 && is_commit != 0 {
+            self.commit(page_size, ntruncate, is_commit != 0, sync_flags);
+        }
+
+        ret
     }
 
     fn on_undo(
@@ -115,98 +132,679 @@ impl WalLoggerHook {
     }
 }
 
+/// Copies the bytes of `from` at `range` to `to`, starting at `to_offset`.
+fn copy_range(
+    from: &File,
+    range: std::ops::Range<usize>,
+    to: &File,
+    to_offset: usize,
+) -> anyhow::Result<()> {
+    let mut buffer = vec![0; MAX_PAGE_SIZE];
+    for i in (0..range.len()).step_by(buffer.len()) {
+        let chunk = &mut buffer[..MAX_PAGE_SIZE.min(range.len() - i)];
+        from.read_exact_at(chunk, (range.start + i) as _)?;
+        to.write_all_at(chunk, (to_offset + i) as _)?;
+    }
+
+    Ok(())
+}
+
+/// Flushes the directory holding `path`, so that a file renamed to `path` is still there after a
+/// crash.
+fn sync_parent_dir(path: &Path) -> anyhow::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+/// How the entries of the log, and the frames streamed to replicas, are compressed.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    Zstd = 1,
+}
+
+impl Compression {
+    /// Compresses `data`. Returns `None` if the data is kept as is, either because compression is
+    /// disabled, or because it wouldn't make the data smaller.
+    pub fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)
+                .ok()
+                .filter(|compressed| compressed.len() < data.len()),
+        }
+    }
+
+    /// Decompresses `data`, whose decompressed size is at most `max_size` bytes.
+    pub fn decompress(self, data: &[u8], max_size: usize) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => Ok(zstd::bulk::decompress(data, max_size)?),
+        }
+    }
+
+    fn from_code(code: u8) -> anyhow::Result<Self> {
+        match code {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            code => anyhow::bail!("unknown compression: {code}"),
+        }
+    }
+}
+
 pub struct WalLogger {
-    current_offset: Mutex<usize>,
+    path: PathBuf,
+    /// The log file is replaced when the log is compacted, so every access goes through this lock.
+    /// It is shared with the thread compacting the log.
+    log: Arc<Mutex<LogFile>>,
+    /// Size in bytes past which the oldest entries of the log are compacted away.
+    max_log_size: usize,
+    /// Compression of the entries appended to the log.
+    compression: Compression,
+    /// Holds the index right after the last committed entry. Updated each time entries are
+    /// appended to the log.
+    new_frame_notifier: watch::Sender<usize>,
+    /// The thread compacting the log, if one was started.
+    compaction: Mutex<Option<JoinHandle<()>>>,
+    /// Held while a transaction is committed and logged.
+    commit_lock: Mutex<()>,
+}
+
+/// Where an entry of the log file is, and what the next entry is chained to.
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    /// Position of the record of the entry in the file.
+    position: usize,
+    checksum: u32,
+    is_commit: bool,
+}
+
+struct LogFile {
+    file: File,
     /// first index present in the file
     start_offset: usize,
-    log_file: File,
+    /// offset in the file right after the last entry
+    current_offset: usize,
+    /// The checksum the first entry in the file is chained to.
+    start_checksum: u32,
+    /// The entries of the file, in order. Records have a variable size, so the index is rebuilt
+    /// when the log is opened, to find entries by their index.
+    index: Vec<IndexEntry>,
+    database_id: u128,
+    /// Size of the pages of the logged frames. Unknown until the first transaction is logged.
+    page_size: Option<usize>,
+}
+
+impl LogFile {
+    /// Returns the index of the next entry to be appended.
+    fn next_offset(&self) -> usize {
+        self.start_offset + self.index.len()
+    }
+
+    /// Returns the checksum of the last entry, that the next one is chained to.
+    fn last_checksum(&self) -> u32 {
+        self.index
+            .last()
+            .map_or(self.start_checksum, |entry| entry.checksum)
+    }
+
+    /// Returns the header of the log file.
+    fn header(&self) -> WalLoggerFileHeader {
+        WalLoggerFileHeader {
+            version: WalLogger::VERSION,
+            start_index: self.start_offset as _,
+            database_id: self.database_id,
+            start_checksum: self.start_checksum,
+            page_size: self.page_size.unwrap_or_default() as _,
+        }
+    }
+
+    fn update_metrics(&self) {
+        metrics::WAL_LOG_SIZE.set(self.current_offset as _);
+        metrics::WAL_LOG_FRAMES.set(self.index.len() as _);
+    }
+
+    fn read_entry(&self, offset: usize) -> anyhow::Result<Option<WalLogEntry>> {
+        if offset < self.start_offset || offset >= self.next_offset() {
+            return Ok(None);
+        }
+        let i = offset - self.start_offset;
+        let prev_checksum = match i {
+            0 => self.start_checksum,
+            i => self.index[i - 1].checksum,
+        };
+
+        let (entry, _, _) = read_record(&self.file, self.index[i].position, prev_checksum)
+            .map_err(|e| anyhow::anyhow!("invalid log entry {offset}: {e}"))?;
+
+        Ok(Some(entry))
+    }
+}
+
+/// Size of the checksum that starts each record.
+const CHECKSUM_SIZE: usize = 4;
+/// Size of the header of a record: its checksum, followed by the length of its body.
+const RECORD_HEADER_SIZE: usize = CHECKSUM_SIZE + 4;
+/// Size of the serialized fields of a frame entry, but its page.
+const FRAME_ENTRY_HEADER_SIZE: usize = 16;
+/// The bits of the `sync_flags` passed to xFrames that tell how the WAL is synced on commit.
+const WAL_SYNC_FLAGS: i32 = 0x03;
+/// Largest page size supported by SQLite.
+pub const MAX_PAGE_SIZE: usize = 65536;
+/// Upper bound of the size of a serialized entry.
+pub const MAX_ENTRY_SIZE: usize = FRAME_ENTRY_HEADER_SIZE + MAX_PAGE_SIZE;
+/// Frames are compressed as they are committed, so speed matters more than ratio.
+const ZSTD_LEVEL: i32 = 1;
+
+/// Chains the checksum of `data` to the checksum of the entry before it.
+fn checksum(prev_checksum: u32, data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(prev_checksum);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Serializes `entry` into a record, and returns it with its checksum.
+///
+/// A record is the checksum of the entry, the length of its body, and its body: the compression
+/// of the entry, followed by the serialized entry, compressed with `compression` unless that
+/// doesn't make it smaller. The checksum covers the length and the body.
+fn encode_record(
+    entry: &WalLogEntry,
+    compression: Compression,
+    prev_checksum: u32,
+) -> (BytesMut, u32) {
+    let data = bincode::serialize(entry).unwrap();
+    let (compression, data) = match compression.compress(&data) {
+        Some(compressed) => (compression, compressed),
+        None => (Compression::None, data),
+    };
+
+    let mut record = BytesMut::with_capacity(RECORD_HEADER_SIZE + 1 + data.len());
+    record.put_u32_le(0);
+    record.put_u32_le(1 + data.len() as u32);
+    record.put_u8(compression as u8);
+    record.put_slice(&data);
+    let checksum = checksum(prev_checksum, &record[CHECKSUM_SIZE..]);
+    record[..CHECKSUM_SIZE].copy_from_slice(&checksum.to_le_bytes());
+
+    (record, checksum)
+}
+
+/// Reads the record at `position` in `file`, after checking that it is intact and follows the
+/// entry whose checksum is `prev_checksum`. Returns its entry, its checksum, and its size.
+fn read_record(
+    file: &File,
+    position: usize,
+    prev_checksum: u32,
+) -> anyhow::Result<(WalLogEntry, u32, usize)> {
+    let mut header = [0; RECORD_HEADER_SIZE];
+    file.read_exact_at(&mut header, position as _)?;
+    let stored = u32::from_le_bytes(header[..CHECKSUM_SIZE].try_into()?);
+    let len = u32::from_le_bytes(header[CHECKSUM_SIZE..].try_into()?) as usize;
+    // a torn length must not make us allocate an arbitrary amount of memory.
+    ensure!(
+        (1..=1 + MAX_ENTRY_SIZE).contains(&len),
+        "invalid record length: {len}"
+    );
+
+    let mut body = vec![0; len];
+    file.read_exact_at(&mut body, (position + RECORD_HEADER_SIZE) as _)?;
+    let mut hasher = crc32fast::Hasher::new_with_initial(prev_checksum);
+    hasher.update(&header[CHECKSUM_SIZE..]);
+    hasher.update(&body);
+    ensure!(hasher.finalize() == stored, "checksum mismatch");
+
+    let data = Compression::from_code(body[0])?.decompress(&body[1..], MAX_ENTRY_SIZE)?;
+
+    Ok((
+        bincode::deserialize(&data)?,
+        stored,
+        RECORD_HEADER_SIZE + len,
+    ))
 }
 
 #[derive(Serialize, Deserialize)]
 struct WalLoggerFileHeader {
     version: u8,
     start_index: u64,
+    /// Identifies the database whose transactions are logged. Generated when the log is created,
+    /// and kept when it is compacted.
+    database_id: u128,
+    /// The checksum the first entry in the file is chained to.
+    start_checksum: u32,
+    /// Size of the pages of the logged frames. Zero until the first transaction is logged.
+    page_size: u32,
+}
+
+impl WalLoggerFileHeader {
+    fn read(file: &File) -> anyhow::Result<Self> {
+        let mut header_buf = [0; WalLogger::HEADER_SIZE];
+        file.read_exact_at(&mut header_buf, 0)?;
+        // The fields added by later versions are zero in the headers of earlier ones.
+        let header: Self = bincode::deserialize(&header_buf)?;
+
+        Ok(header)
+    }
+
+    fn write(&self, file: &File) -> anyhow::Result<()> {
+        let mut header_buf = [0; WalLogger::HEADER_SIZE];
+        bincode::serialize_into(Cursor::new(&mut header_buf[..]), self)?;
+        file.write_all_at(&header_buf, 0)?;
+
+        Ok(())
+    }
 }
 
 impl WalLogger {
-    /// size of a single frame
-    pub const FRAME_SIZE: usize = 4112;
     /// Size of the file header
     pub const HEADER_SIZE: usize = 4096;
+    /// Version of the format of the log file:
+    /// 1. frames of 4096-byte pages.
+    /// 2. checksummed records of variable size, whose entry may be compressed.
+    const VERSION: u8 = 2;
+    /// Size of the frames of a version 1 log.
+    const V1_FRAME_SIZE: usize = FRAME_ENTRY_HEADER_SIZE + 4096;
 
-    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+    pub fn open(
+        path: impl AsRef<Path>,
+        max_log_size: usize,
+        compression: Compression,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
         let mut log_file = OpenOptions::new()
             .create(true)
             .write(true)
             .read(true)
-            .open(path)?;
-        let mut file_end = log_file.metadata()?.len();
+            .open(&path)?;
 
-        let mut header_buf = [0; 4096];
-        let header = if file_end == 0 {
+        if log_file.metadata()?.len() == 0 {
             let header = WalLoggerFileHeader {
-                version: 1,
+                version: Self::VERSION,
                 start_index: 0,
+                database_id: Uuid::new_v4().as_u128(),
+                start_checksum: 0,
+                page_size: 0,
             };
-            bincode::serialize_into(Cursor::new(&mut header_buf[..]), &header)?;
-            log_file.write_all(&header_buf)?;
-            file_end = 4096;
-            header
-        } else {
-            log_file.read_exact(&mut header_buf)?;
-            let header: WalLoggerFileHeader = bincode::deserialize(&header_buf)?;
-            header
+            header.write(&log_file)?;
+        }
+        let mut header = WalLoggerFileHeader::read(&log_file)?;
+        if header.version == 1 {
+            log_file = Self::upgrade_v1(&path, &log_file, header.start_index, compression)?;
+            header = WalLoggerFileHeader::read(&log_file)?;
+        }
+        ensure!(
+            header.version == Self::VERSION,
+            "unsupported WAL log version: {}",
+            header.version
+        );
+        tracing::info!(
+            "opened WAL log of database {}",
+            Uuid::from_u128(header.database_id)
+        );
+
+        let (index, current_offset) = Self::recover(&log_file, &header)?;
+        let log = LogFile {
+            file: log_file,
+            start_offset: header.start_index as _,
+            current_offset,
+            start_checksum: header.start_checksum,
+            index,
+            database_id: header.database_id,
+            page_size: (header.page_size != 0).then_some(header.page_size as _),
         };
+        log.update_metrics();
+        let (new_frame_notifier, _) = watch::channel(log.next_offset());
 
         Ok(Self {
-            current_offset: Mutex::new(file_end as usize),
-            start_offset: header.start_index as _,
-            log_file,
+            path,
+            log: Arc::new(Mutex::new(log)),
+            max_log_size,
+            compression,
+            new_frame_notifier,
+            compaction: Mutex::new(None),
+            commit_lock: Mutex::new(()),
         })
     }
 
+    /// Checks the entries of the log, and truncates it right after the last complete transaction
+    /// whose entries are intact. The end of the log may hold the frames of a transaction whose
+    /// commit was never logged, or that were only partially written, after a crash.
+    ///
+    /// Returns the index of the entries of the log, and the offset in the file right after the
+    /// last entry.
+    fn recover(
+        file: &File,
+        header: &WalLoggerFileHeader,
+    ) -> anyhow::Result<(Vec<IndexEntry>, usize)> {
+        let file_end = file.metadata()?.len() as usize;
+        let mut index = Vec::new();
+        let mut position = Self::HEADER_SIZE;
+        let mut prev_checksum = header.start_checksum;
+        let mut valid = (0, position);
+        while position < file_end {
+            let record = read_record(file, position, prev_checksum);
+            let Ok((entry, checksum, len)) = record else { break };
+            let is_commit = matches!(entry, WalLogEntry::Commit { .. });
+            index.push(IndexEntry {
+                position,
+                checksum,
+                is_commit,
+            });
+            position += len;
+            prev_checksum = checksum;
+            if is_commit {
+                valid = (index.len(), position);
+            }
+        }
+
+        let (valid_len, valid_end) = valid;
+        index.truncate(valid_len);
+        if valid_end < file_end {
+            tracing::warn!(
+                "truncating {} bytes of incomplete transaction at the end of the WAL log",
+                file_end - valid_end
+            );
+            file.set_len(valid_end as _)?;
+            file.sync_all()?;
+        }
+
+        Ok((index, valid_end))
+    }
+
+    /// Rewrites a version 1 log in the current format. The indexes of the entries are kept, so
+    /// that replicas can keep following the log. Returns the rewritten log file.
+    fn upgrade_v1(
+        path: &Path,
+        file: &File,
+        start_index: u64,
+        compression: Compression,
+    ) -> anyhow::Result<File> {
+        tracing::info!("upgrading WAL log to version {}", Self::VERSION);
+        let upgrade_path = path.with_extension("upgrade");
+        let upgrade_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .read(true)
+            .truncate(true)
+            .open(&upgrade_path)?;
+        // Version 1 didn't identify the database: the replicas sync again from a snapshot.
+        let mut upgrade_header = WalLoggerFileHeader {
+            version: Self::VERSION,
+            start_index,
+            database_id: Uuid::new_v4().as_u128(),
+            start_checksum: 0,
+            page_size: 0,
+        };
+
+        // The frames are copied up to the last complete transaction, as recovery would.
+        let file_end = file.metadata()?.len() as usize;
+        let mut buffer = BytesMut::zeroed(Self::V1_FRAME_SIZE);
+        let mut transaction = Vec::new();
+        let mut upgrade_checksum = upgrade_header.start_checksum;
+        let mut upgrade_offset = Self::HEADER_SIZE;
+        for offset in (Self::HEADER_SIZE..file_end).step_by(Self::V1_FRAME_SIZE) {
+            if offset + Self::V1_FRAME_SIZE > file_end {
+                break;
+            }
+            file.read_exact_at(&mut buffer, offset as _)?;
+            let Ok(entry) = bincode::deserialize::<WalLogEntry>(&buffer) else { break };
+            let is_commit = matches!(entry, WalLogEntry::Commit { .. });
+            let (record, record_checksum) = encode_record(&entry, compression, upgrade_checksum);
+            upgrade_checksum = record_checksum;
+            transaction.push(record);
+            if is_commit {
+                for record in transaction.drain(..) {
+                    upgrade_file.write_all_at(&record, upgrade_offset as _)?;
+                    upgrade_offset += record.len();
+                }
+            }
+        }
+        if upgrade_offset > Self::HEADER_SIZE {
+            upgrade_header.page_size = 4096;
+        }
+        upgrade_header.write(&upgrade_file)?;
+        upgrade_file.sync_all()?;
+        std::fs::rename(&upgrade_path, path)?;
+        sync_parent_dir(path)?;
+
+        Ok(upgrade_file)
+    }
+
+    /// Returns a receiver that is notified with the index following the last committed entry
+    /// every time new entries are appended to the log.
+    pub fn subscribe(&self) -> watch::Receiver<usize> {
+        self.new_frame_notifier.subscribe()
+    }
+
+    /// Returns the index of the first entry still present in the log. Entries before it were
+    /// compacted away.
+    pub fn start_offset(&self) -> usize {
+        self.log.lock().start_offset
+    }
+
+    /// Returns the index of the next entry to be appended to the log.
+    pub fn next_offset(&self) -> usize {
+        self.log.lock().next_offset()
+    }
+
+    /// Returns the id of the database, generated when the log was created.
+    pub fn database_id(&self) -> u128 {
+        self.log.lock().database_id
+    }
+
+    /// Returns the size of the logged pages, if a transaction was logged.
+    pub fn page_size(&self) -> Option<usize> {
+        self.log.lock().page_size
+    }
+
+    /// Checks that the pages of the database are `page_size` bytes, the size of the pages already
+    /// logged. The page size of the log is set by the first transaction.
+    fn set_page_size(&self, page_size: usize) -> anyhow::Result<()> {
+        let mut log = self.log.lock();
+        match log.page_size {
+            Some(log_page_size) => ensure!(
+                log_page_size == page_size,
+                "the database has {page_size}-byte pages, but the WAL log has {log_page_size}-byte \
+                pages"
+            ),
+            None => {
+                ensure!(
+                    page_size.is_power_of_two() && (512..=65536).contains(&page_size),
+                    "invalid page size: {page_size}"
+                );
+                log.page_size = Some(page_size);
+                log.header().write(&log.file)?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn append(&self, frames: &[WalLogEntry]) {
-        let mut lock = self.current_offset.lock();
-        let mut current_offset = *lock;
+        let mut log = self.log.lock();
+        let mut current_offset = log.current_offset;
+        let mut last_checksum = log.last_checksum();
         for frame in frames.iter() {
             #[cfg(any(debug_assertions, test))]
             if let WalLogEntry::Frame { ref data, .. } = frame {
-                assert_eq!(data.len(), 4096);
+                assert_eq!(Some(data.len()), log.page_size);
             }
 
-            let mut buffer = BytesMut::zeroed(Self::FRAME_SIZE);
-            bincode::serialize_into(Cursor::new(buffer.deref_mut()), frame).unwrap();
-            self.log_file
-                .write_all_at(&buffer, current_offset as _)
+            let (record, checksum) = encode_record(frame, self.compression, last_checksum);
+            log.file
+                .write_all_at(&record, current_offset as _)
                 // TODO: Handle write error
                 .unwrap();
-            current_offset += Self::FRAME_SIZE;
+            log.index.push(IndexEntry {
+                position: current_offset,
+                checksum,
+                is_commit: matches!(frame, WalLogEntry::Commit { .. }),
+            });
+            current_offset += record.len();
+            last_checksum = checksum;
+        }
+        // The log is synced whenever SQLite syncs the WAL on commit, so that it doesn't lose
+        // transactions the database kept after a crash.
+        let sync = frames.iter().any(|frame| match frame {
+            WalLogEntry::Commit { sync_flags, .. } => sync_flags & WAL_SYNC_FLAGS != 0,
+            WalLogEntry::Frame { .. } => false,
+        });
+        if sync {
+            // TODO: Handle sync error
+            log.file.sync_data().unwrap();
         }
 
-        *lock = current_offset;
+        log.current_offset = current_offset;
+        self.new_frame_notifier
+            .send_modify(|next_index| *next_index += frames.len());
+
+        self.maybe_compact(&log);
+        log.update_metrics();
     }
 
-    /// Returns frame at `index`.
+    /// Compacts the log in the background if it grew past `max_log_size`, keeping about its most
+    /// recent half. Nothing is done while a previous compaction is still running.
+    fn maybe_compact(&self, log: &LogFile) {
+        if log.current_offset - Self::HEADER_SIZE <= self.max_log_size {
+            return;
+        }
+        let mut compaction = self.compaction.lock();
+        if compaction
+            .as_ref()
+            .map_or(false, |thread| !thread.is_finished())
+        {
+            return;
+        }
+
+        // The log must start with a complete transaction: start it right after the first commit
+        // followed by at most half of `max_log_size` of entries.
+        let retained_from = log.current_offset - self.max_log_size / 2;
+        let new_start = log.index.iter().enumerate().find_map(|(i, entry)| {
+            let end = log
+                .index
+                .get(i + 1)
+                .map_or(log.current_offset, |next| next.position);
+            (entry.is_commit && end >= retained_from).then_some(log.start_offset + i + 1)
+        });
+
+        if let Some(new_start) = new_start {
+            let path = self.path.clone();
+            let log = self.log.clone();
+            *compaction = Some(std::thread::spawn(move || {
+                match Self::compact(&path, &log, new_start) {
+                    Ok(()) => tracing::info!("compacted WAL log, first index is now {new_start}"),
+                    Err(e) => tracing::error!("failed to compact WAL log: {e}"),
+                }
+            }));
+        }
+    }
+
+    /// Drops all entries before `new_start` from the log.
     ///
-    /// If the requested frame is before the first frame in the log, or after the last frame,
-    /// Ok(None) is returned.
-    // TODO: implement log compaction
-    // TODO: implement page cache
-    pub fn get_entry(&self, offset: usize) -> anyhow::Result<Option<WalLogEntry>> {
-        if offset < self.start_offset {
-            return Ok(None);
+    /// The retained entries are copied to a new file, which then atomically replaces the current
+    /// log file. The log is only locked to copy the entries appended during the copy, so that
+    /// commits are not stalled by the compaction.
+    fn compact(path: &Path, log: &Mutex<LogFile>, new_start: usize) -> anyhow::Result<()> {
+        let compact_path = path.with_extension("compact");
+        let compact_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .read(true)
+            .truncate(true)
+            .open(&compact_path)?;
+        // The retained records are copied as they are: the first of them stays chained to the
+        // checksum of the entry before it.
+        let (file, header, copy_from, copied_until) = {
+            let log = log.lock();
+            let dropped = new_start - log.start_offset;
+            let header = WalLoggerFileHeader {
+                start_index: new_start as _,
+                start_checksum: log.index[dropped - 1].checksum,
+                ..log.header()
+            };
+            let copy_from = log
+                .index
+                .get(dropped)
+                .map_or(log.current_offset, |entry| entry.position);
+            (log.file.try_clone()?, header, copy_from, log.current_offset)
+        };
+        header.write(&compact_file)?;
+        // Records are only ever appended to the log file, so the ones already written can be
+        // read without the lock.
+        copy_range(
+            &file,
+            copy_from..copied_until,
+            &compact_file,
+            Self::HEADER_SIZE,
+        )?;
+
+        let mut log = log.lock();
+        let copied_len = copied_until - copy_from;
+        copy_range(
+            &log.file,
+            copied_until..log.current_offset,
+            &compact_file,
+            Self::HEADER_SIZE + copied_len,
+        )?;
+        compact_file.sync_all()?;
+        std::fs::rename(&compact_path, path)?;
+        sync_parent_dir(path)?;
+
+        let dropped = new_start - log.start_offset;
+        let retained_len = log.current_offset - copy_from;
+        let start_checksum = header.start_checksum;
+        log.file = compact_file;
+        log.start_offset = new_start;
+        log.current_offset = Self::HEADER_SIZE + retained_len;
+        log.start_checksum = start_checksum;
+        log.index.drain(..dropped);
+        for entry in log.index.iter_mut() {
+            entry.position = entry.position - copy_from + Self::HEADER_SIZE;
         }
-        let read_offset = Self::HEADER_SIZE + (offset - self.start_offset) * Self::FRAME_SIZE;
 
-        if read_offset >= *self.current_offset.lock() {
-            return Ok(None);
+        Ok(())
+    }
+
+    /// Waits for the running compaction, if any, to be done.
+    #[cfg(test)]
+    fn wait_for_compaction(&self) {
+        if let Some(thread) = self.compaction.lock().take() {
+            thread.join().unwrap();
         }
+    }
 
-        let mut buffer = BytesMut::zeroed(Self::FRAME_SIZE);
-        self.log_file.read_exact_at(&mut buffer, read_offset as _)?;
-        let entry: WalLogEntry = bincode::deserialize(&buffer)?;
+    /// Calls `f` with the index of the next entry of the log, while no transaction can be
+    /// committed: every transaction committed to the database up to then is in the log, and no
+    /// other is.
+    ///
+    /// Transactions are logged right after they are committed to the WAL, so without this, a
+    /// reader of the database may see a transaction that is not logged yet.
+    pub fn with_logged_commits<T>(&self, f: impl FnOnce(usize) -> T) -> T {
+        let _commit = self.commit_lock.lock();
+        f(self.next_offset())
+    }
 
-        Ok(Some(entry))
+    /// Flushes the log to disk.
+    pub fn sync(&self) -> anyhow::Result<()> {
+        self.log.lock().file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Returns frame at `index`.
+    ///
+    /// If the requested frame is before the first frame in the log, or after the last frame,
+    /// Ok(None) is returned. Use `start_offset` to tell whether the frame was compacted away.
+    // TODO: implement page cache
+    pub fn get_entry(&self, offset: usize) -> anyhow::Result<Option<WalLogEntry>> {
+        self.log.lock().read_entry(offset)
     }
 }
 
@@ -217,9 +815,10 @@ mod test {
     #[test]
     fn write_and_read_from_frame_log() {
         let log_file = tempfile::NamedTempFile::new().unwrap();
-        let logger = WalLogger::open(log_file.path()).unwrap();
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        logger.set_page_size(4096).unwrap();
 
-        assert_eq!(*logger.current_offset.lock(), WalLogger::HEADER_SIZE);
+        assert_eq!(logger.log.lock().current_offset, WalLogger::HEADER_SIZE);
 
         let frames = (0..10)
             .map(|i| WalLogEntry::Frame {
@@ -237,15 +836,320 @@ mod test {
         }
 
         assert_eq!(
-            *logger.current_offset.lock(),
-            WalLogger::HEADER_SIZE + 10 * WalLogger::FRAME_SIZE
+            logger.log.lock().current_offset,
+            WalLogger::HEADER_SIZE + 10 * (RECORD_HEADER_SIZE + 1 + FRAME_ENTRY_HEADER_SIZE + 4096)
         );
     }
 
+    #[test]
+    fn append_notifies_subscribers() {
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        logger.set_page_size(4096).unwrap();
+        let mut receiver = logger.subscribe();
+        assert_eq!(*receiver.borrow_and_update(), 0);
+
+        let frames = (0..3)
+            .map(|i| WalLogEntry::Frame {
+                data: Bytes::from(vec![i; 4096]),
+                page_no: i as _,
+            })
+            .collect::<Vec<_>>();
+        logger.append(&frames);
+
+        assert!(receiver.has_changed().unwrap());
+        assert_eq!(*receiver.borrow_and_update(), 3);
+    }
+
+    #[test]
+    fn compact_log() {
+        let txn = |i: u8| {
+            [
+                WalLogEntry::Frame {
+                    data: Bytes::from(vec![i; 4096]),
+                    page_no: 1,
+                },
+                WalLogEntry::Frame {
+                    data: Bytes::from(vec![i; 4096]),
+                    page_no: 2,
+                },
+                WalLogEntry::Commit {
+                    page_size: 4096,
+                    size_after: 2,
+                    is_commit: true,
+                    sync_flags: 0,
+                },
+            ]
+        };
+        let txn_size: usize = txn(0)
+            .iter()
+            .map(|entry| encode_record(entry, Compression::None, 0).0.len())
+            .sum();
+        let max_log_size = 3 * txn_size + txn_size / 2;
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let logger = WalLogger::open(log_file.path(), max_log_size, Compression::None).unwrap();
+        logger.set_page_size(4096).unwrap();
+
+        for i in 0..4 {
+            logger.append(&txn(i));
+        }
+        logger.wait_for_compaction();
+
+        // the 12 entries don't fit in the log anymore: it now starts after the third commit.
+        assert_eq!(logger.start_offset(), 9);
+        assert_eq!(logger.next_offset(), 12);
+        assert!(logger.get_entry(8).unwrap().is_none());
+        let WalLogEntry::Frame { page_no, data } = logger.get_entry(9).unwrap().unwrap() else { panic!() };
+        assert_eq!(page_no, 1);
+        assert!(data.iter().all(|x| *x == 3));
+
+        drop(logger);
+        let logger = WalLogger::open(log_file.path(), max_log_size, Compression::None).unwrap();
+        assert_eq!(logger.start_offset(), 9);
+        assert_eq!(logger.next_offset(), 12);
+        assert!(matches!(
+            logger.get_entry(11).unwrap(),
+            Some(WalLogEntry::Commit { .. })
+        ));
+    }
+
+    #[test]
+    fn append_during_compaction() {
+        let txn_size: usize = transaction(0)
+            .iter()
+            .map(|entry| encode_record(entry, Compression::None, 0).0.len())
+            .sum();
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let logger = WalLogger::open(log_file.path(), 2 * txn_size, Compression::None).unwrap();
+        logger.set_page_size(4096).unwrap();
+
+        // the third transaction starts a compaction, that the following ones may run into.
+        for i in 0..10 {
+            logger.append(&transaction(i));
+        }
+        logger.wait_for_compaction();
+
+        assert_eq!(logger.next_offset(), 20);
+        let first = logger.start_offset();
+        assert!(first > 0);
+        for offset in first..20 {
+            let entry = logger.get_entry(offset).unwrap().unwrap();
+            if let WalLogEntry::Frame { data, .. } = entry {
+                assert!(data.iter().all(|x| *x as usize == offset / 2));
+            }
+        }
+
+        drop(logger);
+        let logger = WalLogger::open(log_file.path(), 2 * txn_size, Compression::None).unwrap();
+        assert_eq!(logger.start_offset(), first);
+        assert_eq!(logger.next_offset(), 20);
+    }
+
+    fn transaction(i: u8) -> Vec<WalLogEntry> {
+        vec![
+            WalLogEntry::Frame {
+                data: Bytes::from(vec![i; 4096]),
+                page_no: 1,
+            },
+            WalLogEntry::Commit {
+                page_size: 4096,
+                size_after: 1,
+                is_commit: true,
+                sync_flags: 0,
+            },
+        ]
+    }
+
+    #[test]
+    fn recover_truncates_incomplete_transaction() {
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        logger.set_page_size(4096).unwrap();
+        logger.append(&transaction(0));
+        // the frames of a transaction whose commit was never logged.
+        logger.append(&transaction(1)[..1]);
+        assert_eq!(logger.next_offset(), 3);
+
+        drop(logger);
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        assert_eq!(logger.next_offset(), 2);
+
+        // new entries are chained to the last recovered one.
+        logger.append(&transaction(2));
+        let entry = logger.get_entry(2).unwrap().unwrap();
+        let WalLogEntry::Frame { data, .. } = entry else { panic!() };
+        assert!(data.iter().all(|x| *x == 2));
+        drop(logger);
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        assert_eq!(logger.next_offset(), 4);
+    }
+
+    #[test]
+    fn detect_corrupted_entry() {
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        logger.set_page_size(4096).unwrap();
+        logger.append(&transaction(0));
+        logger.append(&transaction(1));
+
+        // a torn write in the frame of the second transaction.
+        let position = logger.log.lock().index[2].position + 100;
+        log_file
+            .as_file()
+            .write_all_at(&[42], position as _)
+            .unwrap();
+        assert!(logger.get_entry(1).unwrap().is_some());
+        assert!(logger.get_entry(2).is_err());
+
+        drop(logger);
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        assert_eq!(logger.next_offset(), 2);
+    }
+
+    #[test]
+    fn upgrade_v1_log() {
+        // a version 1 log, starting at index 7, that holds a transaction followed by the frame of
+        // an incomplete one.
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let header = WalLoggerFileHeader {
+            version: 1,
+            start_index: 7,
+            database_id: 0,
+            start_checksum: 0,
+            page_size: 0,
+        };
+        header.write(log_file.as_file()).unwrap();
+        let mut entries = transaction(0);
+        entries.push(entries[0].clone());
+        for (i, entry) in entries.iter().enumerate() {
+            let mut frame = vec![0; WalLogger::V1_FRAME_SIZE];
+            bincode::serialize_into(&mut frame[..], entry).unwrap();
+            let position = WalLogger::HEADER_SIZE + i * WalLogger::V1_FRAME_SIZE;
+            log_file
+                .as_file()
+                .write_all_at(&frame, position as _)
+                .unwrap();
+        }
+
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        assert_eq!(logger.start_offset(), 7);
+        assert_eq!(logger.next_offset(), 9);
+        assert_eq!(logger.page_size(), Some(4096));
+        assert_ne!(logger.database_id(), 0);
+        let entry = logger.get_entry(7).unwrap().unwrap();
+        let WalLogEntry::Frame { data, .. } = entry else { panic!() };
+        assert_eq!(data.len(), 4096);
+        assert!(data.iter().all(|x| *x == 0));
+        assert!(matches!(
+            logger.get_entry(8).unwrap(),
+            Some(WalLogEntry::Commit { .. })
+        ));
+
+        // the upgraded log is opened as is.
+        let database_id = logger.database_id();
+        drop(logger);
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        assert_eq!(logger.database_id(), database_id);
+        assert_eq!(logger.next_offset(), 9);
+    }
+
+    #[test]
+    fn upgrade_empty_log() {
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let header = WalLoggerFileHeader {
+            version: 1,
+            start_index: 0,
+            database_id: 0,
+            start_checksum: 0,
+            page_size: 0,
+        };
+        header.write(log_file.as_file()).unwrap();
+
+        // the page size of the log is still set by its first transaction.
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        assert_eq!(logger.page_size(), None);
+        logger.set_page_size(1024).unwrap();
+    }
+
+    #[test]
+    fn refuse_newer_log() {
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let header = WalLoggerFileHeader {
+            version: WalLogger::VERSION + 1,
+            start_index: 0,
+            database_id: 42,
+            start_checksum: 0,
+            page_size: 0,
+        };
+        header.write(log_file.as_file()).unwrap();
+
+        assert!(WalLogger::open(log_file.path(), usize::MAX, Compression::None).is_err());
+    }
+
+    #[test]
+    fn log_large_pages() {
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        logger.set_page_size(65536).unwrap();
+        let txn = [
+            WalLogEntry::Frame {
+                data: Bytes::from(vec![1; 65536]),
+                page_no: 1,
+            },
+            WalLogEntry::Commit {
+                page_size: 65536,
+                size_after: 1,
+                is_commit: true,
+                sync_flags: 0,
+            },
+        ];
+        logger.append(&txn);
+
+        drop(logger);
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        assert_eq!(logger.next_offset(), 2);
+        let entry = logger.get_entry(0).unwrap().unwrap();
+        let WalLogEntry::Frame { data, .. } = entry else { panic!() };
+        assert_eq!(data.len(), 65536);
+        // the page size of a log can't change.
+        assert!(logger.set_page_size(4096).is_err());
+        assert!(logger.set_page_size(65536).is_ok());
+    }
+
+    #[test]
+    fn compress_log() {
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::Zstd).unwrap();
+        logger.set_page_size(4096).unwrap();
+        logger.append(&transaction(1));
+        // a page that doesn't compress is stored as is.
+        let noise = (0..4096).map(|_| rand::random()).collect::<Vec<u8>>();
+        let txn = [
+            WalLogEntry::Frame {
+                data: Bytes::from(noise.clone()),
+                page_no: 2,
+            },
+            transaction(0)[1].clone(),
+        ];
+        logger.append(&txn);
+        assert!(logger.log.lock().current_offset < WalLogger::HEADER_SIZE + 2 * 4096);
+
+        // the compression of each record is stored along with it.
+        drop(logger);
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        assert_eq!(logger.next_offset(), 4);
+        let entry = logger.get_entry(0).unwrap().unwrap();
+        let WalLogEntry::Frame { data, .. } = entry else { panic!() };
+        assert!(data.iter().all(|x| *x == 1));
+        let entry = logger.get_entry(2).unwrap().unwrap();
+        let WalLogEntry::Frame { data, .. } = entry else { panic!() };
+        assert_eq!(data, noise);
+    }
+
     #[test]
     fn index_out_of_bounds() {
         let log_file = tempfile::NamedTempFile::new().unwrap();
-        let logger = WalLogger::open(log_file.path()).unwrap();
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
         assert!(logger.get_entry(1).unwrap().is_none());
     }
 
@@ -253,7 +1157,8 @@ mod test {
     #[should_panic]
     fn incorrect_frame_size() {
         let log_file = tempfile::NamedTempFile::new().unwrap();
-        let logger = WalLogger::open(log_file.path()).unwrap();
+        let logger = WalLogger::open(log_file.path(), usize::MAX, Compression::None).unwrap();
+        logger.set_page_size(4096).unwrap();
         let entry = WalLogEntry::Frame {
             page_no: 0,
             data: vec![0; 3].into(),